@@ -0,0 +1,62 @@
+//! Measures the heap allocations and time per request served from the cache.
+//!
+//! Run with `cargo bench --bench hits`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    convert::Infallible,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use axum::{body::Body, http::Request, response::IntoResponse};
+use axum_response_cache::CacheLayer;
+use tower::{service_fn, Layer, Service};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: usize = 100_000;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let handler = service_fn(|_| async { Ok::<_, Infallible>("Hello, world!".into_response()) });
+    let mut service = CacheLayer::with_lifespan(60).layer(handler);
+
+    // feed the cache
+    service
+        .call(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert!(response.status().is_success());
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "cache hits: {:.2} allocations/op, {:?}/op",
+        allocations as f64 / ITERATIONS as f64,
+        elapsed / ITERATIONS as u32
+    );
+}
@@ -0,0 +1,83 @@
+//! Measures the heap allocations and time per response stored in the cache, for a 64 KB body
+//! streamed in 4 KB chunks with a known `Content-Length`.
+//!
+//! Run with `cargo bench --bench store`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    convert::Infallible,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Request},
+    response::IntoResponse,
+};
+use axum_response_cache::CacheLayer;
+use cached::TimedSizedCache;
+use tower::{service_fn, Layer, Service};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: usize = 2_000;
+const CHUNK: [u8; 4096] = [b'x'; 4096];
+const CHUNKS: usize = 16;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let handler = service_fn(|_| async {
+        let chunks = (0..CHUNKS).map(|_| Ok::<_, Infallible>(Bytes::from_static(&CHUNK)));
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+        Ok::<_, Infallible>(
+            ([(header::CONTENT_LENGTH, CHUNKS * CHUNK.len())], body).into_response(),
+        )
+    });
+    let mut service =
+        CacheLayer::with(TimedSizedCache::with_size_and_lifespan(16, 60)).layer(handler);
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        // every request misses, so the response is buffered and stored
+        let request = Request::get(format!("/{i}")).body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert!(response.status().is_success());
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes;
+
+    println!(
+        "cache stores: {:.2} allocations/op, {} bytes allocated/op, {:?}/op",
+        allocations as f64 / ITERATIONS as f64,
+        allocated_bytes / ITERATIONS,
+        elapsed / ITERATIONS as u32
+    );
+}
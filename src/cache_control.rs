@@ -0,0 +1,164 @@
+//! Parsing of the `Cache-Control` header of the responses and requests.
+
+use std::time::Duration;
+
+use axum::http::{header, HeaderMap};
+
+/// The largest delta-seconds value, the bigger ones are replaced with it (RFC 9111, 1.2.2).
+const MAX_DELTA_SECONDS: u64 = 1 << 31;
+
+/// The directives of the `Cache-Control` header relevant to caching the responses.
+///
+/// The parser is lenient: the directive names are case-insensitive, the values may be quoted,
+/// all the `Cache-Control` headers are combined, and the unknown or malformed directives are
+/// ignored. When a directive is repeated, its first occurrence is used.
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use axum::http::{header, HeaderMap, HeaderValue};
+/// use axum_response_cache::CacheControl;
+///
+/// let mut headers = HeaderMap::new();
+/// headers.insert(
+///     header::CACHE_CONTROL,
+///     HeaderValue::from_static("Public, max-age=\"60\", stale-if-error=300"),
+/// );
+/// let cache_control = CacheControl::from_headers(&headers);
+/// assert_eq!(Some(Duration::from_secs(60)), cache_control.max_age);
+/// assert_eq!(Some(Duration::from_secs(300)), cache_control.stale_if_error);
+/// assert!(!cache_control.no_store);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CacheControl {
+    /// The `max-age` directive.
+    pub max_age: Option<Duration>,
+    /// The `s-maxage` directive.
+    pub s_maxage: Option<Duration>,
+    /// The `no-store` directive.
+    pub no_store: bool,
+    /// The `no-cache` directive (including its form qualified with the header names).
+    pub no_cache: bool,
+    /// The `private` directive (including its form qualified with the header names).
+    pub private: bool,
+    /// The `immutable` directive.
+    pub immutable: bool,
+    /// The `must-revalidate` directive.
+    pub must_revalidate: bool,
+    /// The `must-understand` directive.
+    pub must_understand: bool,
+    /// The `stale-while-revalidate` directive.
+    pub stale_while_revalidate: Option<Duration>,
+    /// The `stale-if-error` directive.
+    pub stale_if_error: Option<Duration>,
+}
+
+impl CacheControl {
+    /// Parse the directives of all the `Cache-Control` headers.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut cache_control = Self::default();
+        let mut seen = Vec::new();
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(directives);
+        for (name, value) in directives {
+            let name = name.to_ascii_lowercase();
+            if seen.contains(&name) {
+                continue;
+            }
+            let seconds = || value.as_deref().and_then(delta_seconds);
+            match name.as_str() {
+                "max-age" => cache_control.max_age = seconds(),
+                "s-maxage" => cache_control.s_maxage = seconds(),
+                "no-store" => cache_control.no_store = true,
+                "no-cache" => cache_control.no_cache = true,
+                "private" => cache_control.private = true,
+                "immutable" => cache_control.immutable = true,
+                "must-revalidate" => cache_control.must_revalidate = true,
+                "must-understand" => cache_control.must_understand = true,
+                "stale-while-revalidate" => cache_control.stale_while_revalidate = seconds(),
+                "stale-if-error" => cache_control.stale_if_error = seconds(),
+                _ => {}
+            }
+            seen.push(name);
+        }
+        cache_control
+    }
+}
+
+/// Parse the directives of the request, handling `Pragma: no-cache` like `no-cache` when it has
+/// no `Cache-Control` header (RFC 9111, 5.4).
+pub(crate) fn request_directives(headers: &HeaderMap) -> CacheControl {
+    let mut cache_control = CacheControl::from_headers(headers);
+    if !headers.contains_key(header::CACHE_CONTROL) {
+        cache_control.no_cache = headers
+            .get_all(header::PRAGMA)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(directives)
+            .any(|(name, _)| name.eq_ignore_ascii_case("no-cache"));
+    }
+    cache_control
+}
+
+/// Split the header value into the directive names with their (unquoted) values, ignoring the
+/// commas within the quoted values.
+fn directives(value: &str) -> Vec<(&str, Option<String>)> {
+    let mut directives = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return directives;
+        }
+        let end = rest.find([',', '=']).unwrap_or(rest.len());
+        let name = rest[..end].trim();
+        rest = &rest[end..];
+        let value = match rest.strip_prefix('=') {
+            Some(quoted) if quoted.trim_start().starts_with('"') => {
+                let (value, after) = unquote(quoted.trim_start());
+                rest = after;
+                Some(value)
+            }
+            Some(token) => {
+                let end = token.find(',').unwrap_or(token.len());
+                rest = &token[end..];
+                Some(token[..end].trim().to_owned())
+            }
+            None => None,
+        };
+        // the garbage following a quoted value is skipped up to the next directive
+        rest = &rest[rest.find(',').unwrap_or(rest.len())..];
+        if !name.is_empty() {
+            directives.push((name, value));
+        }
+    }
+}
+
+/// Read the quoted string at the start of the input, returning its unescaped content and the
+/// input following it (the unterminated strings extend to the end of the input).
+fn unquote(input: &str) -> (String, &str) {
+    let mut value = String::new();
+    let mut chars = input.char_indices().skip(1);
+    while let Some((index, char)) = chars.next() {
+        match char {
+            '"' => return (value, &input[index + 1..]),
+            '\\' => value.extend(chars.next().map(|(_, char)| char)),
+            _ => value.push(char),
+        }
+    }
+    (value, "")
+}
+
+/// Parse the non-negative number of seconds, capping the too big ones.
+fn delta_seconds(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let seconds = value.parse().unwrap_or(MAX_DELTA_SECONDS);
+    Some(Duration::from_secs(seconds.min(MAX_DELTA_SECONDS)))
+}
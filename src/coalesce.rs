@@ -0,0 +1,65 @@
+//! Coalescing of the concurrent misses for the same key into one call of the wrapped service.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+use crate::{CachedResponse, Key};
+
+/// The misses being fetched, with the channels publishing their stored responses.
+pub(crate) type Flights = Arc<Mutex<HashMap<Key, watch::Receiver<Option<CachedResponse>>>>>;
+
+/// The role of the miss in fetching the response for its key.
+pub(crate) enum Flight {
+    /// The first miss, fetching the response for the others.
+    Leader(Leader),
+    /// The miss waiting for the response fetched by the leader.
+    Follower(watch::Receiver<Option<CachedResponse>>),
+}
+
+/// The miss fetching the response for the key, releasing the followers once dropped.
+pub(crate) struct Leader {
+    flights: Flights,
+    key: Key,
+    sender: watch::Sender<Option<CachedResponse>>,
+}
+
+impl Leader {
+    /// Hand the stored (or the shared failed) response to all the followers.
+    pub(crate) fn publish(self, value: &CachedResponse) {
+        self.sender.send_replace(Some(value.clone()));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Join the flight fetching the response for the key, leading it when there’s none yet.
+pub(crate) fn join(flights: &Flights, key: &Key) -> Flight {
+    let mut guard = flights.lock().unwrap();
+    if let Some(receiver) = guard.get(key) {
+        return Flight::Follower(receiver.clone());
+    }
+    let (sender, receiver) = watch::channel(None);
+    guard.insert(key.clone(), receiver);
+    Flight::Leader(Leader {
+        flights: Arc::clone(flights),
+        key: key.clone(),
+        sender,
+    })
+}
+
+/// Wait for the response stored by the leader, or `None` when it didn’t store any.
+pub(crate) async fn wait(
+    mut receiver: watch::Receiver<Option<CachedResponse>>,
+) -> Option<CachedResponse> {
+    receiver.changed().await.ok()?;
+    let value = receiver.borrow_and_update().clone();
+    value
+}
@@ -0,0 +1,180 @@
+//! Compression of the bodies stored in the cache.
+
+use std::io::{self, Read as _, Write as _};
+
+use axum::http::{header, HeaderMap, HeaderValue};
+
+/// The codec used to compress the bodies of the cached responses.
+///
+/// [`Compression::Gzip`] is always available, the heavier codecs have to be enabled with the
+/// `zstd` and `brotli` features of the crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// `gzip` content coding.
+    Gzip,
+    /// `zstd` content coding.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// `br` content coding.
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl Compression {
+    /// The name of the codec used in the `Content-Encoding` and `Accept-Encoding` headers.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+            #[cfg(feature = "brotli")]
+            Self::Brotli => "br",
+        }
+    }
+
+    /// Return the codec with the given name, if it’s enabled.
+    pub(crate) fn from_encoding(encoding: &str) -> Option<Self> {
+        match encoding {
+            "gzip" => Some(Self::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Self::Zstd),
+            #[cfg(feature = "brotli")]
+            "br" => Some(Self::Brotli),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::encode_all(data, 0),
+            #[cfg(feature = "brotli")]
+            Self::Brotli => {
+                let mut output = Vec::new();
+                brotli::BrotliCompress(
+                    &mut &data[..],
+                    &mut output,
+                    &brotli::enc::BrotliEncoderParams::default(),
+                )?;
+                Ok(output)
+            }
+        }
+    }
+
+    pub(crate) fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        match self {
+            Self::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut output)?;
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => {
+                output = zstd::decode_all(data)?;
+            }
+            #[cfg(feature = "brotli")]
+            Self::Brotli => {
+                brotli::BrotliDecompress(&mut &data[..], &mut output)?;
+            }
+        }
+        Ok(output)
+    }
+
+    /// Check whether the client accepts this codec according to the request’s `Accept-Encoding`.
+    /// The codec listed explicitly (eg. rejected with `gzip;q=0`) isn’t matched by `*`.
+    pub(crate) fn is_accepted(&self, request_headers: &HeaderMap) -> bool {
+        let mut wildcard = false;
+        for (name, accepted) in codings(request_headers) {
+            if name.eq_ignore_ascii_case(self.encoding()) {
+                return accepted;
+            }
+            if name == "*" {
+                wildcard = accepted;
+            }
+        }
+        wildcard
+    }
+}
+
+/// The content codings taken into account when comparing the `Accept-Encoding` headers.
+const KNOWN_CODINGS: [&str; 6] = ["*", "br", "compress", "deflate", "gzip", "zstd"];
+
+/// The known content codings rejected explicitly, in the order of [`KNOWN_CODINGS`].
+const REJECTED_CODINGS: [&str; 6] = [
+    "*;q=0",
+    "br;q=0",
+    "compress;q=0",
+    "deflate;q=0",
+    "gzip;q=0",
+    "zstd;q=0",
+];
+
+/// Iterate over the names of the content codings listed by the client, with whether they’re
+/// accepted (ie. not explicitly rejected with `q=0`).
+fn codings(request_headers: &HeaderMap) -> impl Iterator<Item = (&str, bool)> {
+    request_headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let rejected = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (!name.is_empty()).then_some((name, !rejected))
+        })
+}
+
+/// Normalize the request’s `Accept-Encoding` into a sorted set of the accepted known codings
+/// (and the rejected ones when they override the accepted `*`), so that the functionally
+/// identical headers (eg. `gzip, br` and `br;q=0.8, gzip`) are equal.
+pub(crate) fn normalized_accept_encoding(request_headers: &HeaderMap) -> HeaderValue {
+    let wildcard = codings(request_headers).any(|coding| coding == ("*", true));
+    let mut codings: Vec<&str> = codings(request_headers)
+        .filter(|(_, accepted)| *accepted || wildcard)
+        .filter_map(|(name, accepted)| {
+            let name = if name.eq_ignore_ascii_case("x-gzip") {
+                "gzip"
+            } else {
+                name
+            };
+            let index = KNOWN_CODINGS
+                .into_iter()
+                .position(|known| known.eq_ignore_ascii_case(name))?;
+            Some(match accepted {
+                true => KNOWN_CODINGS[index],
+                false => REJECTED_CODINGS[index],
+            })
+        })
+        .collect();
+    codings.sort_unstable();
+    codings.dedup();
+    HeaderValue::from_str(&codings.join(", ")).unwrap()
+}
+
+/// Remove the `Content-Encoding` header listing only the `identity` coding (which shouldn’t be
+/// sent, RFC 9110 § 8.4.1), so the unencoded body is handled like the one without the header.
+pub(crate) fn remove_identity_encoding(headers: &mut HeaderMap) {
+    let identity = headers
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|byte| *byte == b','))
+        .all(|coding| {
+            let coding = coding.trim_ascii();
+            coding.is_empty() || coding.eq_ignore_ascii_case(b"identity")
+        });
+    if identity {
+        headers.remove(header::CONTENT_ENCODING);
+    }
+}
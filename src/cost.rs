@@ -0,0 +1,301 @@
+//! The cache store evicting the cheapest entries first.
+
+use std::{
+    borrow::Borrow,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::http::response::Parts;
+use cached::{Cached, CloneCached};
+
+use crate::{CacheKeys, CachedEntry, Key};
+
+/// The function estimating the cost of recomputing the response from its parts and body size.
+type CostFn = Arc<dyn Fn(&Parts, usize) -> u64 + Send + Sync>;
+
+/// The time the wrapped service took to produce the response (until its headers were received),
+/// attached to the extensions of the stored responses by
+/// [`CacheLayer::retain_by_latency`](crate::CacheLayer::retain_by_latency).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ResponseLatency(pub Duration);
+
+impl ResponseLatency {
+    /// The cost function for the [`CostWeightedCache`] weighting the responses by the number of
+    /// microseconds they took to produce, so the slowest ones are retained (the responses
+    /// without the latency cost nothing).
+    pub fn cost(parts: &Parts, _size: usize) -> u64 {
+        parts.extensions.get::<Self>().map_or(0, |latency| {
+            latency.0.as_micros().try_into().unwrap_or(u64::MAX)
+        })
+    }
+}
+
+/// The size-limited cache with timed expiration which, when full, evicts the entry that’s the
+/// cheapest to recompute (instead of the least recently used one). The cost of an entry is the
+/// sum of the costs of its variants, as estimated by the user-provided function. Entries with
+/// the same cost are evicted from the oldest.
+///
+/// See [`CacheLayer::cost_weighted`](crate::CacheLayer::cost_weighted).
+pub struct CostWeightedCache {
+    capacity: usize,
+    lifespan: Duration,
+    cost_fn: CostFn,
+    store: HashMap<Key, Stored>,
+    // the cheapest entry on the top, outdated records are skipped when evicting
+    heap: BinaryHeap<Reverse<(u64, u64)>>,
+    keys: HashMap<u64, Key>,
+    next_seq: u64,
+    hits: u64,
+    misses: u64,
+}
+
+struct Stored {
+    timestamp: Instant,
+    seq: u64,
+    value: CachedEntry,
+}
+
+impl CostWeightedCache {
+    /// Create the cache holding at most `capacity` entries for `lifespan_sec` seconds each, with
+    /// the given cost function receiving the parts and the stored body size of the responses.
+    pub fn new<F>(capacity: usize, lifespan_sec: u64, cost_fn: F) -> Self
+    where
+        F: Fn(&Parts, usize) -> u64 + Send + Sync + 'static,
+    {
+        Self {
+            capacity: capacity.max(1),
+            lifespan: Duration::from_secs(lifespan_sec),
+            cost_fn: Arc::new(cost_fn),
+            store: HashMap::new(),
+            heap: BinaryHeap::new(),
+            keys: HashMap::new(),
+            next_seq: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn cost(&self, value: &CachedEntry) -> u64 {
+        value
+            .variants
+            .iter()
+            .map(|variant| (self.cost_fn)(&variant.parts, variant.body.len()))
+            .fold(0, u64::saturating_add)
+    }
+
+    fn is_expired(&self, stored: &Stored) -> bool {
+        stored.timestamp.elapsed() >= self.lifespan
+    }
+
+    fn remove_stored<Q>(&mut self, key: &Q) -> Option<Stored>
+    where
+        Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let stored = self.store.remove(key)?;
+        self.keys.remove(&stored.seq);
+        Some(stored)
+    }
+
+    /// Make room for a new entry, preferring the expired entries over the cheapest ones.
+    fn evict(&mut self) {
+        if self.store.len() < self.capacity {
+            return;
+        }
+        let expired: Vec<Key> = self
+            .store
+            .iter()
+            .filter(|(_, stored)| self.is_expired(stored))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove_stored(&key);
+        }
+        while self.store.len() >= self.capacity {
+            let Some(Reverse((_, seq))) = self.heap.pop() else {
+                break;
+            };
+            if let Some(key) = self.keys.remove(&seq) {
+                self.store.remove(&key);
+            }
+        }
+        if self.heap.len() > 2 * self.store.len() + 16 {
+            // drop the records of the replaced and removed entries
+            let keys = &self.keys;
+            self.heap.retain(|Reverse((_, seq))| keys.contains_key(seq));
+        }
+    }
+
+    fn status<Q>(&mut self, key: &Q) -> Option<bool>
+    where
+        Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let expired = self.is_expired(self.store.get(key)?);
+        if expired {
+            self.misses += 1;
+        } else {
+            self.hits += 1;
+        }
+        Some(expired)
+    }
+}
+
+impl Cached<Key, CachedEntry> for CostWeightedCache {
+    fn cache_get<Q>(&mut self, key: &Q) -> Option<&CachedEntry>
+    where
+        Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache_get_mut(key).map(|value| &*value)
+    }
+
+    fn cache_get_mut<Q>(&mut self, key: &Q) -> Option<&mut CachedEntry>
+    where
+        Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.status(key) {
+            None => {
+                self.misses += 1;
+                None
+            }
+            Some(true) => {
+                self.remove_stored(key);
+                None
+            }
+            Some(false) => self.store.get_mut(key).map(|stored| &mut stored.value),
+        }
+    }
+
+    fn cache_set(&mut self, key: Key, value: CachedEntry) -> Option<CachedEntry> {
+        let previous = self.remove_stored(&key);
+        self.evict();
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.heap.push(Reverse((self.cost(&value), seq)));
+        self.keys.insert(seq, key.clone());
+        self.store.insert(
+            key,
+            Stored {
+                timestamp: Instant::now(),
+                seq,
+                value,
+            },
+        );
+        previous
+            .filter(|stored| !self.is_expired(stored))
+            .map(|stored| stored.value)
+    }
+
+    fn cache_get_or_set_with<F: FnOnce() -> CachedEntry>(
+        &mut self,
+        key: Key,
+        f: F,
+    ) -> &mut CachedEntry {
+        if self.cache_get(&key).is_none() {
+            self.cache_set(key.clone(), f());
+        }
+        &mut self.store.get_mut(&key).unwrap().value
+    }
+
+    fn cache_try_get_or_set_with<F: FnOnce() -> Result<CachedEntry, E>, E>(
+        &mut self,
+        key: Key,
+        f: F,
+    ) -> Result<&mut CachedEntry, E> {
+        if self.cache_get(&key).is_none() {
+            self.cache_set(key.clone(), f()?);
+        }
+        Ok(&mut self.store.get_mut(&key).unwrap().value)
+    }
+
+    fn cache_remove<Q>(&mut self, key: &Q) -> Option<CachedEntry>
+    where
+        Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_stored(key)
+            .filter(|stored| !self.is_expired(stored))
+            .map(|stored| stored.value)
+    }
+
+    fn cache_clear(&mut self) {
+        self.store.clear();
+        self.heap.clear();
+        self.keys.clear();
+    }
+
+    fn cache_reset(&mut self) {
+        self.cache_clear();
+        self.cache_reset_metrics();
+    }
+
+    fn cache_reset_metrics(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    fn cache_size(&self) -> usize {
+        self.store.len()
+    }
+
+    fn cache_hits(&self) -> Option<u64> {
+        Some(self.hits)
+    }
+
+    fn cache_misses(&self) -> Option<u64> {
+        Some(self.misses)
+    }
+
+    fn cache_capacity(&self) -> Option<usize> {
+        Some(self.capacity)
+    }
+
+    fn cache_lifespan(&self) -> Option<u64> {
+        Some(self.lifespan.as_secs())
+    }
+
+    fn cache_set_lifespan(&mut self, seconds: u64) -> Option<u64> {
+        let previous = self.lifespan.as_secs();
+        self.lifespan = Duration::from_secs(seconds);
+        Some(previous)
+    }
+}
+
+impl CloneCached<Key, CachedEntry> for CostWeightedCache {
+    fn cache_get_expired<Q>(&mut self, key: &Q) -> (Option<CachedEntry>, bool)
+    where
+        Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.status(key) {
+            None => {
+                self.misses += 1;
+                (None, false)
+            }
+            Some(true) => (self.remove_stored(key).map(|stored| stored.value), true),
+            Some(false) => (
+                self.store.get(key).map(|stored| stored.value.clone()),
+                false,
+            ),
+        }
+    }
+}
+
+impl CacheKeys<Key, CachedEntry> for CostWeightedCache {
+    fn cache_keys(&self) -> Vec<Key> {
+        self.store.keys().cloned().collect()
+    }
+
+    fn cache_entries(&self) -> Vec<(&Key, &CachedEntry)> {
+        self.store
+            .iter()
+            .map(|(key, stored)| (key, &stored.value))
+            .collect()
+    }
+}
@@ -0,0 +1,14 @@
+//! The digests of the cached bodies advertised with the `Repr-Digest` header (RFC 9530).
+
+use axum::http::{HeaderName, HeaderValue};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use sha2::{Digest as _, Sha256};
+
+/// The header carrying the digest of the selected representation.
+pub(crate) const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+
+/// Compute the `Repr-Digest` value of the body, ie. `sha-256=:<base64 of the SHA-256>:`.
+pub(crate) fn repr_digest(body: &[u8]) -> HeaderValue {
+    let digest = STANDARD.encode(Sha256::digest(body));
+    HeaderValue::try_from(format!("sha-256=:{digest}:")).unwrap()
+}
@@ -0,0 +1,52 @@
+//! The future returned by the cache service.
+
+use std::{
+    convert::Infallible,
+    future::{Future, Ready},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::response::Response;
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send + 'static>>;
+
+/// The future returned by [`CacheService`](crate::CacheService).
+///
+/// The responses served from the cache are ready immediately, without allocating a boxed future.
+pub struct CacheFuture {
+    kind: Kind,
+}
+
+enum Kind {
+    Ready(Ready<Result<Response, Infallible>>),
+    Boxed(BoxFuture),
+}
+
+impl CacheFuture {
+    pub(crate) fn ready(response: Response) -> Self {
+        Self {
+            kind: Kind::Ready(std::future::ready(Ok(response))),
+        }
+    }
+
+    pub(crate) fn boxed<F>(future: F) -> Self
+    where
+        F: Future<Output = Result<Response, Infallible>> + Send + 'static,
+    {
+        Self {
+            kind: Kind::Boxed(Box::pin(future)),
+        }
+    }
+}
+
+impl Future for CacheFuture {
+    type Output = Result<Response, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &mut self.get_mut().kind {
+            Kind::Ready(future) => Pin::new(future).poll(cx),
+            Kind::Boxed(future) => future.as_mut().poll(cx),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! The lock-free snapshot of the hottest cached responses.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use axum::http::HeaderMap;
+use cached::Cached;
+use tracing::debug;
+
+use crate::{invalidation::Invalidated, CacheKeys, CachedEntry, Key};
+
+/// The copies of the hottest entries, see [`CacheLayer::hot_keys`](crate::CacheLayer::hot_keys).
+#[derive(Default)]
+pub(crate) struct Hot(ArcSwap<Snapshot>);
+
+/// The copies of the entries taken at once, with the hits they served since.
+#[derive(Default)]
+struct Snapshot {
+    entries: HashMap<Key, (CachedEntry, AtomicU64)>,
+    lifespan: Option<u64>,
+    generation: u64,
+}
+
+impl Hot {
+    /// Return the copy of the entry whose variant matching the request headers is still fresh,
+    /// with the lifespan of the cache, unless the cache was purged since the snapshot was taken.
+    pub(crate) fn get(
+        &self,
+        key: &Key,
+        request_headers: &HeaderMap,
+        generation: u64,
+    ) -> Option<(CachedEntry, Option<u64>)> {
+        let snapshot = self.0.load();
+        if snapshot.generation != generation {
+            return None;
+        }
+        let (entry, hits) = snapshot.entries.get(key)?;
+        let value = entry
+            .variants
+            .iter()
+            .find(|variant| variant.matches(request_headers))?;
+        let fresh = match value.ttl.or(snapshot.lifespan.map(Duration::from_secs)) {
+            Some(ttl) => value.timestamp.elapsed() < ttl,
+            None => true,
+        };
+        if !fresh || value.soft_invalidated {
+            return None;
+        }
+        hits.fetch_add(1, Ordering::Relaxed);
+        Some((entry.clone(), snapshot.lifespan))
+    }
+}
+
+/// Periodically replace the snapshot with the copies of the most hit entries, until the layer
+/// is dropped.
+pub(crate) async fn refresh<C>(
+    cache: Weak<Mutex<C>>,
+    hot: Weak<Hot>,
+    invalidated: Arc<Invalidated>,
+    size: usize,
+    period: Duration,
+) where
+    C: Cached<Key, CachedEntry> + CacheKeys<Key, CachedEntry>,
+{
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let (Some(cache), Some(hot)) = (cache.upgrade(), hot.upgrade()) else {
+            break;
+        };
+        // taken before the copies, so the purges made meanwhile outdate them
+        let generation = invalidated.generation();
+        let previous = hot.0.load_full();
+        let mut guard = cache.lock().unwrap();
+        // the hits served from the snapshot count for the ranking too
+        for (key, (_, hits)) in &previous.entries {
+            if let Some(entry) = guard.cache_get_mut(key) {
+                entry.hits += hits.load(Ordering::Relaxed);
+            }
+        }
+        let mut ranked: Vec<_> = guard
+            .cache_entries()
+            .into_iter()
+            .filter(|(_, entry)| entry.hits > 0)
+            .collect();
+        ranked.sort_by_key(|(_, entry)| Reverse(entry.hits));
+        let entries: HashMap<_, _> = ranked
+            .into_iter()
+            .take(size)
+            .map(|(key, entry)| (key.clone(), (entry.clone(), AtomicU64::new(0))))
+            .collect();
+        let lifespan = guard.cache_lifespan();
+        drop(guard);
+        debug!("Refreshed the snapshot of {} hot entries", entries.len());
+        hot.0.store(Arc::new(Snapshot {
+            entries,
+            lifespan,
+            generation,
+        }));
+    }
+}
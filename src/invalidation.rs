@@ -0,0 +1,243 @@
+//! Invalidation of the cached responses driven by messages from other instances.
+
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use axum::http::{Method, Uri};
+use cached::{Cached, TimedCache, TimedSizedCache};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+use crate::{CachedEntry, Key, ABSENT_KEY_SEGMENT};
+
+/// The message instructing the layer to purge some of the cached responses.
+///
+/// The messages match the keys by the path of the request: the segments the layer prefixes the
+/// keys with (the hashes of the keyed parts of the request, eg. with
+/// [`CacheLayer::vary_by_trusted_headers`](crate::CacheLayer::vary_by_trusted_headers), or `-`
+/// standing for the absent ones) are skipped, so the message purges the responses of all the
+/// variants of the path. The keys whose paths start with the segments looking like those (32
+/// lowercase hexadecimal digits or `-`) may thus be purged for the shorter paths too.
+///
+/// See [`CacheLayer::invalidate_on`](crate::CacheLayer::invalidate_on).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvalidationMsg {
+    /// Purge the response cached for the given method and URI. The URI without the authority
+    /// (eg. `/path`) matches the keys of all the hosts, see
+    /// [`CacheLayer::vary_by_host`](crate::CacheLayer::vary_by_host), the one with it only the
+    /// keys of its host.
+    Key(Method, Uri),
+    /// Purge all the responses cached for the URIs whose path and query start with the prefix.
+    Prefix(String),
+}
+
+impl InvalidationMsg {
+    fn matches(&self, key: &Key) -> bool {
+        let Some(path_and_query) = key.1.path_and_query() else {
+            return false;
+        };
+        let mut paths = request_paths(path_and_query.as_str());
+        match self {
+            Self::Key(method, uri) => {
+                key.0 == *method
+                    && uri
+                        .authority()
+                        .is_none_or(|authority| key.1.authority() == Some(authority))
+                    && paths
+                        .any(|path| Some(path) == uri.path_and_query().map(|path| path.as_str()))
+            }
+            Self::Prefix(prefix) => paths.any(|path| path.starts_with(prefix.as_str())),
+        }
+    }
+}
+
+/// Iterate over the path of the key and its suffixes left after skipping the leading segments
+/// the layer prefixes the keys with, see [`InvalidationMsg`].
+fn request_paths(path_and_query: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(path_and_query), |path| {
+        let rest = path.strip_prefix('/')?;
+        let (segment, _) = rest.split_once('/')?;
+        is_key_segment(segment).then(|| &rest[segment.len()..])
+    })
+}
+
+/// Check whether the path segment is one the layer prefixes the keys with: the hash of a keyed
+/// value (the 32 hexadecimal digits) or the absent one.
+fn is_key_segment(segment: &str) -> bool {
+    segment == ABSENT_KEY_SEGMENT
+        || (segment.len() == 32
+            && segment
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')))
+}
+
+/// The keys purged since their responses were last served, tracked only once enabled by
+/// [`CacheLayer::signal_invalidation`](crate::CacheLayer::signal_invalidation), and the
+/// generation of the cache contents, advanced by every purge so the copies of the entries can
+/// tell they may be outdated.
+#[derive(Debug, Default)]
+pub(crate) struct Invalidated {
+    keys: Mutex<Option<HashSet<Key>>>,
+    generation: AtomicU64,
+}
+
+impl Invalidated {
+    /// Start tracking the purged keys.
+    pub(crate) fn enable(&self) {
+        self.keys.lock().unwrap().get_or_insert_with(HashSet::new);
+    }
+
+    /// Remember the purged keys, if tracked, and advance the generation.
+    pub(crate) fn mark(&self, keys: impl IntoIterator<Item = Key>) {
+        self.advance();
+        if let Some(invalidated) = self.keys.lock().unwrap().as_mut() {
+            invalidated.extend(keys);
+        }
+    }
+
+    /// Forget the key, returning whether it was purged since its response was last served.
+    pub(crate) fn take(&self, key: &Key) -> bool {
+        self.keys
+            .lock()
+            .unwrap()
+            .as_mut()
+            .is_some_and(|invalidated| invalidated.remove(key))
+    }
+
+    /// Advance the generation after changing the entries without purging them.
+    pub(crate) fn advance(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Return the current generation of the cache contents.
+    #[cfg(feature = "hot")]
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+}
+
+/// The caches able to enumerate the entries they store.
+///
+/// It’s required by the features operating on multiple entries at once (eg. purging by prefix).
+pub trait CacheKeys<K, V> {
+    /// Return the keys of all the entries currently stored (including the expired ones).
+    fn cache_keys(&self) -> Vec<K>;
+
+    /// Return all the entries currently stored (including the expired ones) without affecting
+    /// their recency or the cache’s metrics.
+    fn cache_entries(&self) -> Vec<(&K, &V)>;
+
+    /// Return the entry stored for the key (even when expired) without affecting its recency or
+    /// the cache’s metrics. The default implementation scans all the entries.
+    fn cache_peek<'a>(&'a self, key: &K) -> Option<&'a V>
+    where
+        K: PartialEq + 'a,
+    {
+        self.cache_entries()
+            .into_iter()
+            .find(|(stored, _)| *stored == key)
+            .map(|(_, value)| value)
+    }
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V> CacheKeys<K, V> for TimedCache<K, V> {
+    fn cache_keys(&self) -> Vec<K> {
+        self.get_store().keys().cloned().collect()
+    }
+
+    fn cache_entries(&self) -> Vec<(&K, &V)> {
+        self.get_store()
+            .iter()
+            .map(|(key, (_, value))| (key, value))
+            .collect()
+    }
+
+    fn cache_peek<'a>(&'a self, key: &K) -> Option<&'a V>
+    where
+        K: PartialEq + 'a,
+    {
+        self.get_store().get(key).map(|(_, value)| value)
+    }
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V> CacheKeys<K, V> for TimedSizedCache<K, V> {
+    fn cache_keys(&self) -> Vec<K> {
+        self.key_order().cloned().collect()
+    }
+
+    fn cache_entries(&self) -> Vec<(&K, &V)> {
+        self.key_order()
+            .zip(self.value_order().map(|(_, value)| value))
+            .collect()
+    }
+
+    fn cache_peek<'a>(&'a self, key: &K) -> Option<&'a V>
+    where
+        K: PartialEq + 'a,
+    {
+        // the store can’t be looked up without marking the entry as used
+        self.key_order()
+            .zip(self.value_order())
+            .find(|(stored, _)| *stored == key)
+            .map(|(_, (_, value))| value)
+    }
+}
+
+/// Purge the entries matching the message, returning the keys of the removed entries.
+pub(crate) fn invalidate<C>(cache: &mut C, msg: &InvalidationMsg) -> Vec<Key>
+where
+    C: Cached<Key, CachedEntry> + CacheKeys<Key, CachedEntry>,
+{
+    // even the single key may be stored under multiple prefixes, see `InvalidationMsg`
+    cache
+        .cache_keys()
+        .into_iter()
+        .filter(|key| msg.matches(key))
+        .filter(|key| cache.cache_remove(key).is_some())
+        .collect()
+}
+
+/// How often the tasks waiting for the events check whether the cache was dropped meanwhile, so
+/// they stop even when no events arrive.
+pub(crate) const DROP_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Apply the invalidations received over the channel until it’s closed or the cache is dropped.
+pub(crate) async fn listen<C>(
+    cache: Weak<Mutex<C>>,
+    invalidated: Arc<Invalidated>,
+    mut receiver: broadcast::Receiver<InvalidationMsg>,
+) where
+    C: Cached<Key, CachedEntry> + CacheKeys<Key, CachedEntry>,
+{
+    loop {
+        let msg = match tokio::time::timeout(DROP_CHECK_PERIOD, receiver.recv()).await {
+            Ok(msg) => msg,
+            Err(_) if cache.strong_count() == 0 => break,
+            Err(_) => continue,
+        };
+        let Some(cache) = cache.upgrade() else {
+            break;
+        };
+        match msg {
+            Ok(msg) => {
+                let removed = invalidate(&mut *cache.lock().unwrap(), &msg);
+                debug!("Invalidated {} cached responses for {msg:?}", removed.len());
+                invalidated.mark(removed);
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                // the missed messages can’t be recovered, so nothing in the cache can be trusted
+                debug!("Missed {skipped} invalidation messages, clearing the cache");
+                let mut cache = cache.lock().unwrap();
+                invalidated.mark(cache.cache_keys());
+                cache.cache_clear();
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
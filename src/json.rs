@@ -0,0 +1,93 @@
+//! Normalization of the volatile fields of the JSON bodies before storing them.
+
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap},
+};
+use serde_json::Value;
+
+/// What happens to the JSON field selected for normalization, see
+/// [`CacheLayer::normalize_json_fields`](crate::CacheLayer::normalize_json_fields).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JsonFieldAction {
+    /// Remove the field from its object (or the item from its array).
+    Remove,
+    /// Replace the value of the field with `null`.
+    Null,
+}
+
+/// What happens to the response declared as JSON whose body can’t be parsed, see
+/// [`CacheLayer::normalize_json_fields`](crate::CacheLayer::normalize_json_fields).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MalformedJson {
+    /// Cache the body as it is.
+    Store,
+    /// Pass the response through without caching it.
+    PassThrough,
+}
+
+/// The fields to normalize, as JSON pointers (eg. `/meta/request_id`), with their actions.
+#[derive(Clone, Debug)]
+pub(crate) struct Normalization {
+    pub(crate) fields: Arc<[(String, JsonFieldAction)]>,
+    pub(crate) malformed: MalformedJson,
+}
+
+impl Normalization {
+    /// Normalize the body if the response is JSON, returning `Ok(None)` when it isn’t or when it
+    /// didn’t change, and `Err(())` when it isn’t valid JSON.
+    pub(crate) fn apply(&self, headers: &HeaderMap, body: &[u8]) -> Result<Option<Bytes>, ()> {
+        // the bodies encoded by the wrapped service can’t be parsed
+        if !is_json(headers) || headers.contains_key(header::CONTENT_ENCODING) {
+            return Ok(None);
+        }
+        let mut value: Value = serde_json::from_slice(body).map_err(|_| ())?;
+        let mut changed = false;
+        for (pointer, action) in self.fields.iter() {
+            changed |= match action {
+                JsonFieldAction::Remove => remove(&mut value, pointer),
+                JsonFieldAction::Null => value
+                    .pointer_mut(pointer)
+                    .map(|field| *field = Value::Null)
+                    .is_some(),
+            };
+        }
+        if !changed {
+            return Ok(None);
+        }
+        Ok(Some(Bytes::from(
+            serde_json::to_vec(&value).map_err(|_| ())?,
+        )))
+    }
+}
+
+/// Check whether the media type is `application/json` or has the `+json` suffix.
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|media_type| media_type.trim().to_ascii_lowercase())
+        .is_some_and(|media_type| media_type == "application/json" || media_type.ends_with("+json"))
+}
+
+/// Remove the value at the JSON pointer, returning whether it was present.
+fn remove(value: &mut Value, pointer: &str) -> bool {
+    let Some((parent, token)) = pointer.rsplit_once('/') else {
+        return false;
+    };
+    let token = token.replace("~1", "/").replace("~0", "~");
+    match value.pointer_mut(parent) {
+        Some(Value::Object(object)) => object.remove(&token).is_some(),
+        Some(Value::Array(array)) => match token.parse::<usize>() {
+            Ok(index) if index < array.len() => {
+                array.remove(index);
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,93 @@
+//! Histograms of the time spent in the cache operations, see
+//! [`CacheLayer::measure_cache_latency`](crate::CacheLayer::measure_cache_latency).
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The number of the buckets, bounded by the powers of two microseconds (up to ~0.5s), the last
+/// one collecting the longer durations.
+const BUCKETS: usize = 21;
+
+/// The histogram of the durations of one kind of the cache operations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LatencyHistogram {
+    /// The numbers of the operations by the (exclusive) upper bounds of their durations, the
+    /// bounds doubling from 1µs, the last one ([`Duration::MAX`]) collecting the rest.
+    pub buckets: Vec<(Duration, u64)>,
+    /// The total duration of all the operations.
+    pub total: Duration,
+}
+
+impl LatencyHistogram {
+    /// The number of all the recorded operations.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|(_, count)| count).sum()
+    }
+}
+
+/// The latencies of the operations holding the lock of the cache.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CacheLatency {
+    /// Looking the requests up: acquiring the lock and getting the entry, including the
+    /// reinsertion of the expired ones.
+    pub lookup: LatencyHistogram,
+    /// Storing the responses: acquiring the lock and merging the variant into the entry.
+    pub store: LatencyHistogram,
+}
+
+/// The recorders of the latencies shared by the clones of the layer.
+#[derive(Debug, Default)]
+pub(crate) struct Latencies {
+    pub(crate) lookup: Recorder,
+    pub(crate) store: Recorder,
+}
+
+impl Latencies {
+    pub(crate) fn snapshot(&self) -> CacheLatency {
+        CacheLatency {
+            lookup: self.lookup.snapshot(),
+            store: self.store.snapshot(),
+        }
+    }
+}
+
+/// The histogram of the durations counted without locking.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    buckets: [AtomicU64; BUCKETS],
+    total_nanos: AtomicU64,
+}
+
+impl Recorder {
+    pub(crate) fn record(&self, duration: Duration) {
+        // the durations between 2^(n-1) and 2^n microseconds fall into the n-th bucket
+        let micros = duration.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                let bound = match bucket {
+                    _ if bucket == BUCKETS - 1 => Duration::MAX,
+                    _ => Duration::from_micros(1 << bucket),
+                };
+                (bound, count.load(Ordering::Relaxed))
+            })
+            .collect();
+        LatencyHistogram {
+            buckets,
+            total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
@@ -14,6 +14,10 @@
 //! Only successful responses are cached (responses with status codes outside of the `[200-299]`
 //! range are passed-through or ignored).
 //!
+//! Responses with the `Vary` header are cached as separate variants selected by the listed
+//! request headers ([`CachedEntry`] holds all the variants cached for one method and path),
+//! responses with `Vary: *` are not cached.
+//!
 //! The cache limits maximum size of the response’s body (128 MB by default).
 //!
 //! ## Examples
@@ -199,6 +203,65 @@
 //!
 //! Cache invalidation could be dangerous because it can allow a user to force the server to make a request to an external service or database. It is disabled by default, but can be enabled by calling the [`CacheLayer::allow_invalidation`] method.
 //!
+//! ### Compression
+//! When combined with [`tower-http::compression::CompressionLayer`](https://docs.rs/tower-http/latest/tower_http/compression/struct.CompressionLayer.html),
+//! the cache is best placed *above* the compression (ie. added to the router after it), so that
+//! the compressed bodies are cached instead of being compressed again on every hit.
+//!
+//! The responses already encoded by the wrapped service (with the `Content-Encoding` header) are
+//! only reused for requests with the same `Accept-Encoding` as the one that produced them, so
+//! the compressed bodies are never served to clients unable to decode them, regardless of the
+//! order of the layers. To compress the cached bodies without `tower-http`, see
+//! [`CacheLayer::compression`].
+//!
+//! ### Metadata
+//! The extensions of the responses are stored with them, so the typed metadata the handlers
+//! attach to the responses (eg. the upstream the response came from, or its cost) is served back
+//! with the hits too, for the outer middlewares or [`CacheLayer::decorate_hit`] to read. This
+//! includes the non-canonical reason phrase of the HTTP/1 status line, kept by `hyper` in its
+//! `ReasonPhrase` extension, so the legacy clients parsing it get the original one on hits.
+//!
+//! ```rust
+//! use axum::{body::Body, http::Request, response::IntoResponse, routing::get, Router};
+//! use axum_response_cache::CacheLayer;
+//! use tower::Service as _;
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! struct Upstream(&'static str);
+//!
+//! async fn handler() -> impl IntoResponse {
+//!     let mut response = "Hello, world!".into_response();
+//!     response.extensions_mut().insert(Upstream("primary"));
+//!     response
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let mut router = Router::new()
+//!     .route("/", get(handler))
+//!     .layer(CacheLayer::with_lifespan(60));
+//!
+//! for _ in 0..2 {
+//!     let response = router
+//!         .call(Request::get("/").body(Body::empty()).unwrap())
+//!         .await
+//!         .unwrap();
+//!     assert_eq!(Some(&Upstream("primary")), response.extensions().get());
+//! }
+//! # }
+//! ```
+//!
+//! The headers repeated in the response (eg. several `Set-Cookie`) are stored and served with
+//! all their values, in their original order. The header rules always act on all the values of
+//! the name: the updated header of the `304 Not Modified` response replaces all the stored
+//! values, and the removed (or refused) header is removed (or refused) whichever value it has.
+//!
+//! ### WebSockets
+//! The `101 Switching Protocols` responses upgrading the connection (eg. to a WebSocket) are
+//! always passed-through untouched, never buffered, stored or replaced by a stale response, so
+//! the WebSocket routes can be wrapped by a global cache. Their rejections (eg. `403 Forbidden`)
+//! are plain responses, handled like any other.
+//!
 //! ## Using custom cache
 //!
 //! ```rust
@@ -231,22 +294,74 @@
 //! be desirable to re-use the same responses from memory without re-calculating them – skipping requests to data
 //! bases, external services, reading from disk.
 
+pub use cache_control::CacheControl;
+pub use compression::Compression;
+pub use cost::{CostWeightedCache, ResponseLatency};
+pub use future::CacheFuture;
+pub use invalidation::{CacheKeys, InvalidationMsg};
+#[cfg(feature = "json")]
+pub use json::{JsonFieldAction, MalformedJson};
+pub use latency::{CacheLatency, LatencyHistogram};
+pub use snapshot::{EntrySnapshot, VariantSnapshot};
+pub use tiered::SecondaryCache;
+pub use write_behind::PersistentStore;
+
+mod cache_control;
+mod coalesce;
+mod compression;
+mod cost;
+#[cfg(feature = "digest")]
+mod digest;
+mod future;
+#[cfg(feature = "hot")]
+mod hot;
+mod invalidation;
+#[cfg(feature = "json")]
+mod json;
+mod latency;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod negotiation;
+mod snapshot;
+#[cfg(feature = "span-attributes")]
+mod span_attributes;
+mod tee;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tiered;
+#[cfg(feature = "watch")]
+mod watch;
+mod write_behind;
+
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     convert::Infallible,
     future::Future,
+    path::Path,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
+    time::Duration,
 };
 use tracing_futures::Instrument as _;
 
 use axum::{
     body::{Body, Bytes},
-    http::{response::Parts, Request, StatusCode},
+    extract::{FromRequestParts, MatchedPath, OriginalUri, State},
+    http::{
+        header, request::Parts as RequestParts, response::Parts, uri::Authority, HeaderMap,
+        HeaderName, HeaderValue, Method, Request, StatusCode, Uri,
+    },
+    middleware::Next,
     response::{IntoResponse, Response},
 };
 use cached::{Cached, CloneCached, TimedCache};
-use tower::{Layer, Service};
+use tokio::sync::broadcast;
+use tower::{util::BoxCloneService, Layer, Service, ServiceExt as _};
 use tracing::{debug, instrument};
 
 /// The caching key for the responses.
@@ -255,19 +370,809 @@ use tracing::{debug, instrument};
 /// ([`axum::http::Uri`]) of the request they responded to.
 type Key = (axum::http::Method, axum::http::Uri);
 
+/// The asynchronous predicate deciding whether the request should bypass the cache.
+type AsyncPredicate =
+    Arc<dyn Fn(&Request<Body>) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+/// The function building the URI part of the caching key and the TTL of the response from the
+/// request, see [`CacheLayer::key_and_ttl_fn`].
+type KeyFn = Arc<dyn Fn(&Request<Body>) -> (Uri, Option<Duration>) + Send + Sync>;
+
+/// The function hashing the request extension folded into the caching key, see
+/// [`CacheLayer::key_extension`].
+type ExtensionFn = fn(&axum::http::Extensions) -> Option<String>;
+
+/// The hook decorating the responses served from the cache, see [`CacheLayer::decorate_hit`].
+type HitDecorator = Arc<dyn Fn(&mut Response, &HitInfo) + Send + Sync>;
+
+/// The function computing the memory used by the cache, see
+/// [`CacheLayer::skip_stale_reinsert_above`].
+type UsageFn<C> = fn(&C) -> usize;
+
+/// The behaviour of the layer when the [`CacheLayer::async_skip_if`] predicate fails to resolve in
+/// time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuardFailure {
+    /// Fail open: handle the request with the cache as if the predicate returned `false`.
+    Cache,
+    /// Fail closed: bypass the cache as if the predicate returned `true`.
+    Bypass,
+}
+
+/// How the requests with the `Authorization` header are cached, see [`CacheLayer::auth_mode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMode {
+    /// Cache the responses under the key shared by all the users, regardless of the header.
+    /// It’s only safe when the responses don’t depend on the credentials (eg. the header is
+    /// checked by the wrapped service only to allow or deny the access), otherwise the response
+    /// for one user is served to all the others, including the unauthenticated ones.
+    Ignore,
+    /// Pass the requests to the wrapped service, neither serving nor storing the responses.
+    /// It’s the safe choice for the personalized responses, at the cost of not caching them.
+    Bypass,
+    /// Cache the responses separately for each credential, keyed by the hash of the header value
+    /// (the requests without the header share the responses). Each user gets their own copy of
+    /// the responses, so the cache grows with the number of users, and the responses stay
+    /// cached after the credential is revoked (until they expire) for anyone presenting it. The
+    /// responses must depend on nothing but the credential (eg. not on the cookies). The
+    /// responses advertise the keying to the downstream caches with `Vary: Authorization`.
+    ///
+    /// The [`InvalidationMsg`]s (and the file changes, see
+    /// `CacheLayer::invalidate_on_file_changes`) match the keys by the path, purging the
+    /// responses of all the users.
+    PrivateByCredential,
+}
+
+/// Which URI of the request the responses are keyed by, see [`CacheLayer::key_uri_source`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UriSource {
+    /// The URI as seen by the layer: stripped of the prefix the service is nested at (with
+    /// [`Router::nest`](axum::Router::nest) or [`Router::nest_service`](axum::Router::nest_service))
+    /// when the layer is inside the nested service, the full URI otherwise.
+    Nested,
+    /// The URI of the request as it was received by the outermost router (axum’s
+    /// [`OriginalUri`]), regardless of the nesting. The requests not routed by axum are keyed by
+    /// the URI seen by the layer.
+    Original,
+}
+
+/// The behaviour of the coalesced misses when the wrapped service fails the first one, see
+/// [`CacheLayer::coalesce_on_failure`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoalesceFailure {
+    /// Call the wrapped service for each of the waiting misses independently.
+    Retry,
+    /// Serve the failed response (with its buffered body) to all the waiting misses too, so the
+    /// failing service isn’t called again by each of them.
+    Share,
+}
+
+/// How the requests with a body not included in the key (eg. `GET` with a body, sent by some
+/// search APIs) are handled, see [`CacheLayer::get_with_body`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GetBodyPolicy {
+    /// Key the request by its method and URI only, logging a warning: the requests differing
+    /// only by their bodies share the responses.
+    Ignore,
+    /// Include the digest of the body in the key, like [`MethodPolicy::body_in_key`].
+    IncludeInKey,
+    /// Pass the request to the wrapped service, neither serving nor storing the response.
+    Bypass,
+}
+
+/// The caching decision made for the request by an earlier middleware (eg. the authentication
+/// middleware knowing the user sees personalized content), inserted as the request extension.
+/// The requests without the extension are handled as usual.
+///
+/// ```rust
+/// use axum::{extract::Request, middleware::Next, response::Response};
+/// use axum_response_cache::CachePolicy;
+///
+/// async fn personalized(mut request: Request, next: Next) -> Response {
+///     if request.headers().contains_key("x-user-id") {
+///         request.extensions_mut().insert(CachePolicy::Bypass);
+///     }
+///     next.run(request).await
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Handle the request as usual (eg. overriding the `Bypass` inserted by another middleware).
+    Cache,
+    /// Pass the request to the wrapped service, neither serving nor storing the response.
+    Bypass,
+}
+
+/// The caching behaviour for the requests with one HTTP method, see
+/// [`CacheLayer::method_policies`].
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use axum::http::Method;
+/// use axum_response_cache::{CacheLayer, MethodPolicy};
+///
+/// let layer = CacheLayer::with_lifespan(300).method_policies([
+///     (Method::GET, MethodPolicy::cached()),
+///     (
+///         Method::POST,
+///         MethodPolicy::cached()
+///             .ttl(Duration::from_secs(30))
+///             .body_in_key(),
+///     ),
+///     (Method::DELETE, MethodPolicy::bypassed()),
+/// ]);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MethodPolicy {
+    /// Whether the requests are handled by the cache (otherwise they bypass it).
+    pub cached: bool,
+    /// The TTL of the responses (unless set by [`CacheLayer::key_and_ttl_fn`]), instead of the
+    /// lifespan of the cache.
+    pub ttl: Option<Duration>,
+    /// Whether the digest of the request body is a part of the key.
+    pub body_in_key: bool,
+}
+
+impl MethodPolicy {
+    /// Create the policy caching the requests with the lifespan of the cache.
+    pub fn cached() -> Self {
+        Self {
+            cached: true,
+            ttl: None,
+            body_in_key: false,
+        }
+    }
+
+    /// Create the policy bypassing the cache.
+    pub fn bypassed() -> Self {
+        Self {
+            cached: false,
+            ..Self::cached()
+        }
+    }
+
+    /// Set the TTL of the cached responses.
+    pub fn ttl(self, ttl: Duration) -> Self {
+        Self {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Key the requests on their bodies too (eg. the search queries sent with `POST`). The bodies
+    /// are buffered up to the [`CacheLayer::body_limit`], and the requests with bigger bodies (or
+    /// of unknown size) bypass the cache.
+    pub fn body_in_key(self) -> Self {
+        Self {
+            body_in_key: true,
+            ..self
+        }
+    }
+}
+
+/// The digest of the request body, see [`MethodPolicy::body_in_key`].
+#[derive(Clone, Debug)]
+struct BodyDigest(String);
+
+/// The mark of the request with the right force-store secret, see
+/// [`CacheLayer::force_store_header`].
+#[derive(Clone, Copy, Debug)]
+struct ForceStore;
+
+/// Check whether the request may have a body (ie. its body isn’t known to be empty).
+fn has_body(request: &Request<Body>) -> bool {
+    let body = request.body();
+    !http_body::Body::is_end_stream(body) && http_body::Body::size_hint(body).exact() != Some(0)
+}
+
+/// Buffer the request body if its size is known not to exceed the limit and attach its digest
+/// to the request, leaving the other bodies unread. The failure to read the body is answered
+/// with `400 BAD REQUEST`.
+async fn digest_body(request: Request<Body>, limit: usize) -> Result<Request<Body>, Response> {
+    let size = http_body::Body::size_hint(request.body()).upper();
+    if size.is_none_or(|size| size > limit as u64) {
+        return Ok(request);
+    }
+    let (mut parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, limit).await else {
+        return Err((StatusCode::BAD_REQUEST, "Failed to read the request body").into_response());
+    };
+    let digest = blake3::hash(&body).to_hex()[..32].to_owned();
+    parts.extensions.insert(BodyDigest(digest));
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+/// Check whether the earlier middleware decided the request bypasses the cache.
+fn bypassed_by_policy(request: &Request<Body>) -> bool {
+    request.extensions().get::<CachePolicy>() == Some(&CachePolicy::Bypass)
+}
+
+/// Return the reason for the request to bypass the cache (for the debug log), or `None` when
+/// it’s looked up.
+fn bypass_reason(request: &Request<Body>, options: BypassOptions<'_>) -> Option<&'static str> {
+    let headers = request.headers();
+    let policy = options
+        .method_policies
+        .and_then(|policies| policies.get(request.method()));
+    if is_preflight(request) && !options.cache_preflight {
+        Some("CORS preflight request")
+    } else if (options.auth_mode == AuthMode::Bypass && headers.contains_key(header::AUTHORIZATION))
+        || (options.bypass_cookie && headers.contains_key(header::COOKIE))
+    {
+        Some("Request carries credentials")
+    } else if bypassed_by_policy(request) {
+        Some("Request’s cache policy is to bypass")
+    } else if options.request_cache_control && CacheControl::from_headers(headers).no_store {
+        Some("Request’s Cache-Control is no-store")
+    } else if policy.is_some_and(|policy| !policy.cached) {
+        Some("Request’s method isn’t cached")
+    } else if options.get_body_policy == GetBodyPolicy::Bypass
+        && !policy.is_some_and(|policy| policy.body_in_key)
+        && has_body(request)
+    {
+        Some("Request has a body")
+    } else {
+        None
+    }
+}
+
 /// The struct preserving all the headers and body of the cached response.
 #[derive(Clone, Debug)]
 pub struct CachedResponse {
     parts: Parts,
     body: Bytes,
-    timestamp: Option<std::time::Instant>,
+    timestamp: std::time::Instant,
+    // when the body was fetched, kept when the response is revalidated
+    fetched: std::time::Instant,
+    requested: std::time::Instant,
+    age_header: bool,
+    compression: Option<Compression>,
+    vary: Vec<(HeaderName, HeaderValue)>,
+    stale_while_revalidate: Option<Duration>,
+    stale_if_error: Option<Duration>,
+    ttl: Option<Duration>,
+    host: Option<HeaderValue>,
+    origin: Option<Arc<str>>,
+    soft_invalidated: bool,
+    body_hash: Option<blake3::Hash>,
+    // the digest of the body served decompressed, see `CacheLayer::content_digest`
+    #[cfg(feature = "digest")]
+    identity_digest: Option<HeaderValue>,
+}
+
+impl CachedResponse {
+    /// Return the media type of the response (its `Content-Type` without the parameters).
+    fn media_type(&self) -> Option<String> {
+        let content_type = self
+            .parts
+            .headers
+            .get(header::CONTENT_TYPE)?
+            .to_str()
+            .ok()?;
+        let media_type = content_type.split(';').next().unwrap_or_default();
+        Some(media_type.trim().to_ascii_lowercase())
+    }
+
+    /// Create a new response to be cached from its parts and full body.
+    pub fn new(parts: Parts, body: Bytes) -> Self {
+        let cache_control = CacheControl::from_headers(&parts.headers);
+        Self {
+            stale_while_revalidate: cache_control.stale_while_revalidate,
+            stale_if_error: cache_control.stale_if_error,
+            parts,
+            body,
+            timestamp: std::time::Instant::now(),
+            fetched: std::time::Instant::now(),
+            requested: std::time::Instant::now(),
+            age_header: false,
+            compression: None,
+            vary: Vec::new(),
+            ttl: None,
+            host: None,
+            origin: None,
+            soft_invalidated: false,
+            body_hash: None,
+            #[cfg(feature = "digest")]
+            identity_digest: None,
+        }
+    }
+
+    /// Check whether the response expired (according to its own TTL or the cache’s lifespan)
+    /// less than the given window ago.
+    fn within_stale_window(&self, window: Option<Duration>, lifespan: Option<u64>) -> bool {
+        match (window, self.ttl.or(lifespan.map(Duration::from_secs))) {
+            (Some(window), Some(ttl)) => self.timestamp.elapsed() <= ttl + window,
+            _ => false,
+        }
+    }
+
+    /// Check whether the client accepts the response by the directives of its request, see
+    /// [`CacheLayer::respect_request_cache_control`].
+    fn satisfies(&self, directives: &CacheControl) -> bool {
+        !directives.no_cache
+            && directives
+                .max_age
+                .is_none_or(|max_age| self.timestamp.elapsed() <= max_age)
+    }
+
+    /// Make the response fresh again after the wrapped service confirmed it with `304 NOT
+    /// MODIFIED`, updating the stored headers with the ones from that response.
+    fn revalidate(
+        &mut self,
+        updated_headers: &HeaderMap,
+        requested: std::time::Instant,
+        ttl: Option<Duration>,
+    ) {
+        merge_headers(&mut self.parts.headers, updated_headers);
+        let cache_control = CacheControl::from_headers(&self.parts.headers);
+        self.stale_while_revalidate = cache_control.stale_while_revalidate;
+        self.stale_if_error = cache_control.stale_if_error;
+        self.timestamp = std::time::Instant::now();
+        self.requested = requested;
+        self.ttl = ttl;
+    }
+
+    /// Check whether the `If-Range` precondition of the request (if any) matches the stored
+    /// `ETag` or `Last-Modified`, ie. whether the requested range can be served.
+    fn if_range_matches(&self, if_range: Option<&HeaderValue>) -> bool {
+        let Some(if_range) = if_range else {
+            return true;
+        };
+        let headers = &self.parts.headers;
+        // only the strong entity tags can be used for ranges
+        let etag = headers
+            .get(header::ETAG)
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"));
+        etag == Some(if_range) || headers.get(header::LAST_MODIFIED) == Some(if_range)
+    }
+
+    /// Check whether this variant of the response can be served to a request with the given
+    /// headers, ie. whether all the request headers listed in its `Vary` match.
+    fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| vary_value(request_headers, name.clone()) == value)
+    }
+
+    /// Serve the response as a cache hit, with the headers added on serve and decorated with the
+    /// hook of the layer.
+    fn serve_hit(self, options: &ServeOptions, outcome: HitOutcome) -> Response {
+        let age = self.timestamp.elapsed();
+        let mut response = self.serve(options);
+        if let Some(serve_headers) = &options.serve_headers {
+            let headers = response.headers_mut();
+            for name in serve_headers.keys() {
+                if headers.contains_key(name) {
+                    continue;
+                }
+                for value in serve_headers.get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        if let Some(decorate) = &options.decorate_hit {
+            let info = HitInfo {
+                outcome,
+                age,
+                lookup: options.lookup,
+            };
+            decorate(&mut response, &info);
+        }
+        response
+    }
+
+    /// Convert the cached value into a response, decompressing the stored body unless the client
+    /// accepts its encoding, optionally refreshing its `Date` and `Age` headers and serving the
+    /// requested byte range.
+    fn serve(mut self, options: &ServeOptions) -> Response {
+        if options.refresh_date {
+            let age = self.timestamp.elapsed().as_secs()
+                + self
+                    .parts
+                    .headers
+                    .get(header::AGE)
+                    .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+                    .unwrap_or(0);
+            let date = httpdate::fmt_http_date(std::time::SystemTime::now());
+            let headers = &mut self.parts.headers;
+            headers.insert(header::DATE, HeaderValue::from_str(&date).unwrap());
+            if age > 0 || headers.contains_key(header::AGE) {
+                headers.insert(header::AGE, HeaderValue::from(age));
+            }
+        }
+        if let (StatusCode::TOO_MANY_REQUESTS, Some(ttl)) = (self.parts.status, self.ttl) {
+            // the client waits only for the rest of the stored window
+            let remaining = ttl.saturating_sub(self.timestamp.elapsed());
+            let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            if self.parts.headers.contains_key(header::RETRY_AFTER) {
+                self.parts
+                    .headers
+                    .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+            }
+        }
+        if let (Some(compression), false) = (self.compression, options.accepts_compressed) {
+            match compression.decompress(&self.body) {
+                Ok(body) => {
+                    self.body = Bytes::from(body);
+                    self.parts.headers.remove(header::CONTENT_ENCODING);
+                    #[cfg(feature = "digest")]
+                    if let Some(digest) = &self.identity_digest {
+                        self.parts
+                            .headers
+                            .insert(digest::REPR_DIGEST, digest.clone());
+                    }
+                }
+                Err(err) => {
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to decompress cached response: {err}"),
+                    )
+                        .into_response();
+                }
+            }
+        }
+        if let (Some(host), Some(origin)) = (&options.host, &self.host) {
+            if let Some(location) = self
+                .parts
+                .headers
+                .get(header::LOCATION)
+                .and_then(|location| rewrite_location(location, origin, host))
+            {
+                self.parts.headers.insert(header::LOCATION, location);
+            }
+        }
+        if options.ranges && self.parts.status == StatusCode::OK {
+            self.parts
+                .headers
+                .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            let range = options
+                .range
+                .as_ref()
+                .filter(|_| self.if_range_matches(options.if_range.as_ref()))
+                .and_then(|range| byte_range(range, self.body.len()));
+            let length = self.body.len();
+            match range {
+                Some(ByteRange::Satisfiable(start, end)) => {
+                    self.parts.status = StatusCode::PARTIAL_CONTENT;
+                    self.body = self.body.slice(start..=end);
+                    let headers = &mut self.parts.headers;
+                    headers.insert(
+                        header::CONTENT_RANGE,
+                        HeaderValue::from_str(&format!("bytes {start}-{end}/{length}")).unwrap(),
+                    );
+                    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+                }
+                Some(ByteRange::Unsatisfiable) => {
+                    return (
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        [(header::CONTENT_RANGE, format!("bytes */{length}"))],
+                    )
+                        .into_response();
+                }
+                None => {}
+            }
+        }
+        self.into_response()
+    }
+}
+
+/// The settings of the layer affecting how the requests are keyed, see [`request_key`].
+#[derive(Clone, Copy)]
+struct KeyOptions<'a> {
+    by_matched_path: bool,
+    path_and_query_only: bool,
+    uri_source: UriSource,
+    vary_by_host: bool,
+    trusted_headers: &'a [HeaderName],
+    body_keyed: bool,
+    auth_mode: AuthMode,
+    hashed: bool,
+}
+
+/// The settings of the layer deciding which requests bypass the cache, see [`bypass_reason`].
+#[derive(Clone, Copy)]
+struct BypassOptions<'a> {
+    cache_preflight: bool,
+    auth_mode: AuthMode,
+    bypass_cookie: bool,
+    request_cache_control: bool,
+    method_policies: Option<&'a HashMap<Method, MethodPolicy>>,
+    get_body_policy: GetBodyPolicy,
+}
+
+/// The parameters of the request affecting how the cached responses are served to it.
+#[derive(Clone, Default)]
+struct ServeOptions {
+    accepts_compressed: bool,
+    refresh_date: bool,
+    ranges: bool,
+    range: Option<HeaderValue>,
+    if_range: Option<HeaderValue>,
+    host: Option<HeaderValue>,
+    decorate_hit: Option<HitDecorator>,
+    serve_headers: Option<Arc<HeaderMap>>,
+    lookup: Duration,
+}
+
+/// The byte range requested with the `Range` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// The inclusive range of the body’s bytes to serve.
+    Satisfiable(usize, usize),
+    /// The range starting past the end of the body.
+    Unsatisfiable,
+}
+
+/// Parse the single byte range (eg. `bytes=0-499`, `bytes=500-` or `bytes=-500`) from the
+/// `Range` header for the body of the given length. Multiple and malformed ranges are ignored,
+/// so the whole body is served.
+fn byte_range(range: &HeaderValue, length: usize) -> Option<ByteRange> {
+    let spec = range.to_str().ok()?.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let (start, end) = match (start.is_empty(), end.is_empty()) {
+        // suffix range: the last `end` bytes
+        (true, false) => {
+            let suffix: usize = end.parse().ok()?;
+            if suffix == 0 {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            (length.saturating_sub(suffix), length.checked_sub(1)?)
+        }
+        (false, true) => (start.parse().ok()?, length.saturating_sub(1)),
+        (false, false) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(length.saturating_sub(1)))
+        }
+        (true, true) => return None,
+    };
+    if start >= length {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Satisfiable(start, end))
+}
+
+/// All the variants of the response cached for one key.
+///
+/// The variants are selected by the request headers listed in the `Vary` header of the
+/// responses (and by `Accept-Encoding` for the responses encoded by the wrapped service), so
+/// multiple correct variants of the response to the same method and URI can coexist.
+#[derive(Clone, Debug, Default)]
+pub struct CachedEntry {
+    variants: Vec<CachedResponse>,
+    hits: u64,
+}
+
+impl CachedEntry {
+    /// Check whether the entry was hit often enough to be promoted to the extended TTL, and its
+    /// newest variant is still within that TTL.
+    fn is_promoted(&self, (min_hits, extended_ttl): (u64, Duration)) -> bool {
+        self.hits >= min_hits
+            && self
+                .variants
+                .iter()
+                .map(|variant| variant.timestamp)
+                .max()
+                .is_some_and(|timestamp| timestamp.elapsed() < extended_ttl)
+    }
+
+    /// Check whether the newest variant outlived its own TTL (see [`CacheLayer::key_and_ttl_fn`]),
+    /// or `None` if it doesn’t have one.
+    fn ttl_expired(&self) -> Option<bool> {
+        let newest = self
+            .variants
+            .iter()
+            .max_by_key(|variant| variant.timestamp)?;
+        newest.ttl.map(|ttl| newest.timestamp.elapsed() >= ttl)
+    }
+
+    /// Sum the sizes of the stored bodies and headers of all the variants.
+    fn size(&self) -> usize {
+        self.variants
+            .iter()
+            .map(|value| value.body.len() + headers_size(&value.parts.headers))
+            .sum()
+    }
+
+    /// Return the variant matching the request headers.
+    fn select(self, request_headers: &HeaderMap) -> Option<CachedResponse> {
+        self.variants
+            .into_iter()
+            .find(|variant| variant.matches(request_headers))
+    }
+
+    /// Borrow the variant matching the request headers, see [`CachedEntry::select`].
+    fn variant(&self, request_headers: &HeaderMap) -> Option<&CachedResponse> {
+        self.variants
+            .iter()
+            .find(|variant| variant.matches(request_headers))
+    }
+
+    /// Check whether the variant has the same media type as the first variant of the entry (any
+    /// variant is accepted by an empty entry).
+    fn accepts_media_type(&self, value: &CachedResponse) -> bool {
+        self.variants
+            .first()
+            .is_none_or(|first| first.media_type() == value.media_type())
+    }
+
+    /// Check whether the variant with the same `Vary` signature was fetched by a request started
+    /// after the one of the given variant.
+    fn has_newer(&self, value: &CachedResponse) -> bool {
+        self.variants
+            .iter()
+            .any(|variant| variant.vary == value.vary && variant.requested > value.requested)
+    }
+
+    /// Mark the variant with the given `Vary` signature as the most recently used one.
+    fn touch(&mut self, vary: &[(HeaderName, HeaderValue)]) {
+        if let Some(index) = self
+            .variants
+            .iter()
+            .position(|variant| variant.vary == vary)
+        {
+            let variant = self.variants.remove(index);
+            self.variants.push(variant);
+        }
+    }
+
+    /// Add the variant, replacing the one with the same `Vary` signature.
+    fn insert(&mut self, value: CachedResponse) {
+        self.variants.retain(|variant| variant.vary != value.vary);
+        self.variants.push(value);
+    }
+}
+
+/// The snapshot of one cached variant of the response, see [`CacheLayer::dump`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CachedEntryInfo {
+    /// The status of the cached response.
+    pub status: StatusCode,
+    /// The headers of the cached response.
+    pub headers: HeaderMap,
+    /// The request headers (with their values) selecting this variant of the response.
+    pub vary: Vec<(HeaderName, HeaderValue)>,
+    /// The size of the stored (possibly compressed) body in bytes.
+    pub body_size: usize,
+    /// The time elapsed since the response was stored.
+    pub age: Duration,
+    /// The stored (possibly compressed) body, only included by [`CacheLayer::dump_full`].
+    pub body: Option<Bytes>,
+    /// The signature of the request the response was stored for (its method, URI and `Host`),
+    /// only recorded with [`CacheLayer::record_origin_request`].
+    pub origin_request: Option<String>,
+}
+
+impl CachedEntryInfo {
+    fn new(value: &CachedResponse, include_body: bool) -> Self {
+        Self {
+            status: value.parts.status,
+            headers: value.parts.headers.clone(),
+            vary: value.vary.clone(),
+            body_size: value.body.len(),
+            age: value.timestamp.elapsed(),
+            body: include_body.then(|| value.body.clone()),
+            origin_request: value.origin.as_deref().map(str::to_owned),
+        }
+    }
+}
+
+/// How the response served from the cache was resolved, see [`CacheLayer::decorate_hit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HitOutcome {
+    /// The fresh response was found in the cache.
+    Fresh,
+    /// The response was found in the secondary cache, see [`CacheLayer::with_tiers`].
+    Secondary,
+    /// The stale response was revalidated by the wrapped service (`304 NOT MODIFIED`).
+    Revalidated,
+    /// The stale response was served, because the wrapped service failed (or is being called
+    /// in the background, or isn’t called in the cache-only mode).
+    Stale,
+}
+
+/// The details of the response served from the cache, see [`CacheLayer::decorate_hit`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct HitInfo {
+    /// How the response was resolved.
+    pub outcome: HitOutcome,
+    /// The time elapsed since the response was stored.
+    pub age: Duration,
+    /// The time spent looking the request up in the cache.
+    pub lookup: Duration,
+}
+
+/// Whether the request reaching the wrapped service is going to be served from the cache.
+///
+/// Fresh cache hits never reach the wrapped service, thus there is no status for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// There is no response cached for the request.
+    Miss,
+    /// The cached response expired and is being refreshed.
+    Stale,
+    /// The cache is bypassed for the request.
+    Bypass,
+}
+
+/// The extractor providing the handlers of the wrapped service with the cache state of the
+/// request (eg. for cache-aware logging).
+///
+/// ```rust
+/// use axum::{Router, routing::get};
+/// use axum_response_cache::{CacheInfo, CacheLayer};
+///
+/// async fn handler(info: CacheInfo) -> String {
+///     format!("{:?} for {} {}", info.status(), info.method(), info.uri())
+/// }
+///
+/// let router: Router = Router::new()
+///     .route("/", get(handler))
+///     .layer(CacheLayer::with_lifespan(60));
+/// ```
+///
+/// Extracting it from a request that didn’t pass through a [`CacheLayer`] fails with
+/// `500 INTERNAL SERVER ERROR`.
+#[derive(Clone, Debug)]
+pub struct CacheInfo {
+    status: CacheStatus,
+    key: Key,
+    age: Option<std::time::Duration>,
+}
+
+impl CacheInfo {
+    /// The cache status of the request.
+    pub fn status(&self) -> CacheStatus {
+        self.status
+    }
+
+    /// The HTTP method used in the caching key.
+    pub fn method(&self) -> &axum::http::Method {
+        &self.key.0
+    }
+
+    /// The URI used in the caching key.
+    pub fn uri(&self) -> &axum::http::Uri {
+        &self.key.1
+    }
+
+    /// The age of the stale response currently cached for the request, if any.
+    pub fn age(&self) -> Option<std::time::Duration> {
+        self.age
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CacheInfo {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut RequestParts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<CacheInfo>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Missing cache info, is the CacheLayer applied?",
+        ))
+    }
 }
 
 impl IntoResponse for CachedResponse {
     fn into_response(self) -> Response {
         let mut response = Response::from_parts(self.parts, Body::from(self.body));
-        if let Some(timestamp) = self.timestamp {
-            let age = timestamp.elapsed().as_secs();
+        if self.age_header {
+            let age = self.timestamp.elapsed().as_secs();
             response
                 .headers_mut()
                 .insert("X-Cache-Age", age.to_string().parse().unwrap());
@@ -277,27 +1182,277 @@ impl IntoResponse for CachedResponse {
 }
 
 /// The main struct of the library. The layer providing caching to the wrapped service.
-#[derive(Clone)]
 pub struct CacheLayer<C> {
     cache: Arc<Mutex<C>>,
+    secondary: Option<tiered::Secondary>,
+    secondary_backpressure: bool,
     use_stale: bool,
+    stale_server_errors_only: bool,
+    stale_directives: bool,
+    revalidate: bool,
+    upstream_not_modified: bool,
+    max_revalidation_age: Option<Duration>,
+    stale_reinsert: bool,
+    reinsert_limit: Option<(usize, UsageFn<C>)>,
+    promote: Option<(u64, Duration)>,
+    #[cfg(feature = "hot")]
+    hot: Option<Arc<hot::Hot>>,
     limit: usize,
+    tee_streaming: bool,
+    oversized: Arc<Mutex<HashMap<Key, std::time::Instant>>>,
+    oversized_cooldown: Option<Duration>,
+    max_header_bytes: usize,
     allow_invalidation: bool,
+    request_cache_control: bool,
+    force_store: Option<(HeaderName, HeaderValue)>,
+    key_by_matched_path: bool,
+    key_path_and_query_only: bool,
+    uri_source: UriSource,
+    vary_by_host: bool,
+    trusted_headers: Arc<[HeaderName]>,
+    image_formats: Option<Arc<[HeaderValue]>>,
+    key_fn: Option<KeyFn>,
+    ttl_bounds: Option<(Duration, Duration)>,
+    hashed_keys: bool,
+    key_extension: Option<ExtensionFn>,
+    cache_preflight: bool,
     add_response_headers: bool,
+    refresh_date: bool,
+    serve_ranges: bool,
+    forward_cold_ranges: bool,
+    rewrite_location_host: bool,
+    dedup_link_headers: bool,
+    reuse_unchanged_bodies: bool,
+    record_origin: bool,
+    consistent_content_type: bool,
+    max_variants: Option<usize>,
+    newest_wins: bool,
+    measure_latency: bool,
+    lock_warning: Option<Duration>,
+    cache_latency: Option<Arc<latency::Latencies>>,
+    invalidation_signal: Option<(HeaderName, HeaderValue)>,
+    debug_key_header: Option<HeaderName>,
+    client_cache_control: Option<HeaderValue>,
+    coalesce_misses: bool,
+    coalesce_failure: CoalesceFailure,
+    coalesce_only: bool,
+    flights: coalesce::Flights,
+    method_policies: Option<Arc<HashMap<Method, MethodPolicy>>>,
+    get_body_policy: GetBodyPolicy,
+    invalidated: Arc<invalidation::Invalidated>,
+    decorate_hit: Option<HitDecorator>,
+    serve_headers: Option<Arc<HeaderMap>>,
+    compression: Option<Compression>,
+    file_backed: Option<(Arc<Path>, usize)>,
+    content_digest: bool,
+    async_skip: Option<AsyncPredicate>,
+    async_skip_timeout: Duration,
+    async_skip_failure: GuardFailure,
+    cache_only: Option<Arc<AtomicBool>>,
+    #[cfg(feature = "json")]
+    json_normalization: Option<json::Normalization>,
+    refuse_set_cookie: bool,
+    cache_rate_limits: bool,
+    refused_headers: Arc<[HeaderName]>,
+    allowed_headers: Option<Arc<[HeaderName]>>,
+    content_types: Option<Arc<[String]>>,
+    auth_mode: AuthMode,
+    bypass_cookie: bool,
+}
+
+// implemented manually, as the cache itself is shared and doesn’t have to be `Clone`
+impl<C> Clone for CacheLayer<C> {
+    fn clone(&self) -> Self {
+        Self {
+            cache: Arc::clone(&self.cache),
+            secondary: self.secondary.clone(),
+            secondary_backpressure: self.secondary_backpressure,
+            use_stale: self.use_stale,
+            stale_server_errors_only: self.stale_server_errors_only,
+            stale_directives: self.stale_directives,
+            revalidate: self.revalidate,
+            upstream_not_modified: self.upstream_not_modified,
+            max_revalidation_age: self.max_revalidation_age,
+            stale_reinsert: self.stale_reinsert,
+            reinsert_limit: self.reinsert_limit,
+            promote: self.promote,
+            #[cfg(feature = "hot")]
+            hot: self.hot.clone(),
+            limit: self.limit,
+            tee_streaming: self.tee_streaming,
+            oversized: self.oversized.clone(),
+            oversized_cooldown: self.oversized_cooldown,
+            max_header_bytes: self.max_header_bytes,
+            allow_invalidation: self.allow_invalidation,
+            request_cache_control: self.request_cache_control,
+            force_store: self.force_store.clone(),
+            key_by_matched_path: self.key_by_matched_path,
+            key_path_and_query_only: self.key_path_and_query_only,
+            uri_source: self.uri_source,
+            vary_by_host: self.vary_by_host,
+            trusted_headers: self.trusted_headers.clone(),
+            image_formats: self.image_formats.clone(),
+            key_fn: self.key_fn.clone(),
+            ttl_bounds: self.ttl_bounds,
+            hashed_keys: self.hashed_keys,
+            key_extension: self.key_extension,
+            cache_preflight: self.cache_preflight,
+            add_response_headers: self.add_response_headers,
+            refresh_date: self.refresh_date,
+            serve_ranges: self.serve_ranges,
+            forward_cold_ranges: self.forward_cold_ranges,
+            rewrite_location_host: self.rewrite_location_host,
+            dedup_link_headers: self.dedup_link_headers,
+            reuse_unchanged_bodies: self.reuse_unchanged_bodies,
+            record_origin: self.record_origin,
+            consistent_content_type: self.consistent_content_type,
+            max_variants: self.max_variants,
+            newest_wins: self.newest_wins,
+            measure_latency: self.measure_latency,
+            lock_warning: self.lock_warning,
+            cache_latency: self.cache_latency.clone(),
+            invalidation_signal: self.invalidation_signal.clone(),
+            debug_key_header: self.debug_key_header.clone(),
+            client_cache_control: self.client_cache_control.clone(),
+            coalesce_misses: self.coalesce_misses,
+            coalesce_failure: self.coalesce_failure,
+            coalesce_only: self.coalesce_only,
+            flights: Arc::clone(&self.flights),
+            method_policies: self.method_policies.clone(),
+            get_body_policy: self.get_body_policy,
+            invalidated: Arc::clone(&self.invalidated),
+            decorate_hit: self.decorate_hit.clone(),
+            serve_headers: self.serve_headers.clone(),
+            compression: self.compression,
+            file_backed: self.file_backed.clone(),
+            content_digest: self.content_digest,
+            async_skip: self.async_skip.clone(),
+            async_skip_timeout: self.async_skip_timeout,
+            async_skip_failure: self.async_skip_failure,
+            cache_only: self.cache_only.clone(),
+            #[cfg(feature = "json")]
+            json_normalization: self.json_normalization.clone(),
+            refuse_set_cookie: self.refuse_set_cookie,
+            cache_rate_limits: self.cache_rate_limits,
+            refused_headers: self.refused_headers.clone(),
+            allowed_headers: self.allowed_headers.clone(),
+            content_types: self.content_types.clone(),
+            auth_mode: self.auth_mode,
+            bypass_cookie: self.bypass_cookie,
+        }
+    }
 }
 
 impl<C> CacheLayer<C>
 where
-    C: Cached<Key, CachedResponse> + CloneCached<Key, CachedResponse>,
+    C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry>,
 {
     /// Create a new cache layer with a given cache and the default body size limit of 128 MB.
     pub fn with(cache: C) -> Self {
         Self {
             cache: Arc::new(Mutex::new(cache)),
+            secondary: None,
+            secondary_backpressure: false,
             use_stale: false,
+            stale_server_errors_only: false,
+            stale_directives: false,
+            revalidate: false,
+            upstream_not_modified: false,
+            max_revalidation_age: None,
+            stale_reinsert: true,
+            reinsert_limit: None,
+            promote: None,
+            #[cfg(feature = "hot")]
+            hot: None,
             limit: 128 * 1024 * 1024,
+            tee_streaming: false,
+            oversized: Arc::default(),
+            oversized_cooldown: None,
+            max_header_bytes: usize::MAX,
             allow_invalidation: false,
+            request_cache_control: false,
+            force_store: None,
+            key_by_matched_path: false,
+            key_path_and_query_only: false,
+            uri_source: UriSource::Nested,
+            vary_by_host: false,
+            trusted_headers: Arc::new([]),
+            image_formats: None,
+            key_fn: None,
+            ttl_bounds: None,
+            hashed_keys: false,
+            key_extension: None,
+            cache_preflight: false,
             add_response_headers: false,
+            refresh_date: false,
+            serve_ranges: false,
+            forward_cold_ranges: false,
+            rewrite_location_host: false,
+            dedup_link_headers: false,
+            reuse_unchanged_bodies: false,
+            record_origin: false,
+            consistent_content_type: false,
+            max_variants: None,
+            newest_wins: false,
+            measure_latency: false,
+            lock_warning: None,
+            cache_latency: None,
+            invalidation_signal: None,
+            debug_key_header: None,
+            client_cache_control: None,
+            coalesce_misses: false,
+            coalesce_failure: CoalesceFailure::Retry,
+            coalesce_only: false,
+            flights: Arc::default(),
+            method_policies: None,
+            get_body_policy: GetBodyPolicy::Ignore,
+            invalidated: Arc::default(),
+            decorate_hit: None,
+            serve_headers: None,
+            compression: None,
+            file_backed: None,
+            content_digest: false,
+            async_skip: None,
+            async_skip_timeout: Duration::from_secs(1),
+            async_skip_failure: GuardFailure::Bypass,
+            cache_only: None,
+            #[cfg(feature = "json")]
+            json_normalization: None,
+            refuse_set_cookie: false,
+            cache_rate_limits: false,
+            refused_headers: Arc::new([]),
+            allowed_headers: None,
+            content_types: None,
+            auth_mode: AuthMode::Ignore,
+            bypass_cookie: false,
+        }
+    }
+
+    /// Create a new two-tier cache layer: the responses are looked up in the given cache first,
+    /// then in the secondary cache (eg. a shared store like Redis), and fetched from the wrapped
+    /// service only when missing in both. The responses found in the secondary cache are
+    /// promoted back into the first one, and the responses stored in the first cache are written
+    /// through to the secondary one in the background.
+    ///
+    /// Invalidating and flushing the entries affects only the first cache.
+    pub fn with_tiers<S>(cache: C, secondary: S) -> Self
+    where
+        S: SecondaryCache + 'static,
+    {
+        Self {
+            secondary: Some(Arc::new(secondary)),
+            ..Self::with(cache)
+        }
+    }
+
+    /// Apply backpressure from the secondary cache: the service isn’t ready (its `poll_ready`
+    /// returns `Poll::Pending`) until the secondary cache can accept more writes, see
+    /// [`SecondaryCache::poll_write_capacity`]. Without the secondary cache (or when the store
+    /// has unbounded capacity) the readiness of the wrapped service is passed-through.
+    pub fn secondary_backpressure(self) -> Self {
+        Self {
+            secondary_backpressure: true,
+            ..self
         }
     }
 
@@ -305,6 +1460,11 @@ where
     /// from the cache but the service failed to provide a new successful response (ie. eg. when
     /// the underlying service responds with `404 NOT FOUND`, the cache will keep providing the last stale `200 OK`
     /// response produced).
+    ///
+    /// The expired response is refreshed by the request finding it, so the refresh is cancelled
+    /// (the wrapped service’s future dropped) when the client disconnects. Only the background
+    /// refreshes (see [`CacheLayer::honor_stale_directives`] and
+    /// [`CacheLayer::soft_invalidate`]) outlive their requests.
     pub fn use_stale_on_failure(self) -> Self {
         Self {
             use_stale: true,
@@ -312,565 +1472,9118 @@ where
         }
     }
 
-    /// Change the maximum body size limit. If you want unlimited size, use [`usize::MAX`].
-    pub fn body_limit(self, new_limit: usize) -> Self {
+    /// Like [`CacheLayer::use_stale_on_failure`], but keep providing the stale response only when
+    /// the service fails with a server error (`5xx`), following the semantics of the
+    /// `stale-if-error` directive. Client errors (eg. `404 NOT FOUND`) evict the stale response
+    /// and are passed-through.
+    pub fn stale_on_server_error(self) -> Self {
         Self {
-            limit: new_limit,
+            use_stale: true,
+            stale_server_errors_only: true,
             ..self
         }
     }
 
-    /// Allow manual cache invalidation by setting the `X-Invalidate-Cache` header in the request.
-    /// This will allow the cache to be invalidated for the given key.
-    pub fn allow_invalidation(self) -> Self {
+    /// Store the `429 TOO MANY REQUESTS` responses with the `Retry-After` header for the
+    /// duration it indicates, serving them without calling the wrapped service until the window
+    /// passes, so the backoff is enforced uniformly on all the clients. The served `Retry-After`
+    /// is decreased by the age of the stored response.
+    ///
+    /// The responses without the header (or with the window already passed) are
+    /// passed-through as usual. The window is limited by [`CacheLayer::clamp_ttl`]. When the
+    /// stale response is served instead (see [`CacheLayer::use_stale_on_failure`]), the rate
+    /// limit isn’t stored in its place, and the stored rate limit is never served as the stale
+    /// response itself.
+    pub fn cache_rate_limits(self) -> Self {
         Self {
-            allow_invalidation: true,
+            cache_rate_limits: true,
             ..self
         }
     }
 
-    /// Allow the response headers to be included in the cached response.
-    pub fn add_response_headers(self) -> Self {
+    /// Honor the `stale-while-revalidate` and `stale-if-error` directives of the responses’
+    /// `Cache-Control` header, measured from the expiration of the entry in the cache.
+    ///
+    /// Within the `stale-while-revalidate` window the expired response is served immediately
+    /// while the wrapped service refreshes it in the background. Within the `stale-if-error`
+    /// window the expired response is served when the service fails with a server error (`5xx`),
+    /// regardless of [`CacheLayer::use_stale_on_failure`].
+    ///
+    /// The windows can only be applied with the caches reporting their lifespan (eg.
+    /// [`TimedCache`] and [`cached::TimedSizedCache`]).
+    pub fn honor_stale_directives(self) -> Self {
         Self {
-            add_response_headers: true,
+            stale_directives: true,
             ..self
         }
     }
-}
 
-impl CacheLayer<TimedCache<Key, CachedResponse>> {
-    /// Create a new cache layer with the desired TTL in seconds
-    pub fn with_lifespan(ttl_sec: u64) -> CacheLayer<TimedCache<Key, CachedResponse>> {
-        CacheLayer::with(TimedCache::with_lifespan(ttl_sec))
+    /// Revalidate the expired responses with the wrapped service instead of fetching them again,
+    /// by sending the request with the `If-None-Match` and `If-Modified-Since` headers built from
+    /// the stored `ETag` and `Last-Modified`. When the service responds with `304 NOT MODIFIED`,
+    /// the stored headers are updated with the ones from that response (eg. new `Cache-Control`),
+    /// the stored body is kept and the entry becomes fresh again.
+    ///
+    /// Conditional requests sent by the clients themselves are passed to the service unchanged.
+    pub fn revalidate_stale(self) -> Self {
+        Self {
+            revalidate: true,
+            ..self
+        }
     }
-}
-
-impl<S, C> Layer<S> for CacheLayer<C> {
-    type Service = CacheService<S, C>;
 
-    fn layer(&self, inner: S) -> Self::Service {
-        Self::Service {
-            inner,
-            cache: Arc::clone(&self.cache),
-            use_stale: self.use_stale,
-            limit: self.limit,
-            allow_invalidation: self.allow_invalidation,
-            add_response_headers: self.add_response_headers,
+    /// Stop trusting the revalidations (see [`CacheLayer::revalidate_stale`] and
+    /// [`CacheLayer::handle_upstream_not_modified`]) of the responses whose bodies were fetched
+    /// longer than the given age ago: such responses are fetched in full again even when the
+    /// service would confirm them with `304 NOT MODIFIED`, so a buggy validator can’t keep an
+    /// outdated body cached indefinitely.
+    pub fn max_revalidation_age(self, max_age: Duration) -> Self {
+        Self {
+            max_revalidation_age: Some(max_age),
+            ..self
+        }
+    }
+
+    /// Handle the `304 NOT MODIFIED` responses the wrapped service sends on its own (eg. when
+    /// it evaluates the conditional requests itself) instead of passing them through as failed:
+    ///
+    /// - to a request the client didn’t make conditional, the stored (expired) response is
+    ///   served with its full body and revalidated like with [`CacheLayer::revalidate_stale`],
+    /// - to a conditional request of the client, the `304` is forwarded and the stored response
+    ///   is kept (and revalidated, if the `304` has the same `ETag`) instead of evicted.
+    ///
+    /// The `304` responses to the requests with nothing stored are passed through as they are.
+    pub fn handle_upstream_not_modified(self) -> Self {
+        Self {
+            upstream_not_modified: true,
+            ..self
+        }
+    }
+
+    /// Don’t reinsert the expired value into the cache while its refresh is in progress.
+    ///
+    /// By default the stale value is reinserted immediately so that the concurrent requests don’t
+    /// schedule their own refreshes. On size-limited stores this also bumps the entry’s recency,
+    /// which can keep the stale entries of failing endpoints from being evicted. With this
+    /// option the concurrent requests refresh the entry on their own, and the stale value is
+    /// only returned (see [`CacheLayer::use_stale_on_failure`]) to the requests that found it.
+    pub fn skip_stale_reinsert(self) -> Self {
+        Self {
+            stale_reinsert: false,
+            ..self
+        }
+    }
+
+    /// Count the hits of the cached entries and keep the entries hit at least `min_hits` times
+    /// fresh for `extended_ttl` since they were stored, even when the cache considers them
+    /// expired. This keeps the popular responses cached longer than the one-off ones.
+    ///
+    /// The promoted entries are reinserted into the cache when they expire, so the extended TTL
+    /// should be longer than the cache’s own TTL.
+    pub fn promote_after(self, min_hits: u64, extended_ttl: Duration) -> Self {
+        Self {
+            promote: Some((min_hits, extended_ttl)),
+            ..self
+        }
+    }
+
+    /// Change the maximum body size limit. If you want unlimited size, use [`usize::MAX`].
+    pub fn body_limit(self, new_limit: usize) -> Self {
+        Self {
+            limit: new_limit,
+            ..self
+        }
+    }
+
+    /// Remember the keys whose responses exceeded the body size limit for the given time, and
+    /// pass the responses to them through without buffering during that time. By default the
+    /// responses are buffered (and fail with `500 INTERNAL SERVER ERROR` when too big) every time.
+    pub fn oversized_cooldown(self, cooldown: Duration) -> Self {
+        Self {
+            oversized_cooldown: Some(cooldown),
+            ..self
+        }
+    }
+
+    /// Stream the responses of the wrapped service to the client while buffering them for the
+    /// cache, instead of buffering the whole body before responding.
+    ///
+    /// The response is stored only once its whole body was streamed. When the body exceeds the
+    /// size limit (see [`CacheLayer::body_limit`]) or fails midway, the client still receives
+    /// all of it, but nothing is cached.
+    pub fn tee_streaming(self) -> Self {
+        Self {
+            tee_streaming: true,
+            ..self
+        }
+    }
+
+    /// Limit the total size of the response’s headers (sum of the lengths of all the names and
+    /// values). Responses with bigger headers are passed-through without being cached.
+    /// Unlimited by default.
+    pub fn max_header_bytes(self, new_limit: usize) -> Self {
+        Self {
+            max_header_bytes: new_limit,
+            ..self
+        }
+    }
+
+    /// Allow manual cache invalidation by setting the `X-Invalidate-Cache` header in the request.
+    /// This will allow the cache to be invalidated for the given key.
+    pub fn allow_invalidation(self) -> Self {
+        Self {
+            allow_invalidation: true,
+            ..self
+        }
+    }
+
+    /// Honor the `Cache-Control` directives of the requests: `no-store` bypasses the cache,
+    /// `no-cache` fetches the response again (storing it as usual), and `max-age` does so when
+    /// the cached response is older. Without `Cache-Control`, `Pragma: no-cache` of the HTTP/1.0
+    /// clients is handled like `no-cache`.
+    ///
+    /// Ignored by default, as any client can force the requests through to the wrapped service.
+    pub fn respect_request_cache_control(self) -> Self {
+        Self {
+            request_cache_control: true,
+            ..self
+        }
+    }
+
+    /// Allow trusted callers (eg. cache warming pipelines) to force storing the response
+    /// regardless of its status by setting the given request header to the shared secret.
+    ///
+    /// The header is removed from the request before it reaches the wrapped service and from the
+    /// response, and requests with a wrong secret are handled as if the header was missing.
+    pub fn force_store_header(self, name: HeaderName, secret: HeaderValue) -> Self {
+        Self {
+            force_store: Some((name, secret)),
+            ..self
+        }
+    }
+
+    /// Cache the responses by the route pattern (axum’s [`MatchedPath`], eg. `/users/:id`)
+    /// instead of the concrete path and query, so the same response is served for all the paths
+    /// matching the route (eg. for application shells or skeletons).
+    ///
+    /// The requests without the matched path (eg. handled by the fallback) are cached by their
+    /// concrete URI.
+    pub fn key_by_matched_path(self) -> Self {
+        Self {
+            key_by_matched_path: true,
+            ..self
+        }
+    }
+
+    /// Key the responses by the path and query of the URI only (its origin form), dropping the
+    /// scheme and authority, so the proxied requests with absolute URIs (eg.
+    /// `http://example.com/users`) share the responses with the direct ones (`/users`).
+    pub fn key_path_and_query_only(self) -> Self {
+        Self {
+            key_path_and_query_only: true,
+            ..self
+        }
+    }
+
+    /// Select the URI of the request the responses are keyed by (see [`UriSource`]). By default
+    /// it’s the URI seen by the layer ([`UriSource::Nested`]), so the same layer added to the
+    /// service nested at several paths (or the service nested with its own layer) keys the
+    /// requests without their prefixes, sharing the responses among the nested paths. With
+    /// [`UriSource::Original`] the responses are keyed by the full path instead, wherever the
+    /// layer is placed.
+    pub fn key_uri_source(self, source: UriSource) -> Self {
+        Self {
+            uri_source: source,
+            ..self
+        }
+    }
+
+    /// Key the responses on the host of the request too (its `Host` header, or the authority of
+    /// the URI with HTTP/2), for the virtual-hosted apps serving different content per domain
+    /// behind one router. The host is lowercased and stripped of the default port (`80` or
+    /// `443`), so eg. `Example.com:80` and `example.com` share the responses.
+    ///
+    /// The requests without the host share the responses keyed without it, and the requests
+    /// with a malformed `Host` header bypass the cache. The responses advertise the keying to
+    /// the downstream caches with `Vary: Host`.
+    ///
+    /// The [`InvalidationMsg`]s with the URIs without the authority (eg. `/path`) purge the
+    /// responses of all the hosts.
+    pub fn vary_by_host(self) -> Self {
+        Self {
+            vary_by_host: true,
+            ..self
+        }
+    }
+
+    /// Key the responses on the values of the given request headers too, for the content
+    /// varying by the client attributes derived by a reverse proxy or CDN in front of the app,
+    /// eg. the viewer’s country in `CloudFront-Viewer-Country`. The requests without the headers
+    /// share the responses keyed without them, and the responses advertise the keying to the
+    /// downstream caches with `Vary`.
+    ///
+    /// **Only list the headers the proxy always sets itself, overwriting the ones sent by the
+    /// client.** The headers passed through from the client (eg. `X-Forwarded-For`, appended to
+    /// by each hop) can be spoofed, letting the attacker fill the cache with arbitrary variants,
+    /// or store the responses under the key the other clients are looking up. When the app is
+    /// reachable without the proxy, the proxy-set headers can be spoofed too, so the app should
+    /// then drop (or verify) them in a middleware before the layer; deriving the attribute
+    /// there and using [`CacheLayer::key_extension`] is another option.
+    ///
+    /// The [`InvalidationMsg`]s match the keys by the path, purging the responses for all the
+    /// values of the headers.
+    ///
+    /// ```rust
+    /// use axum::http::HeaderName;
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let layer = CacheLayer::with_lifespan(60)
+    ///     .vary_by_trusted_headers(&[HeaderName::from_static("cloudfront-viewer-country")]);
+    /// ```
+    pub fn vary_by_trusted_headers(self, names: &[HeaderName]) -> Self {
+        Self {
+            trusted_headers: names.into(),
+            ..self
+        }
+    }
+
+    /// Negotiate the image format of the request among the offered media types (in the order of
+    /// preference), replacing its `Accept` header with the selected one before looking it up and
+    /// passing it to the wrapped service. The clients resolving to the same format (eg. all the
+    /// browsers sending `Accept: image/avif,image/webp,*/*`) then share the variant of the
+    /// response varying by `Accept`, instead of one variant for each distinct header.
+    ///
+    /// The format with the highest quality (`q`) in the header is selected, the more specific
+    /// media ranges taking precedence (`image/webp` over `image/*` over `*/*`), and the
+    /// requests without the header get the first format. The header of the requests accepting
+    /// none of the formats is left as it is.
+    ///
+    /// The wrapped service has to select the format by the `Accept` header and mark the
+    /// responses with `Vary: Accept` as usual.
+    ///
+    /// ```rust
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let layer = CacheLayer::with_lifespan(60).negotiate_image_formats(&[
+    ///     "image/avif",
+    ///     "image/webp",
+    ///     "image/jpeg",
+    /// ]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the media types isn’t a valid header value.
+    pub fn negotiate_image_formats(self, formats: &[&'static str]) -> Self {
+        Self {
+            image_formats: Some(
+                formats
+                    .iter()
+                    .copied()
+                    .map(HeaderValue::from_static)
+                    .collect(),
+            ),
+            ..self
+        }
+    }
+
+    /// Build the caching key and the TTL of the response from the request with the given
+    /// function (eg. to key the responses of an image processing endpoint by selected query
+    /// parameters and the `Accept` header, and keep the expensive variants cached longer).
+    ///
+    /// The returned URI replaces the request’s URI in the caching key (the method is still part
+    /// of the key). The returned TTL overrides the cache’s lifespan for the stored response, both
+    /// when it’s shorter and longer (the entries outliving the cache’s lifespan are reinserted
+    /// when the cache expires them). Takes precedence over [`CacheLayer::key_by_matched_path`].
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use axum::http::{header, Uri};
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let layer = CacheLayer::with_lifespan(60).key_and_ttl_fn(|request| {
+    ///     let format = match request.headers().get(header::ACCEPT) {
+    ///         Some(accept) if accept.as_bytes().starts_with(b"image/webp") => "webp",
+    ///         _ => "png",
+    ///     };
+    ///     let query = request.uri().query().unwrap_or_default();
+    ///     let key = format!("{}?{query}&format={format}", request.uri().path());
+    ///     let ttl = (!query.is_empty()).then(|| Duration::from_secs(3600));
+    ///     (Uri::try_from(key).unwrap_or_default(), ttl)
+    /// });
+    /// ```
+    pub fn key_and_ttl_fn<F>(self, key_fn: F) -> Self
+    where
+        F: Fn(&Request<Body>) -> (Uri, Option<Duration>) + Send + Sync + 'static,
+    {
+        Self {
+            key_fn: Some(Arc::new(key_fn)),
+            ..self
+        }
+    }
+
+    /// Bound the TTLs of the stored responses to the given range, regardless of the TTLs
+    /// returned by [`CacheLayer::key_and_ttl_fn`] or the cache’s lifespan (the responses of
+    /// unbounded caches are kept for at most `max`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is greater than `max`.
+    pub fn clamp_ttl(self, min: Duration, max: Duration) -> Self {
+        assert!(min <= max, "minimum TTL is greater than the maximum");
+        Self {
+            ttl_bounds: Some((min, max)),
+            ..self
+        }
+    }
+
+    /// Store a fixed-size hash of the URI in the caching keys instead of the URI itself, to
+    /// save memory with long query strings. The URI is replaced with `/` followed by the 128-bit
+    /// BLAKE3 hash of the URI (or of the URI built by [`CacheLayer::key_and_ttl_fn`] or
+    /// [`CacheLayer::key_by_matched_path`]) in hex, the method is still part of the key.
+    ///
+    /// The probability of any collision among `n` distinct URIs is at most `n² / 2¹²⁹`, eg.
+    /// about `1.5 × 10⁻²¹` for a billion URIs.
+    ///
+    /// The original URIs can’t be recovered from the keys, so they aren’t visible in
+    /// [`CacheLayer::dump`], and [`InvalidationMsg`] doesn’t match the hashed keys.
+    pub fn hashed_keys(self) -> Self {
+        Self {
+            hashed_keys: true,
+            ..self
+        }
+    }
+
+    /// Fold the request extension of the given type (eg. the identity of the client certificate
+    /// inserted by the TLS terminating middleware) into the caching key, so the responses are
+    /// cached separately for each value of the extension. The URI in the key is prefixed with
+    /// `/` followed by the 128-bit BLAKE3 hash of the value in hex.
+    ///
+    /// The requests without the extension bypass the cache, so the responses can’t leak between
+    /// the clients when the extension is missing (eg. because of misconfigured middleware).
+    ///
+    /// The extension has to be inserted by a middleware running before the layer. With
+    /// `axum-server` and rustls, the client certificate can be read from the TLS connection in
+    /// a custom acceptor and attached with [`axum::Extension`]; behind a TLS terminating proxy,
+    /// it can be read from the header set by the proxy (as long as the proxy overwrites the
+    /// header sent by the client):
+    ///
+    /// ```rust
+    /// use axum::{extract::Request, middleware::{self, Next}, routing::get, Router};
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// #[derive(Clone, Hash)]
+    /// struct ClientIdentity(String);
+    ///
+    /// async fn client_identity(mut request: Request, next: Next) -> axum::response::Response {
+    ///     let subject = request
+    ///         .headers()
+    ///         .get("x-client-cert-subject")
+    ///         .and_then(|value| value.to_str().ok())
+    ///         .map(str::to_owned);
+    ///     if let Some(subject) = subject {
+    ///         request.extensions_mut().insert(ClientIdentity(subject));
+    ///     }
+    ///     next.run(request).await
+    /// }
+    ///
+    /// let router: Router = Router::new()
+    ///     .route(
+    ///         "/reports",
+    ///         get(|| async { "per-client report" })
+    ///             .layer(CacheLayer::with_lifespan(60).key_extension::<ClientIdentity>()),
+    ///     )
+    ///     .layer(middleware::from_fn(client_identity));
+    /// ```
+    pub fn key_extension<T>(self) -> Self
+    where
+        T: std::hash::Hash + Send + Sync + 'static,
+    {
+        Self {
+            key_extension: Some(extension_hash::<T>),
+            ..self
+        }
+    }
+
+    /// Cache the responses to CORS preflight requests (`OPTIONS` requests with the
+    /// `Access-Control-Request-Method` header), which are bypassing the cache by default.
+    ///
+    /// The preflight responses are cached separately for each combination of the `Origin`,
+    /// `Access-Control-Request-Method` and `Access-Control-Request-Headers` request headers, as
+    /// those determine the response.
+    pub fn cache_cors_preflight(self) -> Self {
+        Self {
+            cache_preflight: true,
+            ..self
+        }
+    }
+
+    /// Allow the response headers to be included in the cached response.
+    pub fn add_response_headers(self) -> Self {
+        Self {
+            add_response_headers: true,
+            ..self
+        }
+    }
+
+    /// Set the `Date` header of the responses served from the cache to the current time (leaving
+    /// the stored response untouched) and add the time spent in the cache to their `Age` header.
+    pub fn refresh_date_header(self) -> Self {
+        Self {
+            refresh_date: true,
+            ..self
+        }
+    }
+
+    /// Serve the byte ranges (requested with the `Range` header) of the cached `200 OK`
+    /// responses as `206 PARTIAL CONTENT`, and advertise the support with `Accept-Ranges: bytes`.
+    /// Only single ranges are supported (multiple ranges are answered with the whole body) and
+    /// the `If-Range` precondition is honored.
+    ///
+    /// The `Range` and `If-Range` headers are removed from the requests passed to the wrapped
+    /// service, so that the whole body is fetched and cached.
+    pub fn serve_ranges(self) -> Self {
+        Self {
+            serve_ranges: true,
+            ..self
+        }
+    }
+
+    /// Like [`CacheLayer::serve_ranges`], but pass the range requests for the responses that
+    /// aren’t cached yet to the wrapped service as they are, instead of fetching the whole body.
+    /// The partial responses (`206 PARTIAL CONTENT`) are passed-through without being cached,
+    /// so the cold range requests for huge resources don’t buffer them; the full responses (when
+    /// the service ignores the range) are cached as usual and the range is served from them.
+    pub fn forward_cold_ranges(self) -> Self {
+        Self {
+            serve_ranges: true,
+            forward_cold_ranges: true,
+            ..self
+        }
+    }
+
+    /// Rewrite the host of the absolute `Location` headers of the cached responses (eg. redirects)
+    /// to the `Host` of the request being served. Only the same-origin locations, pointing to the
+    /// host the response was originally cached for, are rewritten, so that the cached redirects
+    /// stay correct when the layer is shared by multiple hosts.
+    pub fn rewrite_location_host(self) -> Self {
+        Self {
+            rewrite_location_host: true,
+            ..self
+        }
+    }
+
+    /// Remove duplicated `Link` headers (eg. repeated `rel=preload` hints) from the responses
+    /// before storing them in the cache. `Link` headers are always preserved, this only drops
+    /// the exact duplicates.
+    pub fn dedup_link_headers(self) -> Self {
+        Self {
+            dedup_link_headers: true,
+            ..self
+        }
+    }
+
+    /// Keep the hash of the stored bodies and, when refreshing the expired response yields the
+    /// same body, store the refreshed response (with its new headers and lifetime) with the body
+    /// stored before, skipping its compression and the new allocation. Useful for the responses
+    /// that rarely change, at the cost of hashing every stored body.
+    pub fn reuse_unchanged_bodies(self) -> Self {
+        Self {
+            reuse_unchanged_bodies: true,
+            ..self
+        }
+    }
+
+    /// Record the signature of the request each response was stored for (its method, URI and
+    /// `Host` header) alongside the response, and include it in [`CacheLayer::dump`]. It helps
+    /// to find the keying misconfigurations making different requests share the responses.
+    pub fn record_origin_request(self) -> Self {
+        Self {
+            record_origin: true,
+            ..self
+        }
+    }
+
+    /// Keep the media type of the responses cached for each key consistent (eg. to never replace
+    /// the cached JSON with an HTML error page served with a success status): the responses with
+    /// a media type different from the one of the response first cached for the key aren’t
+    /// stored, and the mismatched variants aren’t served but fetched again.
+    ///
+    /// The media type is remembered only while the key is cached.
+    pub fn enforce_consistent_content_type(self) -> Self {
+        Self {
+            consistent_content_type: true,
+            ..self
+        }
+    }
+
+    /// Limit the number of the variants (selected by the `Vary` request headers) cached for one
+    /// method and URI, evicting the least recently used variant when a new one is stored. It
+    /// protects the cache from the clients sending many distinct header combinations.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn max_variants_per_key(self, max: usize) -> Self {
+        assert!(max > 0, "at least one variant has to be cached");
+        Self {
+            max_variants: Some(max),
+            ..self
+        }
+    }
+
+    /// Keep the cached response fetched by the most recent request when the concurrent misses
+    /// race to store the same variant: a response whose request started before the one of the
+    /// cached response doesn’t replace it, even if it completed later.
+    pub fn newest_response_wins(self) -> Self {
+        Self {
+            newest_wins: true,
+            ..self
+        }
+    }
+
+    /// Measure the time the wrapped service takes to produce the responses and attach it to the
+    /// stored responses as the [`ResponseLatency`] extension, so the slow responses, benefiting
+    /// the most from caching, can be preferentially retained by the [`CostWeightedCache`].
+    ///
+    /// ```rust
+    /// use axum_response_cache::{CacheLayer, ResponseLatency};
+    ///
+    /// let layer = CacheLayer::cost_weighted(100, 60, ResponseLatency::cost).retain_by_latency();
+    /// ```
+    pub fn retain_by_latency(self) -> Self {
+        Self {
+            measure_latency: true,
+            ..self
+        }
+    }
+
+    /// Log a warning when storing a response holds the lock of the cache for longer than the
+    /// threshold, to diagnose the lock contention stalling the concurrent requests.
+    ///
+    /// Only merging the response into the cached entry happens under the lock, the responses are
+    /// buffered, compressed and mirrored to the secondary cache outside of it.
+    pub fn warn_on_lock_hold(self, threshold: Duration) -> Self {
+        Self {
+            lock_warning: Some(threshold),
+            ..self
+        }
+    }
+
+    /// Measure the time spent looking the requests up in the cache and storing the responses,
+    /// ie. acquiring the lock of the cache and accessing the store under it, into the histograms
+    /// returned by [`CacheLayer::cache_latency`], to diagnose the lock contention. Each
+    /// operation is measured with a pair of [`Instant::now`](std::time::Instant::now) calls.
+    pub fn measure_cache_latency(self) -> Self {
+        Self {
+            cache_latency: Some(Arc::default()),
+            ..self
+        }
+    }
+
+    /// Add the given header (eg. `Clear-Site-Data: "cache"`) to the first response served for
+    /// each key after its cached responses were invalidated, so the browsers drop their own
+    /// copies too (eg. after a security-sensitive change).
+    ///
+    /// The keys are invalidated by the `X-Invalidate-Cache` header (see
+    /// [`CacheLayer::allow_invalidation`]), the [`InvalidationMsg`]s received by
+    /// [`CacheLayer::invalidate_on`], [`CacheLayer::invalidate_matching`] and
+    /// [`CacheLayer::flush_where`].
+    ///
+    /// ```rust
+    /// use axum::http::{HeaderName, HeaderValue};
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let layer = CacheLayer::with_lifespan(60).signal_invalidation(
+    ///     HeaderName::from_static("clear-site-data"),
+    ///     HeaderValue::from_static("\"cache\""),
+    /// );
+    /// ```
+    pub fn signal_invalidation(self, name: HeaderName, value: HeaderValue) -> Self {
+        self.invalidated.enable();
+        Self {
+            invalidation_signal: Some((name, value)),
+            ..self
+        }
+    }
+
+    /// Add the header with the key the request was looked up with (its method and URI, eg.
+    /// `GET /users?page=2`) to the responses handled by the cache, to check whether different
+    /// requests share the cached response. The bypassed requests don’t get the header.
+    ///
+    /// It’s meant for debugging only, as it exposes the internal keying to the clients. It’s
+    /// off by default.
+    pub fn debug_key_header(self, name: HeaderName) -> Self {
+        Self {
+            debug_key_header: Some(name),
+            ..self
+        }
+    }
+
+    /// Coalesce the concurrent misses for the same key (without a stale response in the cache):
+    /// only the first one calls the wrapped service, and once its response is stored, the others
+    /// are served from the same stored response, sharing the one buffered body. It’s off by
+    /// default, as it assumes the calls of the wrapped service have no side effects: only the
+    /// misses of the safe methods (`GET`, `HEAD` and `OPTIONS`) are coalesced, the other cached
+    /// methods (see [`CacheLayer::method_policies`]) always call the service themselves.
+    ///
+    /// The waiting requests call the wrapped service themselves when the first one isn’t stored
+    /// (eg. it failed, see [`CacheLayer::coalesce_on_failure`], or was streamed with
+    /// [`CacheLayer::tee_streaming`]) or when the stored variant doesn’t match their `Vary`
+    /// headers.
+    pub fn coalesce_misses(self) -> Self {
+        Self {
+            coalesce_misses: true,
+            ..self
+        }
+    }
+
+    /// Change what happens to the coalesced misses (see [`CacheLayer::coalesce_misses`]) when
+    /// the wrapped service fails the first one. By default they call the service themselves
+    /// ([`CoalesceFailure::Retry`]), so one transient failure doesn’t fail all of them.
+    pub fn coalesce_on_failure(self, on_failure: CoalesceFailure) -> Self {
+        Self {
+            coalesce_failure: on_failure,
+            ..self
+        }
+    }
+
+    /// Only coalesce the concurrent misses (see [`CacheLayer::coalesce_misses`]) without
+    /// retaining their responses: the response is removed from the cache as soon as it’s handed
+    /// to the misses waiting for it, so a burst of identical requests calls the wrapped service
+    /// once, while the later requests never get a stale response.
+    ///
+    /// The responses are still written to the secondary cache (see [`CacheLayer::with_tiers`]),
+    /// if any.
+    pub fn coalesce_only(self) -> Self {
+        Self {
+            coalesce_misses: true,
+            coalesce_only: true,
+            ..self
+        }
+    }
+
+    /// Configure the caching behaviour for each HTTP method in one place: whether its requests
+    /// are cached, the TTL of their responses and whether their bodies are a part of the key
+    /// (see [`MethodPolicy`]). The requests with the other methods are handled as usual.
+    pub fn method_policies(
+        self,
+        policies: impl IntoIterator<Item = (Method, MethodPolicy)>,
+    ) -> Self {
+        Self {
+            method_policies: Some(Arc::new(policies.into_iter().collect())),
+            ..self
+        }
+    }
+
+    /// Choose how the requests with a body not keyed by the method policy (see
+    /// [`MethodPolicy::body_in_key`]) are handled, eg. the `GET` requests with a body sent by
+    /// some search APIs. By default the body is ignored with a warning
+    /// ([`GetBodyPolicy::Ignore`]). The requests whose body length is unknown are assumed to
+    /// have a body.
+    pub fn get_with_body(self, policy: GetBodyPolicy) -> Self {
+        Self {
+            get_body_policy: policy,
+            ..self
+        }
+    }
+
+    /// Call the given hook on the responses served from the cache right before they’re returned,
+    /// eg. to add the observability headers computed from how the hit was resolved.
+    ///
+    /// ```rust
+    /// use axum::http::HeaderValue;
+    /// use axum_response_cache::{CacheLayer, HitOutcome};
+    ///
+    /// let layer = CacheLayer::with_lifespan(60).decorate_hit(|response, info| {
+    ///     let desc = match info.outcome {
+    ///         HitOutcome::Fresh | HitOutcome::Secondary => "hit",
+    ///         _ => "stale",
+    ///     };
+    ///     let timing = format!(
+    ///         "cache;desc=\"{desc}\";dur={:.3}",
+    ///         info.lookup.as_secs_f64() * 1000.0
+    ///     );
+    ///     response
+    ///         .headers_mut()
+    ///         .insert("server-timing", HeaderValue::from_str(&timing).unwrap());
+    /// });
+    /// ```
+    pub fn decorate_hit<F>(self, decorate: F) -> Self
+    where
+        F: Fn(&mut Response, &HitInfo) + Send + Sync + 'static,
+    {
+        Self {
+            decorate_hit: Some(Arc::new(decorate)),
+            ..self
+        }
+    }
+
+    /// Add the given headers to the responses served from the cache which lack them, eg. the
+    /// CORS or security headers handled at the edge rather than by the wrapped service. The
+    /// stored responses are left as they are, and the headers they have are never replaced.
+    ///
+    /// ```rust
+    /// use axum::http::{header, HeaderMap, HeaderValue};
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let mut headers = HeaderMap::new();
+    /// headers.insert(
+    ///     header::ACCESS_CONTROL_ALLOW_ORIGIN,
+    ///     HeaderValue::from_static("*"),
+    /// );
+    /// let layer = CacheLayer::with_lifespan(60).add_headers_on_serve(headers);
+    /// ```
+    pub fn add_headers_on_serve(self, headers: HeaderMap) -> Self {
+        Self {
+            serve_headers: Some(Arc::new(headers)),
+            ..self
+        }
+    }
+
+    /// Set the `Cache-Control` header of the responses handled by the cache (hits and misses)
+    /// to the given value, replacing the one from the wrapped service, so the clients cache the
+    /// responses independently of how long the layer does. The stored responses keep their own
+    /// header (eg. for [`CacheLayer::honor_stale_directives`]).
+    ///
+    /// Only the successful (`2xx`) and `304 NOT MODIFIED` responses get the header, the failed
+    /// and the bypassed ones are passed through as they are.
+    ///
+    /// ```rust
+    /// use axum::http::HeaderValue;
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let layer = CacheLayer::with_lifespan(3600)
+    ///     .set_client_cache_control(HeaderValue::from_static("public, max-age=60"));
+    /// ```
+    pub fn set_client_cache_control(self, value: HeaderValue) -> Self {
+        Self {
+            client_cache_control: Some(value),
+            ..self
+        }
+    }
+
+    /// Store the bodies of the responses compressed with the given codec to reduce the memory
+    /// footprint of the cache. The clients accepting the codec (according to the request’s
+    /// `Accept-Encoding` header) receive the compressed body with the appropriate
+    /// `Content-Encoding`, other clients receive the body decompressed on the fly.
+    ///
+    /// Responses that are already encoded by the wrapped service are stored as they are, and only
+    /// served to the clients sending the same `Accept-Encoding`. The `Content-Encoding: identity`
+    /// header is dropped, the body being compressed like the unencoded one.
+    pub fn compression(self, compression: Compression) -> Self {
+        Self {
+            compression: Some(compression),
+            ..self
+        }
+    }
+
+    /// Normalize the volatile fields (eg. the server timestamps or request IDs) of the JSON
+    /// responses before storing them, so the cached bodies are canonical. The fields are selected
+    /// with JSON pointers (eg. `/meta/request_id`), the missing ones are ignored.
+    ///
+    /// Only the responses with the `application/json` (or `+json` suffixed) media type which
+    /// aren’t encoded by the wrapped service are normalized. The malformed ones are handled
+    /// according to `on_malformed`.
+    ///
+    /// ```rust
+    /// use axum_response_cache::{CacheLayer, JsonFieldAction, MalformedJson};
+    ///
+    /// let layer = CacheLayer::with_lifespan(60).normalize_json_fields(
+    ///     &[
+    ///         ("/generated_at", JsonFieldAction::Remove),
+    ///         ("/meta/request_id", JsonFieldAction::Null),
+    ///     ],
+    ///     MalformedJson::PassThrough,
+    /// );
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn normalize_json_fields(
+        self,
+        fields: &[(&str, JsonFieldAction)],
+        on_malformed: MalformedJson,
+    ) -> Self {
+        Self {
+            json_normalization: Some(json::Normalization {
+                fields: fields
+                    .iter()
+                    .map(|(pointer, action)| (pointer.to_string(), *action))
+                    .collect(),
+                malformed: on_malformed,
+            }),
+            ..self
+        }
+    }
+
+    /// Store the bodies bigger than `threshold` bytes in files created in the given directory
+    /// and serve them from the memory mappings of the files, so the large responses (eg. media
+    /// served with `ServeDir`) are held by the OS page cache instead of the heap. The files are
+    /// removed once the responses are evicted and no longer served.
+    ///
+    /// The bodies are still buffered in memory while they’re stored, and are kept in memory when
+    /// the file can’t be written.
+    #[cfg(feature = "mmap")]
+    pub fn file_backed_bodies(self, dir: impl AsRef<Path>, threshold: usize) -> Self {
+        Self {
+            file_backed: Some((Arc::from(dir.as_ref()), threshold)),
+            ..self
+        }
+    }
+
+    /// Compute the SHA-256 digest of the bodies once, when they’re stored, and serve it with the
+    /// `Repr-Digest` header (RFC 9530), so the clients can verify the integrity of the served
+    /// responses (eg. the binary artifacts). The digest replaces the one sent by the wrapped
+    /// service, if any, and matches the body as it’s served, compressed (see
+    /// [`CacheLayer::compression`]) or not.
+    #[cfg(feature = "digest")]
+    pub fn content_digest(self) -> Self {
+        Self {
+            content_digest: true,
+            ..self
+        }
+    }
+
+    /// Bypass the cache for the requests for which the given asynchronous predicate resolves to
+    /// `true` (eg. when a feature flag fetched from an external service disables caching).
+    /// The predicate is awaited before the cache lookup, so the bypassed requests neither read nor
+    /// update the cache.
+    ///
+    /// The predicate receives a reference to the request, so any data it needs has to be extracted
+    /// before the returned future is created. It is limited by the timeout set with
+    /// [`CacheLayer::async_skip_timeout`] (1 second by default).
+    pub fn async_skip_if<F, Fut>(self, predicate: F) -> Self
+    where
+        F: Fn(&Request<Body>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self {
+            async_skip: Some(Arc::new(move |request| Box::pin(predicate(request)))),
+            ..self
+        }
+    }
+
+    /// Change the maximum time the [`CacheLayer::async_skip_if`] predicate is awaited for.
+    pub fn async_skip_timeout(self, timeout: Duration) -> Self {
+        Self {
+            async_skip_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Change what happens when the [`CacheLayer::async_skip_if`] predicate times out. By default
+    /// the cache is bypassed ([`GuardFailure::Bypass`]).
+    pub fn async_skip_on_failure(self, on_failure: GuardFailure) -> Self {
+        Self {
+            async_skip_failure: on_failure,
+            ..self
+        }
+    }
+
+    /// Switch the layer to the cache-only mode while the flag is set (eg. to shed the load of a
+    /// struggling service during an outage): the requests are served only from the cache,
+    /// including the stale responses, and the misses (as well as the requests bypassing the
+    /// cache) are answered with `503 SERVICE UNAVAILABLE` without calling the wrapped service.
+    ///
+    /// ```rust
+    /// use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+    ///
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let cache_only = Arc::new(AtomicBool::new(false));
+    /// let layer = CacheLayer::with_lifespan(60).cache_only(Arc::clone(&cache_only));
+    /// // during the incident
+    /// cache_only.store(true, Ordering::Relaxed);
+    /// ```
+    pub fn cache_only(self, flag: Arc<AtomicBool>) -> Self {
+        Self {
+            cache_only: Some(flag),
+            ..self
+        }
+    }
+
+    /// Store only the responses whose `Content-Type` (ignoring its parameters, eg. `charset`)
+    /// matches one of the given media types. The types can have wildcard subtypes (eg.
+    /// `image/*`). Other responses are passed-through without being cached.
+    ///
+    /// ```rust
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let layer = CacheLayer::with_lifespan(60)
+    ///     .cacheable_content_types(&["application/json", "image/*"]);
+    /// ```
+    pub fn cacheable_content_types(self, content_types: &[&str]) -> Self {
+        Self {
+            content_types: Some(
+                content_types
+                    .iter()
+                    .map(|content_type| content_type.trim().to_ascii_lowercase())
+                    .collect(),
+            ),
+            ..self
+        }
+    }
+
+    /// Apply the rules making the cache safe to share between users: the responses setting
+    /// cookies or signaling the authentication state (with the `WWW-Authenticate`,
+    /// `Proxy-Authenticate` or `Authentication-Info` headers) aren’t stored and the requests with
+    /// the `Authorization` or `Cookie` headers bypass the cache. Each rule can be changed
+    /// afterwards with [`CacheLayer::refuse_set_cookie`], [`CacheLayer::refuse_headers`],
+    /// [`CacheLayer::bypass_authorization`] and [`CacheLayer::bypass_cookie`].
+    ///
+    /// ```rust
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// // public pages don’t depend on cookies, but still never store the ones setting them
+    /// let layer = CacheLayer::with_lifespan(60).safe_shared().bypass_cookie(false);
+    /// ```
+    pub fn safe_shared(self) -> Self {
+        Self {
+            refuse_set_cookie: true,
+            refused_headers: Arc::new(AUTH_RESPONSE_HEADERS),
+            auth_mode: AuthMode::Bypass,
+            bypass_cookie: true,
+            ..self
+        }
+    }
+
+    /// Don’t store the responses with the `Set-Cookie` header, as those are usually personalized.
+    /// Such responses are passed-through as they are.
+    pub fn refuse_set_cookie(self, enabled: bool) -> Self {
+        Self {
+            refuse_set_cookie: enabled,
+            ..self
+        }
+    }
+
+    /// Don’t store the responses with any of the given headers, passing them through as they are.
+    /// The `304 Not Modified` responses with them don’t update the stored responses either, so
+    /// the revalidated shared entries never pick them up. An empty list (the default) disables
+    /// the check.
+    pub fn refuse_headers(self, names: &[HeaderName]) -> Self {
+        Self {
+            refused_headers: names.into(),
+            ..self
+        }
+    }
+
+    /// Only store the responses whose headers are all in the given list, refusing (with a
+    /// warning) to cache the responses with any other header instead of silently stripping it,
+    /// so the headers the cache stores and replays can be reviewed exhaustively. Such responses
+    /// are passed-through as they are.
+    ///
+    /// The list has to include the headers set by the wrapped service on every response, eg.
+    /// `Content-Type`.
+    pub fn strict_header_allowlist(self, names: &[HeaderName]) -> Self {
+        Self {
+            allowed_headers: Some(names.into()),
+            ..self
+        }
+    }
+
+    /// Bypass the cache for the requests with the `Authorization` header, a shorthand for
+    /// [`CacheLayer::auth_mode`] with [`AuthMode::Bypass`] (or [`AuthMode::Ignore`] when
+    /// disabled).
+    pub fn bypass_authorization(self, enabled: bool) -> Self {
+        Self {
+            auth_mode: match enabled {
+                true => AuthMode::Bypass,
+                false => AuthMode::Ignore,
+            },
+            ..self
+        }
+    }
+
+    /// Select how the requests with the `Authorization` header are cached, see [`AuthMode`]
+    /// for the security implications of each mode. By default the header is ignored.
+    ///
+    /// ```rust
+    /// use axum_response_cache::{AuthMode, CacheLayer};
+    ///
+    /// // each user gets their own responses, the anonymous users share theirs
+    /// let layer = CacheLayer::with_lifespan(60).auth_mode(AuthMode::PrivateByCredential);
+    /// ```
+    pub fn auth_mode(self, auth_mode: AuthMode) -> Self {
+        Self { auth_mode, ..self }
+    }
+
+    /// Bypass the cache for the requests with the `Cookie` header (eg. carrying a session).
+    pub fn bypass_cookie(self, enabled: bool) -> Self {
+        Self {
+            bypass_cookie: enabled,
+            ..self
+        }
+    }
+}
+
+impl<C> CacheLayer<C>
+where
+    C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry>,
+{
+    /// Return the response cached for the key, computing and storing it first if it’s missing or
+    /// expired. It uses the same store as the layer, so it can be used by the handlers that need
+    /// manual control over caching (eg. to do some conditional work around it).
+    ///
+    /// The cached response is fresh by the same rules as for the requests: its own TTL and the
+    /// promotion (see [`CacheLayer::promote_after`]) are respected, and the soft invalidated one
+    /// (see [`CacheLayer::soft_invalidate`]) is computed again.
+    ///
+    /// The computed response is stored as the variant not depending on any request headers.
+    /// The cache isn’t locked while the response is computed, but the concurrent calls for the
+    /// same missing key (and the coalesced misses of the layer, see
+    /// [`CacheLayer::coalesce_misses`]) wait for the one computing it.
+    ///
+    /// ```rust
+    /// use axum::{body::Bytes, http::{Method, Response, Uri}};
+    /// use axum_response_cache::{CacheLayer, CachedResponse};
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let layer = CacheLayer::with_lifespan(60);
+    /// let key = (Method::GET, Uri::from_static("/report"));
+    /// let report = layer
+    ///     .get_or_compute(key, || async {
+    ///         let (parts, _) = Response::new(()).into_parts();
+    ///         CachedResponse::new(parts, Bytes::from("expensive report"))
+    ///     })
+    ///     .await;
+    /// # }
+    /// ```
+    pub async fn get_or_compute<F, Fut>(&self, key: Key, compute: F) -> CachedResponse
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = CachedResponse>,
+    {
+        if let Some(value) = self.fresh_value(&key) {
+            return value;
+        }
+        let leader = match coalesce::join(&self.flights, &key) {
+            coalesce::Flight::Follower(receiver) => {
+                debug!("Value being computed already, waiting for it.");
+                let value = coalesce::wait(receiver)
+                    .await
+                    .filter(|value| value.matches(&HeaderMap::new()));
+                if let Some(value) = value {
+                    return value;
+                }
+                None
+            }
+            coalesce::Flight::Leader(leader) => {
+                // the previous leader could’ve stored it meanwhile
+                if let Some(value) = self.fresh_value(&key) {
+                    leader.publish(&value);
+                    return value;
+                }
+                Some(leader)
+            }
+        };
+        let value = compute().await;
+        if let Some(leader) = leader {
+            leader.publish(&value);
+        }
+        store(
+            &self.cache,
+            self.secondary.as_ref(),
+            key,
+            value.clone(),
+            EntryRules {
+                lock_warning: self.lock_warning,
+                cache_latency: self.cache_latency.clone(),
+                ..EntryRules::default()
+            },
+        );
+        value
+    }
+
+    /// Return the fresh response cached for the key, see [`CacheLayer::get_or_compute`].
+    fn fresh_value(&self, key: &Key) -> Option<CachedResponse> {
+        #[cfg(feature = "hot")]
+        let count_hits = self.promote.is_some() || self.hot.is_some();
+        #[cfg(not(feature = "hot"))]
+        let count_hits = self.promote.is_some();
+        let mut guard = self.cache.lock().unwrap();
+        let (cached, evicted) = read_entry(&mut *guard, key, self.promote, count_hits);
+        let value = cached?.select(&HeaderMap::new())?;
+        (!evicted && !value.soft_invalidated).then_some(value)
+    }
+
+    /// Mark the responses cached for the key as needing revalidation without removing them,
+    /// returning whether there were any: the next request is served the cached response right
+    /// away while it’s refreshed in the background (like with `stale-while-revalidate`), and the
+    /// concurrent requests are served the cached response until the refreshed one is stored.
+    ///
+    /// The cached response is kept when the refresh fails.
+    pub fn soft_invalidate(&self, key: &Key) -> bool {
+        let mut guard = self.cache.lock().unwrap();
+        let Some(entry) = guard.cache_get_mut(key) else {
+            return false;
+        };
+        for variant in &mut entry.variants {
+            variant.soft_invalidated = true;
+        }
+        self.invalidated.advance();
+        true
+    }
+}
+
+impl<C> CacheLayer<C>
+where
+    C: Cached<Key, CachedEntry> + CacheKeys<Key, CachedEntry>,
+{
+    /// Take a snapshot of the cache contents for debugging (eg. to log it or serve it from an
+    /// admin endpoint), with one item for each cached variant of the responses. The snapshot
+    /// includes the expired entries not purged from the store yet, but not the bodies.
+    pub fn dump(&self) -> Vec<(Key, CachedEntryInfo)> {
+        self.dump_entries(false)
+    }
+
+    /// Take a snapshot of the cache contents like [`CacheLayer::dump`], including the bodies.
+    pub fn dump_full(&self) -> Vec<(Key, CachedEntryInfo)> {
+        self.dump_entries(true)
+    }
+
+    /// Iterate over the cached variants of the responses (eg. to build a sitemap or a report of
+    /// the cached URLs with their sizes and ages). The iterator walks the snapshot taken like
+    /// with [`CacheLayer::dump`], so the cache isn’t locked while iterating.
+    pub fn entries(&self) -> impl Iterator<Item = (Key, CachedEntryInfo)> {
+        self.dump_entries(false).into_iter()
+    }
+
+    /// Check whether the request would be served from the cache (eg. to validate the keying and
+    /// `Vary` configuration with sample requests), without serving or affecting any entries.
+    ///
+    /// The key is built the same way as when handling the request, and the requests bypassing
+    /// the cache never hit. The freshness is judged by the age of the stored response, and the
+    /// predicate of [`CacheLayer::async_skip_if`] isn’t evaluated. The requests keyed on their
+    /// bodies (see [`MethodPolicy::body_in_key`]) are never reported to hit, as their bodies
+    /// aren’t read.
+    pub fn would_hit(&self, request: &Request<Body>) -> bool {
+        let bypass_options = BypassOptions {
+            cache_preflight: self.cache_preflight,
+            auth_mode: self.auth_mode,
+            bypass_cookie: self.bypass_cookie,
+            request_cache_control: self.request_cache_control,
+            method_policies: self.method_policies.as_deref(),
+            get_body_policy: self.get_body_policy,
+        };
+        // the bodies aren’t read, so the requests keyed by them can’t be probed
+        let body_keyed = self
+            .method_policies
+            .as_ref()
+            .and_then(|policies| policies.get(request.method()))
+            .is_some_and(|policy| policy.body_in_key)
+            || (self.get_body_policy != GetBodyPolicy::Ignore && has_body(request));
+        if body_keyed || bypass_reason(request, bypass_options).is_some() {
+            return false;
+        }
+        let Some((custom_uri, _)) = request_key(
+            request,
+            self.key_fn.as_ref(),
+            self.key_extension,
+            KeyOptions {
+                by_matched_path: self.key_by_matched_path,
+                path_and_query_only: self.key_path_and_query_only,
+                uri_source: self.uri_source,
+                vary_by_host: self.vary_by_host,
+                trusted_headers: &self.trusted_headers,
+                body_keyed: self.get_body_policy == GetBodyPolicy::IncludeInKey,
+                auth_mode: self.auth_mode,
+                hashed: self.hashed_keys,
+            },
+        ) else {
+            return false;
+        };
+        let key = (
+            request.method().clone(),
+            custom_uri.unwrap_or_else(|| request.uri().clone()),
+        );
+        let mut headers = Cow::Borrowed(request.headers());
+        if let Some(formats) = &self.image_formats {
+            negotiation::negotiate_accept(headers.to_mut(), formats);
+        }
+        let guard = self.cache.lock().unwrap();
+        let lifespan = guard.cache_lifespan().map(Duration::from_secs);
+        let Some(entry) = guard.cache_peek(&key) else {
+            return false;
+        };
+        let directives = self
+            .request_cache_control
+            .then(|| cache_control::request_directives(request.headers()));
+        let Some(value) = entry.variant(&headers) else {
+            return false;
+        };
+        // the variant of the mismatched media type is fetched again, see `lookup`
+        if self.consistent_content_type && !entry.accepts_media_type(value) {
+            return false;
+        }
+        let fresh = match value.ttl.or(lifespan) {
+            Some(ttl) => value.timestamp.elapsed() < ttl,
+            None => true,
+        };
+        fresh
+            && directives
+                .as_ref()
+                .is_none_or(|directives| value.satisfies(directives))
+    }
+
+    /// Remove the cached responses whose keys match the predicate, returning the number of
+    /// removed keys. It covers the invalidation rules [`InvalidationMsg`] can’t express (eg. by
+    /// method, suffix or with exceptions).
+    ///
+    /// ```rust
+    /// use axum::http::Method;
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let layer = CacheLayer::with_lifespan(60);
+    /// // purge everything under `/api/v1` except the health check
+    /// layer.invalidate_matching(|(_method, uri)| {
+    ///     uri.path().starts_with("/api/v1/") && uri.path() != "/api/v1/health"
+    /// });
+    /// // purge all the `POST` responses
+    /// layer.invalidate_matching(|(method, _uri)| method == Method::POST);
+    /// ```
+    pub fn invalidate_matching<F>(&self, predicate: F) -> usize
+    where
+        F: Fn(&Key) -> bool,
+    {
+        let mut guard = self.cache.lock().unwrap();
+        let removed: Vec<Key> = guard
+            .cache_keys()
+            .into_iter()
+            .filter(|key| predicate(key))
+            .filter(|key| guard.cache_remove(key).is_some())
+            .collect();
+        drop(guard);
+        debug!("Invalidated {} cached responses", removed.len());
+        let count = removed.len();
+        self.invalidated.mark(removed);
+        count
+    }
+
+    /// Remove the cached variants of the responses matching the predicate, returning the number
+    /// of removed variants.
+    ///
+    /// It’s meant to be used when changing the settings at runtime: a new layer created from a
+    /// clone of the old one shares its cache, so the entries stored under the old policy can be
+    /// flushed.
+    ///
+    /// ```rust
+    /// use axum::http::header;
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// let layer = CacheLayer::with_lifespan(60);
+    /// // …
+    /// let reconfigured = layer.clone().max_header_bytes(4096);
+    /// reconfigured.flush_where(|_key, info| {
+    ///     info.headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum::<usize>() > 4096
+    /// });
+    /// ```
+    pub fn flush_where<F>(&self, predicate: F) -> usize
+    where
+        F: Fn(&Key, &CachedEntryInfo) -> bool,
+    {
+        let mut guard = self.cache.lock().unwrap();
+        let flushed: Vec<(Key, usize)> = guard
+            .cache_entries()
+            .into_iter()
+            .filter_map(|(key, entry)| {
+                let matching = entry
+                    .variants
+                    .iter()
+                    .filter(|value| predicate(key, &CachedEntryInfo::new(value, false)))
+                    .count();
+                (matching > 0).then(|| (key.clone(), matching))
+            })
+            .collect();
+        self.invalidated
+            .mark(flushed.iter().map(|(key, _)| key.clone()));
+        let mut removed = 0;
+        for (key, matching) in flushed {
+            removed += matching;
+            let Some(entry) = guard.cache_get_mut(&key) else {
+                // expired entries are removed as a whole
+                guard.cache_remove(&key);
+                continue;
+            };
+            entry
+                .variants
+                .retain(|value| !predicate(&key, &CachedEntryInfo::new(value, false)));
+            if entry.variants.is_empty() {
+                guard.cache_remove(&key);
+            }
+        }
+        removed
+    }
+
+    /// Return the histograms of the cache lookup and store latencies measured so far (by this
+    /// layer and its clones), or `None` unless enabled with
+    /// [`CacheLayer::measure_cache_latency`].
+    pub fn cache_latency(&self) -> Option<CacheLatency> {
+        Some(self.cache_latency.as_ref()?.snapshot())
+    }
+
+    /// Estimate the memory used by the cached responses: the sizes of their stored bodies and
+    /// headers (including the expired entries not purged from the store yet).
+    pub fn memory_usage(&self) -> usize {
+        stored_bytes(&*self.cache.lock().unwrap())
+    }
+
+    /// Skip reinserting the expired values (see [`CacheLayer::skip_stale_reinsert`]) while the
+    /// cached responses use more than the given number of bytes (see
+    /// [`CacheLayer::memory_usage`]), so the stale data can be reclaimed under memory pressure
+    /// at the cost of weaker protection against concurrent refreshes.
+    ///
+    /// The usage is computed by walking the whole cache whenever an expired value is found.
+    pub fn skip_stale_reinsert_above(self, bytes: usize) -> Self {
+        Self {
+            reinsert_limit: Some((bytes, stored_bytes::<C>)),
+            ..self
+        }
+    }
+
+    fn dump_entries(&self, include_body: bool) -> Vec<(Key, CachedEntryInfo)> {
+        let guard = self.cache.lock().unwrap();
+        guard
+            .cache_entries()
+            .into_iter()
+            .flat_map(|(key, entry)| {
+                entry
+                    .variants
+                    .iter()
+                    .map(|value| (key.clone(), CachedEntryInfo::new(value, include_body)))
+            })
+            .collect()
+    }
+}
+
+impl<C> CacheLayer<C>
+where
+    C: Cached<Key, CachedEntry> + CacheKeys<Key, CachedEntry> + Send + 'static,
+{
+    /// Purge the cached responses according to the [`InvalidationMsg`]s received over the
+    /// broadcast channel, eg. bridged from a pub/sub system to keep the caches of multiple
+    /// instances eventually consistent.
+    ///
+    /// The listening task is spawned on the current Tokio runtime (thus this method panics when
+    /// called outside of one) and stops when the channel is closed or (within a second) after
+    /// the layer is dropped, even when no messages arrive. When the receiver lags behind, the
+    /// whole cache is cleared as the missed invalidations can’t be recovered.
+    pub fn invalidate_on(self, receiver: broadcast::Receiver<InvalidationMsg>) -> Self {
+        tokio::spawn(invalidation::listen(
+            Arc::downgrade(&self.cache),
+            Arc::clone(&self.invalidated),
+            receiver,
+        ));
+        self
+    }
+
+    /// Purge the cached responses of the files in the directory (eg. served with `ServeDir`)
+    /// when they change on disk, so they can be cached with a long lifespan. The `prefix` is the
+    /// URI path the directory is served at (eg. `/static`, or an empty string for the root).
+    ///
+    /// The change of a file purges the responses cached for its URI with any query (and for the
+    /// URIs it’s a prefix of), and the change of an `index.html` file also purges the response
+    /// cached for the URI of its directory.
+    ///
+    /// The watching task is spawned on the current Tokio runtime (thus this method panics when
+    /// called outside of one) and stops (with the watcher) within a second after the layer is
+    /// dropped, even when no files change. The whole cache is cleared when the watcher reports
+    /// an error, as the missed changes can’t be recovered.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error when the directory can’t be watched.
+    #[cfg(feature = "watch")]
+    pub fn invalidate_on_file_changes(
+        self,
+        dir: impl AsRef<Path>,
+        prefix: &str,
+    ) -> notify::Result<Self> {
+        watch::watch(
+            Arc::downgrade(&self.cache),
+            Arc::clone(&self.invalidated),
+            dir.as_ref(),
+            prefix,
+        )?;
+        Ok(self)
+    }
+
+    /// Serve the hits of the `size` most hit keys (eg. the homepage) without locking the cache:
+    /// the copies of their entries are kept in a lock-free snapshot consulted before the cache,
+    /// replaced every `period` with the copies of the entries hit the most so far.
+    ///
+    /// Purging (or soft-invalidating) any responses outdates the snapshot, so it isn’t
+    /// consulted until it’s replaced. The responses stored for the hot keys while their copies
+    /// are still fresh (eg. with [`CacheLayer::force_store_header`]) are served once the
+    /// snapshot is replaced. The hits are counted once this is enabled, and
+    /// [`CacheLayer::max_variants_per_key`] still locks the cache to record the hit variants.
+    ///
+    /// The replacing task is spawned on the current Tokio runtime (thus this method panics when
+    /// called outside of one) and stops once the layer is dropped.
+    #[cfg(feature = "hot")]
+    pub fn hot_keys(self, size: usize, period: Duration) -> Self {
+        let hot = Arc::new(hot::Hot::default());
+        tokio::spawn(hot::refresh(
+            Arc::downgrade(&self.cache),
+            Arc::downgrade(&hot),
+            Arc::clone(&self.invalidated),
+            size,
+            period,
+        ));
+        Self {
+            hot: Some(hot),
+            ..self
+        }
+    }
+}
+
+impl CacheLayer<TimedCache<Key, CachedEntry>> {
+    /// Create a new cache layer with the desired TTL in seconds
+    pub fn with_lifespan(ttl_sec: u64) -> CacheLayer<TimedCache<Key, CachedEntry>> {
+        CacheLayer::with(TimedCache::with_lifespan(ttl_sec))
+    }
+}
+
+impl CacheLayer<CostWeightedCache> {
+    /// Create a new cache layer holding at most `size` responses for `ttl_sec` seconds, which
+    /// evicts the responses that are the cheapest to recompute when full (see
+    /// [`CostWeightedCache`]). The cost function receives the parts and the body size of the
+    /// response.
+    ///
+    /// ```rust
+    /// use axum_response_cache::CacheLayer;
+    ///
+    /// // the bigger responses are the more expensive ones to recompute
+    /// let layer = CacheLayer::cost_weighted(100, 60, |_parts, size| size as u64);
+    /// ```
+    pub fn cost_weighted<F>(size: usize, ttl_sec: u64, cost_fn: F) -> Self
+    where
+        F: Fn(&Parts, usize) -> u64 + Send + Sync + 'static,
+    {
+        CacheLayer::with(CostWeightedCache::new(size, ttl_sec, cost_fn))
+    }
+}
+
+impl<C> CacheLayer<C>
+where
+    C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry> + Send + 'static,
+{
+    /// Create a new cache layer writing the stored responses behind to the persistent store
+    /// (eg. on disk), so they survive the restarts without slowing the requests down, a
+    /// shorthand for [`CacheLayer::with`] and [`CacheLayer::write_behind`].
+    pub fn with_write_behind<P>(cache: C, store: P, channel_capacity: usize) -> Self
+    where
+        P: PersistentStore + 'static,
+    {
+        Self::with(cache).write_behind(store, channel_capacity)
+    }
+
+    /// Write the stored responses behind to the persistent store (eg. on disk), so they survive
+    /// the restarts without slowing the requests down. The responses are stored in the cache
+    /// right away and queued for persisting, and the background task writes them to the store
+    /// one by one. The task first repopulates the cache with the entries loaded from the store,
+    /// keeping the ones stored meanwhile.
+    ///
+    /// The queue holds at most `channel_capacity` entries, the responses stored while it’s full
+    /// aren’t persisted. The task is spawned on the current Tokio runtime (thus this method
+    /// panics when called outside of one) and stops once the layer and its services are
+    /// dropped. Invalidating and flushing the entries affects only the first cache.
+    ///
+    /// The secondary cache of the layer (see [`CacheLayer::with_tiers`]) is kept: the responses
+    /// are looked up and written through to it as before, and persisted as well.
+    ///
+    /// ```rust
+    /// # use axum::{async_trait, http::{Method, Uri}};
+    /// # use axum_response_cache::{CachedEntry, PersistentStore, SecondaryCache};
+    /// use axum_response_cache::CacheLayer;
+    /// use cached::TimedCache;
+    ///
+    /// # struct Redis;
+    /// # #[async_trait]
+    /// # impl SecondaryCache for Redis {
+    /// #     async fn get(&self, _: &(Method, Uri)) -> Option<CachedEntry> { None }
+    /// #     async fn set(&self, _: (Method, Uri), _: CachedEntry) {}
+    /// # }
+    /// # struct Disk;
+    /// # #[async_trait]
+    /// # impl PersistentStore for Disk {
+    /// #     async fn load(&self) -> Vec<((Method, Uri), CachedEntry)> { Vec::new() }
+    /// #     async fn persist(&self, _: (Method, Uri), _: CachedEntry) {}
+    /// # }
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let layer = CacheLayer::with_tiers(TimedCache::with_lifespan(60), Redis)
+    ///     .write_behind(Disk, 1024);
+    /// # }
+    /// ```
+    pub fn write_behind<P>(self, store: P, channel_capacity: usize) -> Self
+    where
+        P: PersistentStore + 'static,
+    {
+        let write_behind = write_behind::spawn(
+            Arc::downgrade(&self.cache),
+            store,
+            channel_capacity,
+            self.secondary.clone(),
+        );
+        Self {
+            secondary: Some(Arc::new(write_behind)),
+            ..self
+        }
+    }
+
+    /// Erase the type of the cache, so layers using different caches can be stored in one
+    /// collection (eg. for routes registered dynamically by plugins).
+    ///
+    /// ```rust
+    /// use axum::{routing::{get, Route}, Router};
+    /// use axum_response_cache::{BoxCacheLayer, CacheLayer};
+    /// use cached::stores::TimedSizedCache;
+    ///
+    /// let layers: Vec<(&str, BoxCacheLayer<Route>)> = vec![
+    ///     ("/short", CacheLayer::with_lifespan(1).boxed()),
+    ///     ("/sized", CacheLayer::with(TimedSizedCache::with_size_and_lifespan(50, 60)).boxed()),
+    /// ];
+    ///
+    /// let mut router = Router::new();
+    /// for (path, layer) in layers {
+    ///     router = router.route(path, get(|| async { "Hello, world!" }).layer(layer));
+    /// }
+    /// # let _: Router = router;
+    /// ```
+    pub fn boxed<S>(self) -> BoxCacheLayer<S>
+    where
+        S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        BoxCacheLayer {
+            layer: Arc::new(move |inner| BoxCloneService::new(self.layer(inner))),
+        }
+    }
+}
+
+/// The [`CacheLayer`] with the type of its cache erased, see [`CacheLayer::boxed`].
+pub struct BoxCacheLayer<S> {
+    layer: Arc<dyn Fn(S) -> BoxCloneService<Request<Body>, Response, Infallible> + Send + Sync>,
+}
+
+impl<S> Clone for BoxCacheLayer<S> {
+    fn clone(&self) -> Self {
+        Self {
+            layer: Arc::clone(&self.layer),
+        }
+    }
+}
+
+impl<S> Layer<S> for BoxCacheLayer<S> {
+    type Service = BoxCloneService<Request<Body>, Response, Infallible>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        (self.layer)(inner)
+    }
+}
+
+/// Handle the request with the cache in front of the rest of the middleware stack, to compose
+/// the layer with [`axum::middleware::from_fn_with_state`] instead of adding it as a tower
+/// layer. The responses are shared by all the requests handled with the same layer (or its
+/// clones).
+///
+/// ```rust
+/// use axum::{middleware, routing::get, Router};
+/// use axum_response_cache::{cache_middleware, CacheLayer};
+///
+/// let router: Router = Router::new()
+///     .route("/", get(|| async { "Hello, world!" }))
+///     .layer(middleware::from_fn_with_state(
+///         CacheLayer::with_lifespan(60),
+///         cache_middleware,
+///     ));
+/// ```
+pub async fn cache_middleware<C>(
+    State(layer): State<CacheLayer<C>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response
+where
+    C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry> + Send + 'static,
+{
+    layer.layer(next).oneshot(request).await.unwrap()
+}
+
+impl<S, C> Layer<S> for CacheLayer<C> {
+    type Service = CacheService<S, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Self::Service {
+            inner,
+            cache: Arc::clone(&self.cache),
+            secondary: self.secondary.clone(),
+            secondary_backpressure: self.secondary_backpressure,
+            use_stale: self.use_stale,
+            stale_server_errors_only: self.stale_server_errors_only,
+            stale_directives: self.stale_directives,
+            revalidate: self.revalidate,
+            upstream_not_modified: self.upstream_not_modified,
+            max_revalidation_age: self.max_revalidation_age,
+            stale_reinsert: self.stale_reinsert,
+            reinsert_limit: self.reinsert_limit,
+            promote: self.promote,
+            #[cfg(feature = "hot")]
+            hot: self.hot.clone(),
+            limit: self.limit,
+            tee_streaming: self.tee_streaming,
+            oversized: Arc::clone(&self.oversized),
+            oversized_cooldown: self.oversized_cooldown,
+            max_header_bytes: self.max_header_bytes,
+            allow_invalidation: self.allow_invalidation,
+            request_cache_control: self.request_cache_control,
+            force_store: self.force_store.clone(),
+            key_by_matched_path: self.key_by_matched_path,
+            key_path_and_query_only: self.key_path_and_query_only,
+            uri_source: self.uri_source,
+            vary_by_host: self.vary_by_host,
+            trusted_headers: self.trusted_headers.clone(),
+            image_formats: self.image_formats.clone(),
+            key_fn: self.key_fn.clone(),
+            ttl_bounds: self.ttl_bounds,
+            hashed_keys: self.hashed_keys,
+            key_extension: self.key_extension,
+            cache_preflight: self.cache_preflight,
+            add_response_headers: self.add_response_headers,
+            refresh_date: self.refresh_date,
+            serve_ranges: self.serve_ranges,
+            forward_cold_ranges: self.forward_cold_ranges,
+            rewrite_location_host: self.rewrite_location_host,
+            dedup_link_headers: self.dedup_link_headers,
+            reuse_unchanged_bodies: self.reuse_unchanged_bodies,
+            record_origin: self.record_origin,
+            consistent_content_type: self.consistent_content_type,
+            max_variants: self.max_variants,
+            newest_wins: self.newest_wins,
+            measure_latency: self.measure_latency,
+            lock_warning: self.lock_warning,
+            cache_latency: self.cache_latency.clone(),
+            invalidation_signal: self.invalidation_signal.clone(),
+            debug_key_header: self.debug_key_header.clone(),
+            client_cache_control: self.client_cache_control.clone(),
+            coalesce_misses: self.coalesce_misses,
+            coalesce_failure: self.coalesce_failure,
+            coalesce_only: self.coalesce_only,
+            flights: Arc::clone(&self.flights),
+            method_policies: self.method_policies.clone(),
+            get_body_policy: self.get_body_policy,
+            invalidated: Arc::clone(&self.invalidated),
+            decorate_hit: self.decorate_hit.clone(),
+            serve_headers: self.serve_headers.clone(),
+            compression: self.compression,
+            file_backed: self.file_backed.clone(),
+            content_digest: self.content_digest,
+            async_skip: self.async_skip.clone(),
+            async_skip_timeout: self.async_skip_timeout,
+            async_skip_failure: self.async_skip_failure,
+            cache_only: self.cache_only.clone(),
+            #[cfg(feature = "json")]
+            json_normalization: self.json_normalization.clone(),
+            refuse_set_cookie: self.refuse_set_cookie,
+            cache_rate_limits: self.cache_rate_limits,
+            refused_headers: self.refused_headers.clone(),
+            allowed_headers: self.allowed_headers.clone(),
+            content_types: self.content_types.clone(),
+            auth_mode: self.auth_mode,
+            bypass_cookie: self.bypass_cookie,
+        }
+    }
+}
+
+pub struct CacheService<S, C> {
+    inner: S,
+    cache: Arc<Mutex<C>>,
+    secondary: Option<tiered::Secondary>,
+    secondary_backpressure: bool,
+    use_stale: bool,
+    stale_server_errors_only: bool,
+    stale_directives: bool,
+    revalidate: bool,
+    upstream_not_modified: bool,
+    max_revalidation_age: Option<Duration>,
+    stale_reinsert: bool,
+    reinsert_limit: Option<(usize, UsageFn<C>)>,
+    promote: Option<(u64, Duration)>,
+    #[cfg(feature = "hot")]
+    hot: Option<Arc<hot::Hot>>,
+    limit: usize,
+    tee_streaming: bool,
+    oversized: Arc<Mutex<HashMap<Key, std::time::Instant>>>,
+    oversized_cooldown: Option<Duration>,
+    max_header_bytes: usize,
+    allow_invalidation: bool,
+    request_cache_control: bool,
+    force_store: Option<(HeaderName, HeaderValue)>,
+    key_by_matched_path: bool,
+    key_path_and_query_only: bool,
+    uri_source: UriSource,
+    vary_by_host: bool,
+    trusted_headers: Arc<[HeaderName]>,
+    image_formats: Option<Arc<[HeaderValue]>>,
+    key_fn: Option<KeyFn>,
+    ttl_bounds: Option<(Duration, Duration)>,
+    hashed_keys: bool,
+    key_extension: Option<ExtensionFn>,
+    cache_preflight: bool,
+    add_response_headers: bool,
+    refresh_date: bool,
+    serve_ranges: bool,
+    forward_cold_ranges: bool,
+    rewrite_location_host: bool,
+    dedup_link_headers: bool,
+    reuse_unchanged_bodies: bool,
+    record_origin: bool,
+    consistent_content_type: bool,
+    max_variants: Option<usize>,
+    newest_wins: bool,
+    measure_latency: bool,
+    lock_warning: Option<Duration>,
+    cache_latency: Option<Arc<latency::Latencies>>,
+    invalidation_signal: Option<(HeaderName, HeaderValue)>,
+    debug_key_header: Option<HeaderName>,
+    client_cache_control: Option<HeaderValue>,
+    coalesce_misses: bool,
+    coalesce_failure: CoalesceFailure,
+    coalesce_only: bool,
+    flights: coalesce::Flights,
+    method_policies: Option<Arc<HashMap<Method, MethodPolicy>>>,
+    get_body_policy: GetBodyPolicy,
+    invalidated: Arc<invalidation::Invalidated>,
+    decorate_hit: Option<HitDecorator>,
+    serve_headers: Option<Arc<HeaderMap>>,
+    compression: Option<Compression>,
+    file_backed: Option<(Arc<Path>, usize)>,
+    content_digest: bool,
+    async_skip: Option<AsyncPredicate>,
+    async_skip_timeout: Duration,
+    async_skip_failure: GuardFailure,
+    cache_only: Option<Arc<AtomicBool>>,
+    #[cfg(feature = "json")]
+    json_normalization: Option<json::Normalization>,
+    refuse_set_cookie: bool,
+    cache_rate_limits: bool,
+    refused_headers: Arc<[HeaderName]>,
+    allowed_headers: Option<Arc<[HeaderName]>>,
+    content_types: Option<Arc<[String]>>,
+    auth_mode: AuthMode,
+    bypass_cookie: bool,
+}
+
+// implemented manually, as the cache itself is shared and doesn’t have to be `Clone`
+impl<S: Clone, C> Clone for CacheService<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            cache: Arc::clone(&self.cache),
+            secondary: self.secondary.clone(),
+            secondary_backpressure: self.secondary_backpressure,
+            use_stale: self.use_stale,
+            stale_server_errors_only: self.stale_server_errors_only,
+            stale_directives: self.stale_directives,
+            revalidate: self.revalidate,
+            upstream_not_modified: self.upstream_not_modified,
+            max_revalidation_age: self.max_revalidation_age,
+            stale_reinsert: self.stale_reinsert,
+            reinsert_limit: self.reinsert_limit,
+            promote: self.promote,
+            #[cfg(feature = "hot")]
+            hot: self.hot.clone(),
+            limit: self.limit,
+            tee_streaming: self.tee_streaming,
+            oversized: self.oversized.clone(),
+            oversized_cooldown: self.oversized_cooldown,
+            max_header_bytes: self.max_header_bytes,
+            allow_invalidation: self.allow_invalidation,
+            request_cache_control: self.request_cache_control,
+            force_store: self.force_store.clone(),
+            key_by_matched_path: self.key_by_matched_path,
+            key_path_and_query_only: self.key_path_and_query_only,
+            uri_source: self.uri_source,
+            vary_by_host: self.vary_by_host,
+            trusted_headers: self.trusted_headers.clone(),
+            image_formats: self.image_formats.clone(),
+            key_fn: self.key_fn.clone(),
+            ttl_bounds: self.ttl_bounds,
+            hashed_keys: self.hashed_keys,
+            key_extension: self.key_extension,
+            cache_preflight: self.cache_preflight,
+            add_response_headers: self.add_response_headers,
+            refresh_date: self.refresh_date,
+            serve_ranges: self.serve_ranges,
+            forward_cold_ranges: self.forward_cold_ranges,
+            rewrite_location_host: self.rewrite_location_host,
+            dedup_link_headers: self.dedup_link_headers,
+            reuse_unchanged_bodies: self.reuse_unchanged_bodies,
+            record_origin: self.record_origin,
+            consistent_content_type: self.consistent_content_type,
+            max_variants: self.max_variants,
+            newest_wins: self.newest_wins,
+            measure_latency: self.measure_latency,
+            lock_warning: self.lock_warning,
+            cache_latency: self.cache_latency.clone(),
+            invalidation_signal: self.invalidation_signal.clone(),
+            debug_key_header: self.debug_key_header.clone(),
+            client_cache_control: self.client_cache_control.clone(),
+            coalesce_misses: self.coalesce_misses,
+            coalesce_failure: self.coalesce_failure,
+            coalesce_only: self.coalesce_only,
+            flights: Arc::clone(&self.flights),
+            method_policies: self.method_policies.clone(),
+            get_body_policy: self.get_body_policy,
+            invalidated: Arc::clone(&self.invalidated),
+            decorate_hit: self.decorate_hit.clone(),
+            serve_headers: self.serve_headers.clone(),
+            compression: self.compression,
+            file_backed: self.file_backed.clone(),
+            content_digest: self.content_digest,
+            async_skip: self.async_skip.clone(),
+            async_skip_timeout: self.async_skip_timeout,
+            async_skip_failure: self.async_skip_failure,
+            cache_only: self.cache_only.clone(),
+            #[cfg(feature = "json")]
+            json_normalization: self.json_normalization.clone(),
+            refuse_set_cookie: self.refuse_set_cookie,
+            cache_rate_limits: self.cache_rate_limits,
+            refused_headers: self.refused_headers.clone(),
+            allowed_headers: self.allowed_headers.clone(),
+            content_types: self.content_types.clone(),
+            auth_mode: self.auth_mode,
+            bypass_cookie: self.bypass_cookie,
+        }
+    }
+}
+
+impl<S, C> Service<Request<Body>> for CacheService<S, C>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry> + Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = CacheFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let (true, Some(secondary)) = (self.secondary_backpressure, &self.secondary) {
+            std::task::ready!(secondary.poll_write_capacity(cx));
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    #[cfg_attr(not(feature = "span-attributes"), instrument(skip(self, request)))]
+    #[cfg_attr(
+        feature = "span-attributes",
+        instrument(skip(self, request), fields(cache.hit, cache.key, cache.ttl_remaining))
+    )]
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        // the secret never reaches the wrapped service, whichever way the request goes
+        if let Some((name, secret)) = &self.force_store {
+            let forced = request
+                .headers()
+                .get(name)
+                .is_some_and(|value| constant_time_eq(value.as_bytes(), secret.as_bytes()));
+            request.headers_mut().remove(name);
+            if forced {
+                request.extensions_mut().insert(ForceStore);
+            }
+        }
+
+        let bypass_options = BypassOptions {
+            cache_preflight: self.cache_preflight,
+            auth_mode: self.auth_mode,
+            bypass_cookie: self.bypass_cookie,
+            request_cache_control: self.request_cache_control,
+            method_policies: self.method_policies.as_deref(),
+            get_body_policy: self.get_body_policy,
+        };
+        if let Some(reason) = bypass_reason(&request, bypass_options) {
+            debug!("{reason}, bypassing the cache");
+            return CacheFuture::boxed(self.clone().bypass(request));
+        }
+
+        let body_keyed = self
+            .method_policy(request.method())
+            .is_some_and(|policy| policy.body_in_key);
+        let body_keyed = match self.get_body_policy {
+            _ if body_keyed || !has_body(&request) => body_keyed,
+            GetBodyPolicy::Ignore => {
+                tracing::warn!("Request has a body, not including it in the key");
+                false
+            }
+            GetBodyPolicy::IncludeInKey => true,
+            // the request with a body bypasses the cache, see `bypass_reason`
+            GetBodyPolicy::Bypass => false,
+        };
+        if self.async_skip.is_some() || body_keyed {
+            let this = self.clone();
+            return CacheFuture::boxed(
+                async move {
+                    let mut request = request;
+                    if let Some(predicate) = this.async_skip.clone() {
+                        let skip = match tokio::time::timeout(
+                            this.async_skip_timeout,
+                            predicate(&request),
+                        )
+                        .await
+                        {
+                            Ok(skip) => skip,
+                            Err(_) => {
+                                let failure = this.async_skip_failure;
+                                debug!("Async skip guard timed out, applying {failure:?}");
+                                failure == GuardFailure::Bypass
+                            }
+                        };
+                        if skip {
+                            debug!("Async skip guard passed, bypassing the cache");
+                            return this.bypass(request).await;
+                        }
+                    }
+                    if body_keyed {
+                        request = match digest_body(request, this.limit).await {
+                            Ok(request) => request,
+                            Err(response) => return Ok(response),
+                        };
+                        if request.extensions().get::<BodyDigest>().is_none() {
+                            debug!("Request body can’t be keyed, bypassing the cache");
+                            return this.bypass(request).await;
+                        }
+                    }
+                    match this.lookup(&mut request) {
+                        Lookup::Hit(response) => Ok(response),
+                        Lookup::Miss(miss) => this.fetch(request, miss).await,
+                        Lookup::Bypass => this.bypass(request).await,
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+
+        // the hits are resolved right away, so they don’t need a boxed future
+        match self.lookup(&mut request) {
+            Lookup::Hit(response) => CacheFuture::ready(response),
+            Lookup::Miss(miss) => CacheFuture::boxed(self.clone().fetch(request, miss)),
+            Lookup::Bypass => CacheFuture::boxed(self.clone().bypass(request)),
+        }
+    }
+}
+
+/// The outcome of looking the request up in the cache.
+enum Lookup {
+    Hit(Response),
+    // boxed, as the misses allocate anyway
+    Miss(Box<Miss>),
+    Bypass,
+}
+
+/// The state of the cache lookup needed to fetch the response from the wrapped service.
+struct Miss {
+    key: Key,
+    stale: Option<CachedResponse>,
+    lifespan: Option<u64>,
+    options: ServeOptions,
+    ttl: Option<Duration>,
+    range_forwarded: bool,
+    signaled: bool,
+    soft: bool,
+    leader: Option<coalesce::Leader>,
+}
+
+impl<S, C> CacheService<S, C>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry> + Send + 'static,
+{
+    /// Pass the request to the wrapped service without reading or updating the cache. Its
+    /// response is returned as it is, so the streamed bodies are neither buffered nor limited.
+    async fn bypass(mut self, mut request: Request<Body>) -> Result<Response, Infallible> {
+        if self.is_cache_only() {
+            debug!("Cache-only mode, not passing the bypassed request");
+            return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
+        }
+        let key = (request.method().clone(), request.uri().clone());
+        request.extensions_mut().insert(CacheInfo {
+            status: CacheStatus::Bypass,
+            key,
+            age: None,
+        });
+        self.inner.call(request).await
+    }
+
+    /// Check whether the layer is switched to the cache-only mode.
+    fn is_cache_only(&self) -> bool {
+        self.cache_only
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Check whether the hits of the entries are counted, for [`CacheLayer::promote_after`] or
+    /// the ranking of the hot keys (see [`CacheLayer::hot_keys`]).
+    fn counts_hits(&self) -> bool {
+        #[cfg(feature = "hot")]
+        if self.hot.is_some() {
+            return true;
+        }
+        self.promote.is_some()
+    }
+
+    /// Return the policy configured for the method, see [`CacheLayer::method_policies`].
+    fn method_policy(&self, method: &Method) -> Option<MethodPolicy> {
+        self.method_policies.as_ref()?.get(method).copied()
+    }
+
+    /// Check whether the requests with the method are keyed by their bodies (when they have
+    /// one), see [`MethodPolicy::body_in_key`] and [`GetBodyPolicy::IncludeInKey`].
+    fn keys_bodies(&self, method: &Method) -> bool {
+        self.get_body_policy == GetBodyPolicy::IncludeInKey
+            || self
+                .method_policy(method)
+                .is_some_and(|policy| policy.body_in_key)
+    }
+
+    /// Look the request up in the cache, serving the fresh response directly.
+    fn lookup(&self, request: &mut Request<Body>) -> Lookup {
+        if let Some(formats) = &self.image_formats {
+            negotiation::negotiate_accept(request.headers_mut(), formats);
+        }
+        let started = self
+            .decorate_hit
+            .as_ref()
+            .map(|_| std::time::Instant::now());
+        let Some((custom_uri, ttl)) = request_key(
+            request,
+            self.key_fn.as_ref(),
+            self.key_extension,
+            KeyOptions {
+                by_matched_path: self.key_by_matched_path,
+                path_and_query_only: self.key_path_and_query_only,
+                uri_source: self.uri_source,
+                vary_by_host: self.vary_by_host,
+                trusted_headers: &self.trusted_headers,
+                body_keyed: self.keys_bodies(request.method()),
+                auth_mode: self.auth_mode,
+                hashed: self.hashed_keys,
+            },
+        ) else {
+            debug!("Request lacks the keyed extension, bypassing the cache");
+            return Lookup::Bypass;
+        };
+        let ttl = ttl.or_else(|| self.method_policy(request.method())?.ttl);
+        let uri_taken = custom_uri.is_none();
+        // move the method and URI out of the request, so they aren’t cloned on cache hits
+        let key = (
+            std::mem::take(request.method_mut()),
+            custom_uri.unwrap_or_else(|| std::mem::take(request.uri_mut())),
+        );
+
+        // Check for the custom header "X-Invalidate-Cache" if invalidation is allowed
+        if self.allow_invalidation && request.headers().contains_key("X-Invalidate-Cache") {
+            // Manually invalidate the cache for this key
+            self.cache.lock().unwrap().cache_remove(&key);
+            debug!("Cache invalidated manually for key {:?}", key);
+            self.invalidated.mark([key.clone()]);
+        }
+        let signaled = self.invalidation_signal.is_some() && self.invalidated.take(&key);
+
+        #[cfg(feature = "hot")]
+        let hot = self
+            .hot
+            .as_ref()
+            .and_then(|hot| hot.get(&key, request.headers(), self.invalidated.generation()));
+        #[cfg(not(feature = "hot"))]
+        let hot = None;
+        let (cached, evicted, lifespan) = if let Some((entry, lifespan)) = hot {
+            debug!("Hot value found in the snapshot");
+            (Some(entry), false, lifespan)
+        } else {
+            let locking = self
+                .cache_latency
+                .as_ref()
+                .map(|_| std::time::Instant::now());
+            let mut guard = self.cache.lock().unwrap();
+            let (cached, evicted) = read_entry(&mut *guard, &key, self.promote, self.counts_hits());
+            // the expired value isn’t in the cache anymore, so reinserting it adds to the usage
+            let pressure = match (cached.as_ref(), self.reinsert_limit) {
+                (Some(stale), Some((limit, usage))) if evicted => {
+                    usage(&guard) + stale.size() > limit
+                }
+                _ => false,
+            };
+            if pressure {
+                debug!("Cache over the memory limit, not reinserting stale value");
+            }
+            if let (Some(stale), true, true, false) =
+                (cached.as_ref(), evicted, self.stale_reinsert, pressure)
+            {
+                // reinsert stale value immediately so that others don’t schedule their updating
+                debug!("Found stale value in cache, reinsterting and attempting refresh");
+                guard.cache_set(key.clone(), stale.clone());
+            }
+            let lifespan = guard.cache_lifespan();
+            drop(guard);
+            if let (Some(latency), Some(locking)) = (&self.cache_latency, locking) {
+                latency.lookup.record(locking.elapsed());
+            }
+            (cached, evicted, lifespan)
+        };
+        let directives = self
+            .request_cache_control
+            .then(|| cache_control::request_directives(request.headers()));
+        let cached = cached.and_then(|entry| {
+            let expected = self
+                .consistent_content_type
+                .then(|| entry.variants.first().map(CachedResponse::media_type));
+            entry.select(request.headers()).filter(|value| {
+                // the mismatched variant is fetched again
+                expected
+                    .as_ref()
+                    .is_none_or(|expected| *expected == Some(value.media_type()))
+            })
+        });
+        let cached = cached.filter(|value| {
+            // the response the client refuses is fetched again
+            directives
+                .as_ref()
+                .is_none_or(|directives| value.satisfies(directives))
+        });
+
+        let headers = request.headers();
+        let options = ServeOptions {
+            accepts_compressed: self
+                .compression
+                .is_some_and(|compression| compression.is_accepted(headers)),
+            refresh_date: self.refresh_date,
+            ranges: self.serve_ranges,
+            range: headers
+                .get(header::RANGE)
+                .filter(|_| self.serve_ranges)
+                .cloned(),
+            if_range: headers
+                .get(header::IF_RANGE)
+                .filter(|_| self.serve_ranges)
+                .cloned(),
+            host: headers
+                .get(header::HOST)
+                .filter(|_| self.rewrite_location_host)
+                .cloned(),
+            decorate_hit: self.decorate_hit.clone(),
+            serve_headers: self.serve_headers.clone(),
+            lookup: started.map(|started| started.elapsed()).unwrap_or_default(),
+        };
+        let soft = matches!((&cached, evicted), (Some(value), false) if value.soft_invalidated);
+        if soft {
+            debug!("Soft invalidated value, refreshing it in the background");
+            // only this request refreshes it, the others are served the value meanwhile
+            if let Some(entry) = self.cache.lock().unwrap().cache_get_mut(&key) {
+                entry
+                    .variants
+                    .iter_mut()
+                    .filter(|variant| {
+                        cached
+                            .as_ref()
+                            .is_some_and(|value| value.vary == variant.vary)
+                    })
+                    .for_each(|variant| variant.soft_invalidated = false);
+            }
+        }
+        let cached = match cached {
+            Some(value) if soft => Some(CachedResponse {
+                soft_invalidated: false,
+                ..value
+            }),
+            cached => cached,
+        };
+        let stale = match (cached, evicted) {
+            (Some(value), false) if !soft => {
+                if self.max_variants.is_some() {
+                    if let Some(entry) = self.cache.lock().unwrap().cache_get_mut(&key) {
+                        entry.touch(&value.vary);
+                    }
+                }
+                #[cfg(feature = "span-attributes")]
+                span_attributes::record(&key, Some(&value), lifespan);
+                let mut response = value.serve_hit(&options, HitOutcome::Fresh);
+                if let (true, Some((name, value))) = (signaled, &self.invalidation_signal) {
+                    response.headers_mut().insert(name, value.clone());
+                }
+                if let Some(name) = &self.debug_key_header {
+                    add_key_header(&mut response, name.clone(), &key);
+                }
+                if let Some(value) = &self.client_cache_control {
+                    set_client_cache_control(&mut response, value.clone());
+                }
+                return Lookup::Hit(response);
+            }
+            (stale, _) => stale,
+        };
+        #[cfg(feature = "span-attributes")]
+        span_attributes::record(&key, None, lifespan);
+
+        *request.method_mut() = key.0.clone();
+        if uri_taken {
+            *request.uri_mut() = key.1.clone();
+        }
+        let range_forwarded = self.forward_cold_ranges
+            && stale.is_none()
+            && request.headers().contains_key(header::RANGE);
+        if self.serve_ranges && !range_forwarded {
+            // fetch the whole body, the range is served from the cached response
+            request.headers_mut().remove(header::RANGE);
+            request.headers_mut().remove(header::IF_RANGE);
+        }
+        Lookup::Miss(Box::new(Miss {
+            key,
+            stale,
+            lifespan,
+            options,
+            ttl,
+            range_forwarded,
+            signaled,
+            soft,
+            leader: None,
+        }))
+    }
+
+    /// Fetch the response from the wrapped service (or wait for the concurrent miss fetching it,
+    /// see [`CacheLayer::coalesce_misses`]) and update the cache with it, signaling the
+    /// invalidation of the key (see [`CacheLayer::signal_invalidation`]), exposing the key
+    /// (see [`CacheLayer::debug_key_header`]) and setting the client’s `Cache-Control` (see
+    /// [`CacheLayer::set_client_cache_control`]).
+    async fn fetch(
+        self,
+        request: Request<Body>,
+        mut miss: Box<Miss>,
+    ) -> Result<Response, Infallible> {
+        let signal = self.invalidation_signal.clone().filter(|_| miss.signaled);
+        let key_header = self
+            .debug_key_header
+            .clone()
+            .map(|name| (name, miss.key.clone()));
+        let client_cache_control = self.client_cache_control.clone();
+        // the calls of the other methods may have side effects
+        let safe = matches!(miss.key.0, Method::GET | Method::HEAD | Method::OPTIONS);
+        let flight = (self.coalesce_misses && safe && miss.stale.is_none())
+            .then(|| coalesce::join(&self.flights, &miss.key));
+        let coalesced = match flight {
+            Some(coalesce::Flight::Follower(receiver)) => {
+                debug!("Miss being fetched already, waiting for its response.");
+                coalesce::wait(receiver)
+                    .await
+                    .filter(|value| value.matches(request.headers()))
+                    .map(|value| value.serve(&miss.options))
+            }
+            Some(coalesce::Flight::Leader(leader)) => {
+                miss.leader = Some(leader);
+                None
+            }
+            None => None,
+        };
+        let mut response = match coalesced {
+            Some(response) => response,
+            None => self.fetch_response(request, miss).await?,
+        };
+        if let Some((name, value)) = signal {
+            response.headers_mut().insert(name, value);
+        }
+        if let Some((name, key)) = key_header {
+            add_key_header(&mut response, name, &key);
+        }
+        if let Some(value) = client_cache_control {
+            set_client_cache_control(&mut response, value);
+        }
+        Ok(response)
+    }
+
+    /// Fetch the response from the wrapped service and update the cache with it.
+    async fn fetch_response(
+        self,
+        mut request: Request<Body>,
+        miss: Box<Miss>,
+    ) -> Result<Response, Infallible> {
+        let cache_only = self.is_cache_only();
+        let Self {
+            mut inner,
+            cache,
+            secondary,
+            use_stale,
+            stale_server_errors_only,
+            stale_directives,
+            revalidate,
+            upstream_not_modified,
+            max_revalidation_age,
+            limit,
+            tee_streaming,
+            oversized,
+            oversized_cooldown,
+            max_header_bytes,
+            force_store,
+            add_response_headers,
+            dedup_link_headers,
+            reuse_unchanged_bodies,
+            record_origin,
+            consistent_content_type,
+            max_variants,
+            newest_wins,
+            measure_latency,
+            lock_warning,
+            cache_latency,
+            compression,
+            refuse_set_cookie,
+            cache_rate_limits,
+            refused_headers,
+            allowed_headers,
+            content_types,
+            ttl_bounds,
+            coalesce_failure,
+            coalesce_only,
+            vary_by_host,
+            trusted_headers,
+            auth_mode,
+            #[cfg(feature = "mmap")]
+            file_backed,
+            #[cfg(feature = "digest")]
+            content_digest,
+            #[cfg(feature = "json")]
+            json_normalization,
+            ..
+        } = self;
+        let Miss {
+            key,
+            stale,
+            lifespan,
+            options,
+            ttl,
+            range_forwarded,
+            soft,
+            leader,
+            ..
+        } = *miss;
+        let ttl = match ttl_bounds {
+            Some((min, max)) => Some(
+                ttl.or(lifespan.map(Duration::from_secs))
+                    .map_or(max, |ttl| ttl.clamp(min, max)),
+            ),
+            None => ttl,
+        };
+        let settings = StoreSettings {
+            limit,
+            add_response_headers,
+            dedup_link_headers,
+            reuse_unchanged_bodies,
+            compression,
+            keyed_headers: [
+                (vary_by_host, header::HOST),
+                (
+                    auth_mode == AuthMode::PrivateByCredential,
+                    header::AUTHORIZATION,
+                ),
+            ]
+            .into_iter()
+            .filter_map(|(keyed, name)| keyed.then_some(name))
+            .chain(trusted_headers.iter().cloned())
+            .collect(),
+            ttl,
+            origin: record_origin.then(|| origin_request(&request)),
+            requested: std::time::Instant::now(),
+            previous_body: stale
+                .as_ref()
+                .filter(|_| reuse_unchanged_bodies)
+                .and_then(|stale| Some((stale.body_hash?, stale.compression, stale.body.clone()))),
+            rules: EntryRules {
+                consistent_content_type,
+                max_variants,
+                newest_wins,
+                lock_warning,
+                cache_latency,
+            },
+            #[cfg(feature = "mmap")]
+            file_backed,
+            #[cfg(feature = "digest")]
+            content_digest,
+            #[cfg(feature = "json")]
+            json_normalization,
+        };
+        let preflight = is_preflight(&request);
+
+        if let (None, Some(secondary)) = (&stale, &secondary) {
+            if let Some(entry) = secondary.get(&key).await {
+                debug!("Found value in the secondary cache, promoting it");
+                let value = entry.clone().select(request.headers());
+                cache.lock().unwrap().cache_set(key.clone(), entry);
+                if let Some(value) = value {
+                    return Ok(value.serve_hit(&options, HitOutcome::Secondary));
+                }
+            }
+        }
+
+        if cache_only {
+            return Ok(match stale {
+                Some(stale) => {
+                    debug!("Cache-only mode, returning stale value.");
+                    stale.serve_hit(&options, HitOutcome::Stale)
+                }
+                None => {
+                    debug!("Cache-only mode, not fetching the missing value.");
+                    StatusCode::SERVICE_UNAVAILABLE.into_response()
+                }
+            });
+        }
+
+        let forced = request.extensions_mut().remove::<ForceStore>().is_some();
+        let request_headers = request.headers().clone();
+        // the stale response fetched too long ago is fetched again instead of revalidated
+        let revalidable = stale.clone().filter(|stale| {
+            max_revalidation_age.is_none_or(|max_age| stale.fetched.elapsed() < max_age)
+        });
+        let revalidating = match &revalidable {
+            Some(stale) if revalidate && !is_conditional(request.headers()) => {
+                add_validators(request.headers_mut(), &stale.parts.headers)
+            }
+            _ => false,
+        };
+        request.extensions_mut().insert(CacheInfo {
+            status: if stale.is_some() {
+                CacheStatus::Stale
+            } else {
+                CacheStatus::Miss
+            },
+            key: key.clone(),
+            age: stale.as_ref().map(|value| value.timestamp.elapsed()),
+        });
+        let background = stale
+            .clone()
+            .filter(|stale| {
+                soft || (stale_directives
+                    && stale.within_stale_window(stale.stale_while_revalidate, lifespan))
+            })
+            .map(|stale| stale.serve_hit(&options, HitOutcome::Stale));
+        let refresh = async move {
+            let called = std::time::Instant::now();
+            let mut response = inner
+                .call(request)
+                .instrument(tracing::info_span!("inner_service"))
+                .await
+                .unwrap();
+            if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+                debug!("Connection upgraded, passing the response through.");
+                return Ok(response);
+            }
+            if measure_latency {
+                let latency = ResponseLatency(called.elapsed());
+                response.extensions_mut().insert(latency);
+            }
+
+            if let Some((name, _)) = &force_store {
+                response.headers_mut().remove(name);
+            }
+            if range_forwarded && response.status() == StatusCode::PARTIAL_CONTENT {
+                debug!("Partial response to the forwarded range request, not caching.");
+                return Ok(response);
+            }
+            if preflight {
+                // the preflight response is determined by these request headers
+                response.headers_mut().append(
+                    header::VARY,
+                    HeaderValue::from_static(
+                        "origin, access-control-request-method, access-control-request-headers",
+                    ),
+                );
+            }
+
+            let not_modified = response.status() == StatusCode::NOT_MODIFIED;
+            let conditional = is_conditional(&request_headers);
+            if let (Some(mut value), true, true) = (
+                revalidable.clone(),
+                not_modified,
+                revalidating || (upstream_not_modified && !conditional),
+            ) {
+                value.revalidate(response.headers(), settings.requested, ttl);
+                if let Some(name) = refused_header(
+                    response.headers(),
+                    refuse_set_cookie,
+                    &refused_headers,
+                    allowed_headers.as_deref(),
+                ) {
+                    debug!("Revalidation has the {name} header, not storing the updated value.");
+                    return Ok(value.serve_hit(&options, HitOutcome::Revalidated));
+                }
+                debug!("Stale value revalidated, updating its headers.");
+                store(
+                    &cache,
+                    secondary.as_ref(),
+                    key,
+                    value.clone(),
+                    settings.rules,
+                );
+                return Ok(value.serve_hit(&options, HitOutcome::Revalidated));
+            }
+            if upstream_not_modified && not_modified && conditional {
+                let etag = response.headers().get(header::ETAG);
+                let refused = refused_header(
+                    response.headers(),
+                    refuse_set_cookie,
+                    &refused_headers,
+                    allowed_headers.as_deref(),
+                );
+                let revalidated = revalidable.filter(|stale| {
+                    refused.is_none()
+                        && etag.is_some()
+                        && stale.parts.headers.get(header::ETAG) == etag
+                });
+                if let Some(mut value) = revalidated {
+                    debug!(
+                        "Stale value revalidated by the client’s request, updating its headers."
+                    );
+                    value.revalidate(response.headers(), settings.requested, ttl);
+                    store(&cache, secondary.as_ref(), key, value, settings.rules);
+                }
+                debug!("Service validated the client’s copy, forwarding its response.");
+                return Ok(response);
+            }
+
+            // the stored rate limit isn’t a response to fall back to once its window passed
+            let stale_fallback = stale.as_ref().is_some_and(|stale_value| {
+                stale_value.parts.status != StatusCode::TOO_MANY_REQUESTS
+                    && (soft
+                        || (use_stale
+                            && (!stale_server_errors_only || response.status().is_server_error()))
+                        || (stale_directives
+                            && response.status().is_server_error()
+                            && stale_value
+                                .within_stale_window(stale_value.stale_if_error, lifespan)))
+            });
+            // the rate limit doesn’t replace the stale response served instead
+            let rate_limited = retry_after(response.headers()).filter(|_| {
+                cache_rate_limits
+                    && response.status() == StatusCode::TOO_MANY_REQUESTS
+                    && !stale_fallback
+            });
+            let settings = match rate_limited {
+                Some(retry_after) => {
+                    let retry_after =
+                        ttl_bounds.map_or(retry_after, |(min, max)| retry_after.clamp(min, max));
+                    debug!("Response rate limited, caching it for {retry_after:?}.");
+                    StoreSettings {
+                        ttl: Some(retry_after),
+                        ..settings
+                    }
+                }
+                None => settings,
+            };
+            match stale {
+                _ if response.status().is_success() || forced || rate_limited.is_some() => {
+                    let header_bytes = headers_size(response.headers());
+                    if header_bytes > max_header_bytes {
+                        debug!("Response headers too big ({header_bytes} bytes), not caching.");
+                        return Ok(response);
+                    }
+                    if varies_on_everything(response.headers()) {
+                        debug!("Response varies on everything, not caching.");
+                        return Ok(response);
+                    }
+                    if let Some(name) = refused_header(
+                        response.headers(),
+                        refuse_set_cookie,
+                        &refused_headers,
+                        allowed_headers.as_deref(),
+                    ) {
+                        if allowed_headers
+                            .as_ref()
+                            .is_some_and(|allowed| !allowed.contains(name))
+                        {
+                            tracing::warn!(
+                                "Response has the {name} header outside the allowlist, not caching."
+                            );
+                        } else {
+                            debug!("Response has the {name} header, not caching.");
+                        }
+                        return Ok(response);
+                    }
+                    if let Some(content_types) = &content_types {
+                        if !has_content_type(response.headers(), content_types) {
+                            debug!("Response’s content type isn’t allowed, not caching.");
+                            return Ok(response);
+                        }
+                    }
+                    if tee_streaming {
+                        return Ok(tee_into_cache(
+                            &cache,
+                            secondary,
+                            key,
+                            response,
+                            settings,
+                            request_headers,
+                        ));
+                    }
+                    if let Some(cooldown) = oversized_cooldown {
+                        let mut oversized = oversized.lock().unwrap();
+                        oversized.retain(|_, since| since.elapsed() < cooldown);
+                        if oversized.contains_key(&key) {
+                            debug!("Response known to be too big, not caching.");
+                            return Ok(response);
+                        }
+                    }
+                    let cooldown_key = oversized_cooldown.map(|_| key.clone());
+                    let transient_key = coalesce_only.then(|| key.clone());
+                    match update_cache(
+                        &cache,
+                        secondary.as_ref(),
+                        key,
+                        response,
+                        settings,
+                        &request_headers,
+                    )
+                    .await
+                    {
+                        Ok(value) => {
+                            if let Some(leader) = leader {
+                                leader.publish(&value);
+                            }
+                            if let Some(key) = transient_key {
+                                debug!("Response handed to the coalesced misses, evicting it.");
+                                cache.lock().unwrap().cache_remove(&key);
+                            }
+                            Ok(value.serve(&options))
+                        }
+                        Err(err) => {
+                            if let (StoreError::TooBig(_), Some(key)) = (&err, cooldown_key) {
+                                debug!("Response too big, not caching it during the cooldown.");
+                                oversized
+                                    .lock()
+                                    .unwrap()
+                                    .insert(key, std::time::Instant::now());
+                            }
+                            Ok(err.into_response())
+                        }
+                    }
+                }
+                Some(stale_value) if stale_fallback => {
+                    debug!("Returning stale value.");
+                    Ok(stale_value.serve_hit(&options, HitOutcome::Stale))
+                }
+                Some(_) => {
+                    debug!("Stale value in cache, evicting and returning failed response.");
+                    cache.lock().unwrap().cache_remove(&key);
+                    Ok(response)
+                }
+                None => match leader.filter(|_| coalesce_failure == CoalesceFailure::Share) {
+                    Some(leader) => Ok(share_failure(leader, response, limit).await),
+                    None => Ok(response),
+                },
+            }
+        };
+        if let Some(response) = background {
+            debug!("Returning stale value while revalidating it in the background.");
+            tokio::spawn(async move {
+                // consume the body, so that the streamed responses are stored too
+                let body = refresh.await.unwrap().into_body();
+                let _ = axum::body::to_bytes(body, usize::MAX).await;
+            });
+            return Ok(response);
+        }
+        refresh.await
+    }
+}
+
+/// The response headers signaling the authentication state, refused by [`CacheLayer::safe_shared`].
+const AUTH_RESPONSE_HEADERS: [HeaderName; 3] = [
+    header::WWW_AUTHENTICATE,
+    header::PROXY_AUTHENTICATE,
+    HeaderName::from_static("authentication-info"),
+];
+
+/// Find the header keeping the response out of the cache (see [`CacheLayer::refuse_set_cookie`],
+/// [`CacheLayer::refuse_headers`] and [`CacheLayer::strict_header_allowlist`]), checked for the
+/// fetched responses and the `304` responses revalidating the stored ones alike.
+fn refused_header<'a>(
+    headers: &'a HeaderMap,
+    refuse_set_cookie: bool,
+    refused_headers: &[HeaderName],
+    allowed_headers: Option<&[HeaderName]>,
+) -> Option<&'a HeaderName> {
+    headers.keys().find(|name| {
+        (refuse_set_cookie && *name == header::SET_COOKIE)
+            || refused_headers.contains(name)
+            || allowed_headers.is_some_and(|allowed| !allowed.contains(name))
+    })
+}
+
+/// Check whether the response’s media type matches one of the allowed ones.
+fn has_content_type(headers: &HeaderMap, allowed: &[String]) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    allowed
+        .iter()
+        .any(|allowed| match allowed.strip_suffix('*') {
+            Some(prefix) if prefix.is_empty() || prefix.ends_with('/') => {
+                media_type.starts_with(prefix)
+            }
+            _ => *allowed == media_type,
+        })
+}
+
+/// Replace the URI with the hash of it, see [`CacheLayer::hashed_keys`].
+fn hashed_uri(uri: &Uri) -> Uri {
+    let hash = blake3::hash(uri.to_string().as_bytes());
+    Uri::try_from(format!("/{}", &hash.to_hex()[..32])).unwrap()
+}
+
+/// Build the URI part of the caching key (`None` when the request’s URI is used as it is) and
+/// the TTL of the response from the request, or return `None` when the request lacks the keyed
+/// extension and has to bypass the cache.
+fn request_key(
+    request: &Request<Body>,
+    key_fn: Option<&KeyFn>,
+    key_extension: Option<ExtensionFn>,
+    options: KeyOptions<'_>,
+) -> Option<(Option<Uri>, Option<Duration>)> {
+    let KeyOptions {
+        by_matched_path: key_by_matched_path,
+        path_and_query_only: key_path_and_query_only,
+        uri_source,
+        vary_by_host,
+        trusted_headers,
+        body_keyed,
+        auth_mode,
+        hashed: hashed_keys,
+    } = options;
+    let extension = match key_extension {
+        Some(extension_hash) => Some(extension_hash(request.extensions())?),
+        None => None,
+    };
+    let (custom_uri, ttl) = match key_fn {
+        Some(key_fn) => {
+            let (uri, ttl) = key_fn(request);
+            (Some(uri), ttl)
+        }
+        None => (None, None),
+    };
+    let custom_uri = custom_uri.or_else(|| {
+        key_by_matched_path
+            .then(|| request.extensions().get::<MatchedPath>())
+            .flatten()
+            .and_then(|matched_path| Uri::try_from(matched_path.as_str()).ok())
+    });
+    let custom_uri = custom_uri.or_else(|| match uri_source {
+        UriSource::Original => Some(request.extensions().get::<OriginalUri>()?.0.clone()),
+        UriSource::Nested => None,
+    });
+    let custom_uri = match custom_uri.as_ref().unwrap_or(request.uri()) {
+        uri if key_path_and_query_only && uri.authority().is_some() => {
+            let path = uri.path_and_query().map_or("/", |path| path.as_str());
+            Some(Uri::try_from(path).ok()?)
+        }
+        _ => custom_uri,
+    };
+    let custom_uri = match extension {
+        Some(hash) => prefixed_uri(custom_uri.as_ref().unwrap_or(request.uri()), &hash),
+        None => custom_uri,
+    };
+    // the extension and trusted headers always prefix the key with their hashes, the credential
+    // and body only when keyed, with the absent ones standing in for the missing values
+    let custom_uri = match auth_mode {
+        AuthMode::PrivateByCredential => {
+            let hash = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .map(|credential| blake3::hash(credential.as_bytes()).to_hex());
+            let segment = hash.as_ref().map_or(ABSENT_KEY_SEGMENT, |hash| &hash[..32]);
+            prefixed_uri(custom_uri.as_ref().unwrap_or(request.uri()), segment)
+        }
+        _ => custom_uri,
+    };
+    let custom_uri = match trusted_headers {
+        [] => custom_uri,
+        names => {
+            let hash = trusted_headers_hash(request.headers(), names);
+            prefixed_uri(custom_uri.as_ref().unwrap_or(request.uri()), &hash)
+        }
+    };
+    let custom_uri = match request.extensions().get::<BodyDigest>() {
+        Some(BodyDigest(digest)) => {
+            prefixed_uri(custom_uri.as_ref().unwrap_or(request.uri()), digest)
+        }
+        None if body_keyed => prefixed_uri(
+            custom_uri.as_ref().unwrap_or(request.uri()),
+            ABSENT_KEY_SEGMENT,
+        ),
+        None => custom_uri,
+    };
+    let custom_uri = match vary_by_host {
+        true => Some(host_uri(
+            custom_uri.as_ref().unwrap_or(request.uri()),
+            request,
+        )?),
+        false => custom_uri,
+    };
+    let custom_uri = match custom_uri {
+        _ if !hashed_keys => custom_uri,
+        Some(uri) => Some(hashed_uri(&uri)),
+        None => Some(hashed_uri(request.uri())),
+    };
+    Some((custom_uri, ttl))
+}
+
+/// Hash the values of the trusted request headers, see [`CacheLayer::vary_by_trusted_headers`].
+/// The missing header hashes differently from the empty one.
+fn trusted_headers_hash(request_headers: &HeaderMap, names: &[HeaderName]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for name in names {
+        hasher.update(name.as_str().as_bytes());
+        // the header values can’t contain line breaks, so the separators are unambiguous
+        for value in request_headers.get_all(name) {
+            hasher.update(b"=");
+            hasher.update(value.as_bytes());
+        }
+        hasher.update(b"\n");
+    }
+    hasher.finalize().to_hex()[..32].to_owned()
+}
+
+/// The key segment standing for the absent hash (eg. of the request without a body). All the keys
+/// of the layer get one segment for each of the hashed parts, either this one or the 32
+/// hexadecimal digits of the hash, so a request path starting with a hash can’t impersonate the
+/// request keyed by it.
+const ABSENT_KEY_SEGMENT: &str = "-";
+
+/// Prefix the path of the URI with the hash, dropping its scheme and authority.
+fn prefixed_uri(uri: &Uri, hash: &str) -> Option<Uri> {
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    Uri::try_from(format!("/{hash}{path}")).ok()
+}
+
+/// Replace the authority of the URI with the normalized host of the request (or drop it when
+/// the request has no host), returning `None` when the `Host` header is malformed.
+fn host_uri(uri: &Uri, request: &Request<Body>) -> Option<Uri> {
+    let host = match request.headers().get(header::HOST) {
+        Some(host) => Some(Authority::try_from(host.as_bytes()).ok()?),
+        None => request.uri().authority().cloned(),
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let Some(host) = host else {
+        return Uri::try_from(path).ok();
+    };
+    let normalized = match host.port_u16() {
+        Some(80 | 443) | None => host.host().to_ascii_lowercase(),
+        Some(port) => format!("{}:{port}", host.host().to_ascii_lowercase()),
+    };
+    Uri::try_from(format!("http://{normalized}{path}")).ok()
+}
+
+/// Describe the request for [`CacheLayer::record_origin_request`].
+fn origin_request(request: &Request<Body>) -> Arc<str> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .map(|host| String::from_utf8_lossy(host.as_bytes()));
+    match host {
+        Some(host) => format!("{} {} (host: {host})", request.method(), request.uri()),
+        None => format!("{} {}", request.method(), request.uri()),
+    }
+    .into()
+}
+
+/// Hash the request extension of the given type, see [`CacheLayer::key_extension`].
+fn extension_hash<T: std::hash::Hash + Send + Sync + 'static>(
+    extensions: &axum::http::Extensions,
+) -> Option<String> {
+    /// The adapter feeding the `Hash` implementations into the BLAKE3 hasher.
+    struct Blake3(blake3::Hasher);
+
+    impl std::hash::Hasher for Blake3 {
+        fn write(&mut self, bytes: &[u8]) {
+            self.0.update(bytes);
+        }
+
+        fn finish(&self) -> u64 {
+            let hash = self.0.finalize();
+            u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+        }
+    }
+
+    let value = extensions.get::<T>()?;
+    let mut hasher = Blake3(blake3::Hasher::new());
+    value.hash(&mut hasher);
+    Some(hasher.0.finalize().to_hex()[..32].to_owned())
+}
+
+/// Check whether the request is a CORS preflight request.
+fn is_preflight(request: &Request<Body>) -> bool {
+    request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// The reasons of failing to store the response in the cache.
+#[derive(Debug)]
+enum StoreError {
+    /// The body exceeded the limit (in bytes).
+    TooBig(usize),
+    /// The body stream failed (eg. the connection to the upstream was dropped).
+    Body,
+    /// The body couldn’t be compressed.
+    Compression,
+    /// The response can’t be cached and is passed-through as it is.
+    #[cfg(feature = "json")]
+    Uncacheable(Box<Response>),
+}
+
+impl IntoResponse for StoreError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::TooBig(limit) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("File too big, over {limit} bytes"),
+            )
+                .into_response(),
+            Self::Body => {
+                (StatusCode::BAD_GATEWAY, "Failed to read the response body").into_response()
+            }
+            Self::Compression => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to compress the response",
+            )
+                .into_response(),
+            #[cfg(feature = "json")]
+            Self::Uncacheable(response) => *response,
+        }
+    }
+}
+
+/// The rules applied to the cached entries when storing new variants in them.
+#[derive(Clone, Debug, Default)]
+struct EntryRules {
+    consistent_content_type: bool,
+    max_variants: Option<usize>,
+    newest_wins: bool,
+    lock_warning: Option<Duration>,
+    cache_latency: Option<Arc<latency::Latencies>>,
+}
+
+/// The settings deciding how the responses are prepared for storing in the cache.
+#[derive(Clone, Debug)]
+struct StoreSettings {
+    limit: usize,
+    add_response_headers: bool,
+    dedup_link_headers: bool,
+    reuse_unchanged_bodies: bool,
+    compression: Option<Compression>,
+    // the request headers the key depends on, advertised with `Vary`
+    keyed_headers: Vec<HeaderName>,
+    ttl: Option<Duration>,
+    origin: Option<Arc<str>>,
+    requested: std::time::Instant,
+    // the hash, compression and body of the stale response being refreshed
+    previous_body: Option<(blake3::Hash, Option<Compression>, Bytes)>,
+    rules: EntryRules,
+    #[cfg(feature = "mmap")]
+    file_backed: Option<(Arc<Path>, usize)>,
+    #[cfg(feature = "digest")]
+    content_digest: bool,
+    #[cfg(feature = "json")]
+    json_normalization: Option<json::Normalization>,
+}
+
+#[instrument(skip(cache, secondary, response))]
+async fn update_cache<C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry>>(
+    cache: &Arc<Mutex<C>>,
+    secondary: Option<&tiered::Secondary>,
+    key: Key,
+    response: Response,
+    settings: StoreSettings,
+    request_headers: &HeaderMap,
+) -> Result<CachedResponse, StoreError> {
+    let limit = settings.limit;
+    let (parts, body) = response.into_parts();
+    let content_length = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    let body = buffer_body(body, limit, content_length).await?;
+    let rules = settings.rules.clone();
+    let value = prepare(parts, body, settings, request_headers)?;
+    store(cache, secondary, key, value.clone(), rules);
+    Ok(value)
+}
+
+/// Buffer the whole body, failing when it exceeds the limit or its stream fails. The single
+/// frame is used as it is, and when the `Content-Length` is known, the frames are collected into
+/// a buffer preallocated for it.
+async fn buffer_body(
+    mut body: Body,
+    limit: usize,
+    content_length: Option<usize>,
+) -> Result<Bytes, StoreError> {
+    let length = content_length
+        .filter(|length| *length <= limit)
+        .unwrap_or_default();
+    let mut first = Bytes::new();
+    let mut buffer: Option<Vec<u8>> = None;
+    let mut size = 0;
+    while let Some(frame) =
+        std::future::poll_fn(|cx| http_body::Body::poll_frame(Pin::new(&mut body), cx)).await
+    {
+        // the trailers aren’t cached
+        let Ok(data) = frame.map_err(|_| StoreError::Body)?.into_data() else {
+            continue;
+        };
+        size += data.len();
+        if size > limit {
+            return Err(StoreError::TooBig(limit));
+        }
+        match buffer.as_mut() {
+            Some(buffer) => buffer.extend_from_slice(&data),
+            None if first.is_empty() => first = data,
+            None => {
+                let mut preallocated = Vec::with_capacity(length.max(size));
+                preallocated.extend_from_slice(&first);
+                preallocated.extend_from_slice(&data);
+                buffer = Some(preallocated);
+            }
+        }
+    }
+    Ok(buffer.map_or(first, Bytes::from))
+}
+
+/// Buffer the failed response of the coalesced miss and hand it to the waiting misses too, see
+/// [`CoalesceFailure::Share`].
+async fn share_failure(leader: coalesce::Leader, response: Response, limit: usize) -> Response {
+    let (parts, body) = response.into_parts();
+    match buffer_body(body, limit, None).await {
+        Ok(body) => {
+            leader.publish(&CachedResponse::new(parts.clone(), body.clone()));
+            Response::from_parts(parts, Body::from(body))
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Forward the response to the client while buffering it, storing it in the cache only when
+/// the whole body was received without exceeding the limit.
+fn tee_into_cache<C>(
+    cache: &Arc<Mutex<C>>,
+    secondary: Option<tiered::Secondary>,
+    key: Key,
+    response: Response,
+    settings: StoreSettings,
+    request_headers: HeaderMap,
+) -> Response
+where
+    C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry> + Send + 'static,
+{
+    let (parts, body) = response.into_parts();
+    let stored_parts = parts.clone();
+    let cache = Arc::clone(cache);
+    let body = tee::TeeBody::new(
+        body,
+        settings.limit,
+        Box::new(move |body| {
+            let rules = settings.rules.clone();
+            if let Ok(value) = prepare(stored_parts, body, settings, &request_headers) {
+                store(&cache, secondary.as_ref(), key, value, rules);
+            }
+        }),
+    );
+    Response::from_parts(parts, Body::new(body))
+}
+
+/// Add the header with the method and URI of the key to the response.
+fn add_key_header(response: &mut Response, name: HeaderName, key: &Key) {
+    if let Ok(value) = HeaderValue::from_str(&format!("{} {}", key.0, key.1)) {
+        response.headers_mut().insert(name, value);
+    }
+}
+
+/// Replace the `Cache-Control` header of the successful or `304 NOT MODIFIED` response.
+fn set_client_cache_control(response: &mut Response, value: HeaderValue) {
+    let status = response.status();
+    if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+}
+
+/// Check whether the response has the `Vary: *` header, making it impossible to reuse.
+fn varies_on_everything(headers: &HeaderMap) -> bool {
+    vary_names(headers).any(|name| name == "*")
+}
+
+/// Iterate over the names of the headers listed in the `Vary` header.
+fn vary_names(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// Add the names missing from the `Vary` header to it, unless it varies on everything already.
+fn merge_vary(headers: &mut HeaderMap, names: &[HeaderName]) {
+    let missing: Vec<&str> = names
+        .iter()
+        .map(HeaderName::as_str)
+        .filter(|name| {
+            !vary_names(headers).any(|listed| listed == "*" || listed.eq_ignore_ascii_case(name))
+        })
+        .collect();
+    if !missing.is_empty() {
+        headers.append(
+            header::VARY,
+            HeaderValue::from_str(&missing.join(", ")).unwrap(),
+        );
+    }
+}
+
+/// Convert the response with the fully buffered body into the value stored in the cache.
+fn prepare(
+    mut parts: Parts,
+    mut body: Bytes,
+    settings: StoreSettings,
+    request_headers: &HeaderMap,
+) -> Result<CachedResponse, StoreError> {
+    if settings.dedup_link_headers {
+        dedup_header(&mut parts.headers, header::LINK);
+    }
+    #[cfg(feature = "json")]
+    if let Some(normalization) = &settings.json_normalization {
+        match normalization.apply(&parts.headers, &body) {
+            Ok(Some(normalized)) => {
+                body = normalized;
+                parts.headers.remove(header::CONTENT_LENGTH);
+            }
+            Ok(None) => {}
+            Err(()) if normalization.malformed == MalformedJson::Store => {
+                debug!("Malformed JSON response, caching it as it is.");
+            }
+            Err(()) => {
+                debug!("Malformed JSON response, not caching.");
+                let response = Response::from_parts(parts, Body::from(body));
+                return Err(StoreError::Uncacheable(Box::new(response)));
+            }
+        }
+    }
+    let mut vary_names: Vec<HeaderName> = vary_names(&parts.headers)
+        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        .collect();
+    // responses encoded by the wrapped service can only be reused for the same `Accept-Encoding`
+    // (and are stored as they are, never compressed again)
+    compression::remove_identity_encoding(&mut parts.headers);
+    let encoded = parts.headers.contains_key(header::CONTENT_ENCODING);
+    if encoded {
+        vary_names.push(header::ACCEPT_ENCODING);
+    }
+    vary_names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    vary_names.dedup();
+    let vary = vary_names
+        .into_iter()
+        .map(|name| {
+            let value = vary_value(request_headers, name.clone());
+            (name, value)
+        })
+        .collect();
+    let compression = settings.compression.filter(|_| !encoded);
+    #[cfg(feature = "digest")]
+    let identity_digest = settings.content_digest.then(|| digest::repr_digest(&body));
+    let body_hash = settings.reuse_unchanged_bodies.then(|| blake3::hash(&body));
+    // the unchanged body is reused as it was stored (compressed or backed by a file)
+    let reused = match settings.previous_body {
+        Some((hash, previous, body)) if Some(hash) == body_hash && previous == compression => {
+            debug!("Response’s body unchanged, reusing the stored one.");
+            Some(body)
+        }
+        _ => None,
+    };
+    if let Some(compression) = compression {
+        if reused.is_none() {
+            body = Bytes::from(
+                compression
+                    .compress(&body)
+                    .map_err(|_| StoreError::Compression)?,
+            );
+        }
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(compression.encoding()),
+        );
+    }
+    let mut keyed_headers = settings.keyed_headers;
+    if encoded || compression.is_some() {
+        keyed_headers.push(header::ACCEPT_ENCODING);
+    }
+    merge_vary(&mut parts.headers, &keyed_headers);
+    #[cfg(feature = "mmap")]
+    if let (Some((dir, threshold)), None) = (settings.file_backed.as_ref(), &reused) {
+        if body.len() > *threshold {
+            match mmap::file_backed(dir, &body) {
+                Ok(mapped) => body = mapped,
+                Err(err) => tracing::warn!(
+                    "Failed to store the body in a file, keeping it in memory: {err}"
+                ),
+            }
+        }
+    }
+    if let Some(reused) = reused {
+        body = reused;
+    }
+    #[cfg(feature = "digest")]
+    let identity_digest = match (identity_digest, compression) {
+        (Some(identity_digest), Some(_)) => {
+            parts
+                .headers
+                .insert(digest::REPR_DIGEST, digest::repr_digest(&body));
+            Some(identity_digest)
+        }
+        (Some(identity_digest), None) => {
+            parts.headers.insert(digest::REPR_DIGEST, identity_digest);
+            None
+        }
+        (None, _) => None,
+    };
+    Ok(CachedResponse {
+        age_header: settings.add_response_headers,
+        compression,
+        body_hash,
+        vary,
+        ttl: settings.ttl,
+        host: request_headers.get(header::HOST).cloned(),
+        origin: settings.origin,
+        requested: settings.requested,
+        #[cfg(feature = "digest")]
+        identity_digest,
+        ..CachedResponse::new(parts, body)
+    })
+}
+
+/// Replace the host of the absolute location pointing to the origin host with the given one,
+/// returning `None` for the relative and cross-origin locations.
+fn rewrite_location(
+    location: &HeaderValue,
+    origin: &HeaderValue,
+    host: &HeaderValue,
+) -> Option<HeaderValue> {
+    let location = location.to_str().ok()?;
+    let (scheme, rest) = location.split_once("://")?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    if origin == host || !authority.eq_ignore_ascii_case(origin.to_str().ok()?) {
+        return None;
+    }
+    HeaderValue::from_str(&format!("{scheme}://{}{path}", host.to_str().ok()?)).ok()
+}
+
+/// Store the variant of the response, keeping the other variants cached for the key.
+///
+/// The value has to be fully prepared (see [`prepare`]), as only merging it into the cached entry
+/// happens under the lock of the cache.
+fn store<C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry>>(
+    cache: &Mutex<C>,
+    secondary: Option<&tiered::Secondary>,
+    key: Key,
+    value: CachedResponse,
+    rules: EntryRules,
+) {
+    let mirrored_key = secondary.map(|_| key.clone());
+    let locking = rules
+        .cache_latency
+        .as_ref()
+        .map(|_| std::time::Instant::now());
+    let mut guard = cache.lock().unwrap();
+    let locked = std::time::Instant::now();
+    let mirrored = insert_variant(&mut *guard, key, value, &rules, secondary.is_some());
+    drop(guard);
+    if let (Some(latency), Some(locking)) = (&rules.cache_latency, locking) {
+        latency.store.record(locking.elapsed());
+    }
+    let held = locked.elapsed();
+    if rules.lock_warning.is_some_and(|threshold| held > threshold) {
+        tracing::warn!("Storing the response held the cache lock for {held:?}");
+    }
+    if let (Some(secondary), Some(key), Some(entry)) = (secondary, mirrored_key, mirrored) {
+        tiered::mirror(secondary, key, entry);
+    }
+}
+
+/// Merge the variant into the entry cached for the key, returning the copy of the updated entry
+/// when `mirrored`.
+fn insert_variant<C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry>>(
+    cache: &mut C,
+    key: Key,
+    value: CachedResponse,
+    rules: &EntryRules,
+    mirrored: bool,
+) -> Option<CachedEntry> {
+    let mut entry = match cache.cache_get_expired(&key) {
+        (Some(entry), false) => entry,
+        _ => CachedEntry::default(),
+    };
+    if rules.consistent_content_type && !entry.accepts_media_type(&value) {
+        debug!("Response’s media type differs from the cached one, not caching.");
+        return None;
+    }
+    if rules.newest_wins && entry.has_newer(&value) {
+        debug!("Response to a more recent request is already cached, not caching.");
+        return None;
+    }
+    entry.insert(value);
+    if let Some(max) = rules.max_variants {
+        // the variants are ordered from the least recently used
+        let excess = entry.variants.len().saturating_sub(max);
+        entry.variants.drain(..excess);
+    }
+    let copy = mirrored.then(|| entry.clone());
+    cache.cache_set(key, entry);
+    copy
+}
+
+/// Read the entry for the key from the cache: the expired entry still within its own TTL (or
+/// promoted to the extended TTL, see [`CacheLayer::promote_after`]) is reinserted, the one that
+/// outlived its own TTL is reported as expired, and the hits of the fresh one are counted.
+/// Returns the entry (if any) and whether it expired.
+fn read_entry<C: Cached<Key, CachedEntry> + CloneCached<Key, CachedEntry>>(
+    cache: &mut C,
+    key: &Key,
+    promote: Option<(u64, Duration)>,
+    count_hits: bool,
+) -> (Option<CachedEntry>, bool) {
+    let (cached, mut evicted) = cache.cache_get_expired(key);
+    match (cached.as_ref().and_then(CachedEntry::ttl_expired), evicted) {
+        (Some(false), true) => {
+            debug!("Expired value within its own TTL, reinserting");
+            cache.cache_set(key.clone(), cached.clone().unwrap());
+            evicted = false;
+        }
+        (Some(true), false) => evicted = true,
+        _ => {}
+    }
+    if let (Some(_), false, true) = (cached.as_ref(), evicted, count_hits) {
+        if let Some(entry) = cache.cache_get_mut(key) {
+            entry.hits += 1;
+        }
+    }
+    if let (Some(entry), true, Some(promote)) = (cached.as_ref(), evicted, promote) {
+        if entry.is_promoted(promote) {
+            debug!("Expired value promoted to extended TTL, reinserting");
+            cache.cache_set(key.clone(), entry.clone());
+            evicted = false;
+        }
+    }
+    (cached, evicted)
+}
+
+/// Check whether the request is conditional, ie. made by a client revalidating its own copy.
+fn is_conditional(request_headers: &HeaderMap) -> bool {
+    request_headers.contains_key(header::IF_NONE_MATCH)
+        || request_headers.contains_key(header::IF_MODIFIED_SINCE)
+}
+
+/// Add the validators of the stored response to the request, returning whether there were any.
+fn add_validators(request_headers: &mut HeaderMap, stored_headers: &HeaderMap) -> bool {
+    let mut added = false;
+    if let Some(etag) = stored_headers.get(header::ETAG) {
+        request_headers.insert(header::IF_NONE_MATCH, etag.clone());
+        added = true;
+    }
+    if let Some(last_modified) = stored_headers.get(header::LAST_MODIFIED) {
+        request_headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        added = true;
+    }
+    added
+}
+
+/// Update the stored headers with the ones from the `304 NOT MODIFIED` response, leaving out
+/// the headers describing the body, which is kept.
+fn merge_headers(stored_headers: &mut HeaderMap, updated_headers: &HeaderMap) {
+    for name in updated_headers.keys() {
+        if [
+            header::CONTENT_LENGTH,
+            header::CONTENT_ENCODING,
+            header::TRANSFER_ENCODING,
+        ]
+        .contains(name)
+        {
+            continue;
+        }
+        stored_headers.remove(name);
+        for value in updated_headers.get_all(name) {
+            stored_headers.append(name.clone(), value.clone());
         }
     }
 }
 
-#[derive(Clone)]
-pub struct CacheService<S, C> {
-    inner: S,
-    cache: Arc<Mutex<C>>,
-    use_stale: bool,
-    limit: usize,
-    allow_invalidation: bool,
-    add_response_headers: bool,
-}
+/// Compare the secrets in time independent of the position of the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Parse the `Retry-After` header (in seconds or as the HTTP date) into the duration to wait,
+/// `None` when it’s missing, malformed or already passed.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    let retry_after = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(std::time::SystemTime::now())
+            .ok()?,
+    };
+    Some(retry_after).filter(|retry_after| !retry_after.is_zero())
+}
+
+/// Compute the total size of the headers as the sum of the lengths of their names and values.
+fn headers_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum()
+}
+
+/// Sum the sizes of the bodies and headers of all the responses stored in the cache.
+fn stored_bytes<C: CacheKeys<Key, CachedEntry>>(cache: &C) -> usize {
+    cache
+        .cache_entries()
+        .into_iter()
+        .map(|(_, entry)| entry.size())
+        .sum()
+}
+
+/// Compute the value of the request header identifying the variant of the response.
+fn vary_value(request_headers: &HeaderMap, name: HeaderName) -> HeaderValue {
+    if name == header::ACCEPT_ENCODING {
+        compression::normalized_accept_encoding(request_headers)
+    } else {
+        joined_header(request_headers, name)
+    }
+}
+
+/// Join all the values of the given header into one, an empty value if the header is missing.
+fn joined_header(headers: &HeaderMap, name: HeaderName) -> HeaderValue {
+    let values: Vec<_> = headers
+        .get_all(name)
+        .iter()
+        .map(HeaderValue::as_bytes)
+        .collect();
+    HeaderValue::from_bytes(&values.join(&b", "[..])).unwrap_or(HeaderValue::from_static(""))
+}
+
+/// Remove exact duplicates of the given header, keeping the order of first occurrences.
+fn dedup_header(headers: &mut HeaderMap, name: HeaderName) {
+    let mut unique: Vec<HeaderValue> = Vec::new();
+    for value in headers.get_all(&name) {
+        if !unique.contains(value) {
+            unique.push(value.clone());
+        }
+    }
+    headers.remove(&name);
+    for value in unique {
+        headers.append(name.clone(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+
+    use axum::{
+        extract::{Path, State},
+        http::{Request, StatusCode},
+        response::AppendHeaders,
+        routing::get,
+        Router,
+    };
+    use tower::Service;
+
+    #[derive(Clone, Debug)]
+    struct Counter {
+        value: Arc<AtomicIsize>,
+    }
+
+    impl Counter {
+        fn new(init: isize) -> Self {
+            Self {
+                value: AtomicIsize::from(init).into(),
+            }
+        }
+
+        fn increment(&self) {
+            self.value.fetch_add(1, Ordering::Release);
+        }
+
+        fn read(&self) -> isize {
+            self.value.load(Ordering::Acquire)
+        }
+    }
+
+    #[tokio::test]
+    async fn should_use_cached_value() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).use_stale_on_failure();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..10 {
+            let status = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+
+        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+    }
+
+    #[tokio::test]
+    async fn should_not_cache_unsuccessful_responses() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            let responses = [
+                StatusCode::BAD_REQUEST,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::NOT_FOUND,
+            ];
+            let mut rng = rand::thread_rng();
+            responses[rng.gen_range(0..responses.len())]
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).use_stale_on_failure();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..10 {
+            let status = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(!status.is_success(), "handler should never return success");
+        }
+
+        assert_eq!(
+            10,
+            counter.read(),
+            "handler should’ve been called for all requests"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_use_last_correct_stale_value() {
+        let handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            let responses = [
+                StatusCode::BAD_REQUEST,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::NOT_FOUND,
+            ];
+            let mut rng = rand::thread_rng();
+
+            // first response successful, later failed
+            if prev == 0 {
+                StatusCode::OK
+            } else {
+                responses[rng.gen_range(0..responses.len())]
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).use_stale_on_failure();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter);
+
+        // feed the cache
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // wait over 1s for cache eviction
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+        for _ in 1..10 {
+            let status = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(
+                status.is_success(),
+                "cache should return stale successful value"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_use_stale_values_only_on_server_errors() {
+        let handler = |State(cnt): State<Counter>| async move {
+            match cnt.value.fetch_add(1, Ordering::AcqRel) {
+                0 => StatusCode::OK,
+                1 => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::NOT_FOUND,
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).stale_on_server_error();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter);
+
+        // feed the cache
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(
+            status.is_success(),
+            "server error should be masked with stale value"
+        );
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status,
+            "client error should be passed-through"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_use_stale_values() {
+        let handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            let responses = [
+                StatusCode::BAD_REQUEST,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::NOT_FOUND,
+            ];
+            let mut rng = rand::thread_rng();
+
+            // first response successful, later failed
+            if prev == 0 {
+                StatusCode::OK
+            } else {
+                responses[rng.gen_range(0..responses.len())]
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        // feed the cache
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // wait over 1s for cache eviction
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+        for _ in 1..10 {
+            let status = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(
+                !status.is_success(),
+                "cache should forward unsuccessful values"
+            );
+        }
+
+        assert_eq!(
+            10,
+            counter.read(),
+            "handler should’ve been called for all requests"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_invalidate_cache_when_disabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        // First request to cache the response
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // Second request should return the cached response - no increment
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // Third request with X-Invalidate-Cache header should not invalidate the cache - no increment
+        let status = router
+            .call(
+                Request::get("/")
+                    .header("X-Invalidate-Cache", "true")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // Fourth request should still return the cached response - no increment
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+    }
+
+    #[tokio::test]
+    async fn should_invalidate_cache_when_enabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).allow_invalidation();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        // First request to cache the response
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // Second request should return the cached response - no increment
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // Third request with X-Invalidate-Cache header to invalidate the cache
+        let status = router
+            .call(
+                Request::get("/")
+                    .header("X-Invalidate-Cache", "true")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // Fourth request to verify that the handler is called again
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        assert_eq!(2, counter.read(), "handler should’ve been called twice");
+    }
+
+    #[tokio::test]
+    async fn should_not_include_age_header_when_disabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        // First request to cache the response
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(
+            response.status().is_success(),
+            "handler should return success"
+        );
+
+        // Second request should return the cached response
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(
+            response.status().is_success(),
+            "handler should return success"
+        );
+        assert!(
+            response.headers().get("X-Cache-Age").is_none(),
+            "Age header should not be present"
+        );
+
+        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+    }
+
+    #[tokio::test]
+    async fn should_include_age_header_when_enabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).add_response_headers();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        // First request to cache the response
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(
+            response.status().is_success(),
+            "handler should return success"
+        );
+
+        // Age should be 0
+        assert_eq!(
+            response
+                .headers()
+                .get("X-Cache-Age")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or(""),
+            "0",
+            "Age header should be present and equal to 0"
+        );
+        // wait over 2s to age the cache
+        tokio::time::sleep(tokio::time::Duration::from_millis(2100)).await;
+        // Second request should return the cached response
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response
+                .headers()
+                .get("X-Cache-Age")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or(""),
+            "2",
+            "Age header should be present and equal to 2"
+        );
+
+        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+    }
+
+    #[tokio::test]
+    async fn should_preserve_link_headers() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            (
+                AppendHeaders([
+                    (header::LINK, "</style.css>; rel=preload; as=style"),
+                    (header::LINK, "</app.js>; rel=preload; as=script"),
+                ]),
+                StatusCode::OK,
+            )
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let links: Vec<_> = response.headers().get_all(header::LINK).iter().collect();
+            assert_eq!(
+                links,
+                [
+                    "</style.css>; rel=preload; as=style",
+                    "</app.js>; rel=preload; as=script"
+                ],
+                "Link headers should round-trip through the cache"
+            );
+        }
+
+        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+    }
+
+    #[tokio::test]
+    async fn should_dedup_link_headers_when_enabled() {
+        let handler = || async {
+            (
+                AppendHeaders([
+                    (header::LINK, "</style.css>; rel=preload; as=style"),
+                    (header::LINK, "</app.js>; rel=preload; as=script"),
+                    (header::LINK, "</style.css>; rel=preload; as=style"),
+                ]),
+                StatusCode::OK,
+            )
+        };
+
+        let cache = CacheLayer::with_lifespan(60).dedup_link_headers();
+        let mut router = Router::new().route("/", get(handler).layer(cache));
+
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let links: Vec<_> = response.headers().get_all(header::LINK).iter().collect();
+        assert_eq!(
+            links,
+            [
+                "</style.css>; rel=preload; as=style",
+                "</app.js>; rel=preload; as=script"
+            ],
+            "duplicated Link headers should be removed"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_bypass_cache_when_async_guard_passes() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).async_skip_if(|request: &Request<Body>| {
+            let skip = request.uri().path() == "/skipped";
+            async move { skip }
+        });
+        let mut router = Router::new()
+            .route("/", get(handler))
+            .route("/skipped", get(handler))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        for _ in 0..5 {
+            for path in ["/", "/skipped"] {
+                let status = router
+                    .call(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success(), "handler should return success");
+            }
+        }
+
+        assert_eq!(
+            6,
+            counter.read(),
+            "handler should’ve been called once for cached and for all skipped requests"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_apply_failure_policy_when_async_guard_times_out() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        for (on_failure, expected_calls) in [(GuardFailure::Bypass, 3), (GuardFailure::Cache, 1)] {
+            let counter = Counter::new(0);
+            let cache = CacheLayer::with_lifespan(60)
+                .async_skip_if(|_: &Request<Body>| async {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+                    false
+                })
+                .async_skip_timeout(Duration::from_millis(10))
+                .async_skip_on_failure(on_failure);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+
+            for _ in 0..3 {
+                let status = router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success(), "handler should return success");
+            }
+
+            assert_eq!(
+                expected_calls,
+                counter.read(),
+                "handler calls should follow the {on_failure:?} policy"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_provide_cache_info_to_handler() {
+        let handler = |info: CacheInfo| async move {
+            format!(
+                "{:?} {} {}",
+                info.status(),
+                info.uri(),
+                info.age().is_some()
+            )
+        };
+
+        let cache = CacheLayer::with_lifespan(1);
+        let mut router = Router::new().route("/", get(handler).options(handler).layer(cache));
+
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            &body[..],
+            b"Miss / false",
+            "handler should see a cache miss"
+        );
+
+        let preflight = Request::options("/")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.call(preflight).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            &body[..],
+            b"Bypass / false",
+            "handler should see the bypassed preflight"
+        );
+
+        // wait over 1s for cache eviction
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            &body[..],
+            b"Stale / true",
+            "handler should see a stale entry with its age"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_serve_compressed_body_to_capable_clients() {
+        let codecs = [
+            Compression::Gzip,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "brotli")]
+            Compression::Brotli,
+        ];
+
+        for codec in codecs {
+            let cache = CacheLayer::with_lifespan(60).compression(codec);
+            let mut router =
+                Router::new().route("/", get(|| async { "Hello, world!" }).layer(cache));
+
+            let response = router
+                .call(
+                    Request::get("/")
+                        .header(
+                            header::ACCEPT_ENCODING,
+                            format!("{}, identity", codec.encoding()),
+                        )
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                response.headers().get(header::CONTENT_ENCODING).unwrap(),
+                codec.encoding(),
+                "capable client should receive {codec:?} body"
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(codec.decompress(&body).unwrap(), b"Hello, world!");
+
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert!(
+                response.headers().get(header::CONTENT_ENCODING).is_none(),
+                "other clients should receive a plain body"
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], b"Hello, world!");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_invalidate_on_broadcast_messages() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let (sender, receiver) = broadcast::channel(16);
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).invalidate_on(receiver);
+        let mut router = Router::new()
+            .route("/users/:id", get(handler))
+            .route("/posts/:id", get(handler))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        let paths = ["/users/1", "/users/2", "/posts/1"];
+        for path in paths {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(3, counter.read(), "all responses should’ve been cached");
+
+        sender
+            .send(InvalidationMsg::Prefix(String::from("/users/")))
+            .unwrap();
+        sender
+            .send(InvalidationMsg::Key(
+                axum::http::Method::GET,
+                axum::http::Uri::from_static("/posts/1"),
+            ))
+            .unwrap();
+        // let the listening task process the messages
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+        for path in paths {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            6,
+            counter.read(),
+            "all responses should’ve been invalidated"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_cache_responses_with_oversized_headers() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            ([(header::SET_COOKIE, "a".repeat(100))], StatusCode::OK)
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).max_header_bytes(64);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..3 {
+            let status = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+
+        assert_eq!(
+            3,
+            counter.read(),
+            "handler should’ve been called for all requests"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_reinsert_stale_value_when_disabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            if prev == 0 {
+                StatusCode::OK
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1)
+            .use_stale_on_failure()
+            .skip_stale_reinsert();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        // feed the cache
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        // wait over 1s for cache eviction
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(
+            status.is_success(),
+            "request finding the stale value should receive it"
+        );
+
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(
+            !status.is_success(),
+            "stale value shouldn’t have been kept in the cache"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_key_encoded_responses_by_accept_encoding() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            match headers.get(header::ACCEPT_ENCODING) {
+                Some(_) => ([(header::CONTENT_ENCODING, "gzip")], "gzipped").into_response(),
+                None => "plain".into_response(),
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let gzip_request = || {
+            Request::get("/")
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = router.call(gzip_request()).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let response = router.call(gzip_request()).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(1, counter.read(), "encoded response should be reused");
+
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(
+            response.headers().get(header::CONTENT_ENCODING).is_none(),
+            "encoded response should never be served to a client not accepting it"
+        );
+        assert_eq!(2, counter.read(), "handler should’ve been called again");
+    }
+
+    #[tokio::test]
+    async fn should_compress_only_unencoded_responses() {
+        let handler = |State(cnt): State<Counter>, Path(coding): Path<String>| async move {
+            cnt.increment();
+            ([(header::CONTENT_ENCODING, coding)], "body").into_response()
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).compression(Compression::Gzip);
+        let mut router = Router::new()
+            .route("/:coding", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let request = |uri: &str, accept_encoding: &'static str| {
+            Request::get(uri)
+                .header(header::ACCEPT_ENCODING, accept_encoding)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for accept_encoding in ["gzip", "gzip", "br"] {
+            let response = router
+                .call(request("/identity", accept_encoding))
+                .await
+                .unwrap();
+            let expected = (accept_encoding == "gzip").then_some("gzip");
+            assert_eq!(
+                expected,
+                response
+                    .headers()
+                    .get(header::CONTENT_ENCODING)
+                    .map(|value| value.to_str().unwrap()),
+                "identity-encoded response should be compressed like the unencoded one"
+            );
+        }
+        assert_eq!(
+            1,
+            counter.read(),
+            "response should be shared by all encodings"
+        );
+
+        for _ in 0..2 {
+            let response = router.call(request("/br", "br")).await.unwrap();
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], b"body", "encoded body should be stored as it is");
+        }
+        assert_eq!(2, counter.read(), "encoded response should be reused");
+    }
+
+    #[tokio::test]
+    async fn should_compute_value_only_once() {
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let key = || (axum::http::Method::GET, axum::http::Uri::from_static("/"));
+
+        for _ in 0..3 {
+            let value = cache
+                .get_or_compute(key(), || async {
+                    counter.increment();
+                    let (parts, _) = Response::new(()).into_parts();
+                    CachedResponse::new(parts, Bytes::from("computed"))
+                })
+                .await;
+            let body = axum::body::to_bytes(value.into_response().into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], b"computed");
+        }
+
+        assert_eq!(1, counter.read(), "value should’ve been computed only once");
+
+        // the layer should serve the value computed manually
+        let mut router = Router::new().route("/", get(|| async { "handler" }).layer(cache));
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"computed");
+    }
+
+    #[tokio::test]
+    async fn should_cache_multiple_variants_by_vary() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            let language = headers
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("en")
+                .to_owned();
+            ([(header::VARY, "accept-language")], language)
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..3 {
+            for language in ["en", "pl", "de"] {
+                let response = router
+                    .call(
+                        Request::get("/")
+                            .header(header::ACCEPT_LANGUAGE, language)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    &body[..],
+                    language.as_bytes(),
+                    "should serve matching variant"
+                );
+            }
+        }
+
+        assert_eq!(
+            3,
+            counter.read(),
+            "handler should’ve been called once per variant"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_cache_responses_varying_on_everything() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            ([(header::VARY, "*")], StatusCode::OK)
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..3 {
+            router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            3,
+            counter.read(),
+            "handler should’ve been called for all requests"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_refresh_date_header_when_enabled() {
+        let handler = || async {
+            (
+                [(header::DATE, "Mon, 01 Jan 2001 00:00:00 GMT")],
+                StatusCode::OK,
+            )
+        };
+
+        let cache = CacheLayer::with_lifespan(60).refresh_date_header();
+        let mut router = Router::new().route("/", get(handler).layer(cache));
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let date = response.headers()[header::DATE].to_str().unwrap();
+            let date = httpdate::parse_http_date(date).unwrap();
+            assert!(
+                date.elapsed().unwrap() < Duration::from_secs(5),
+                "Date header should be current"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_share_variant_for_equivalent_accept_encoding() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            ([(header::CONTENT_ENCODING, "gzip")], "gzipped")
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for accept_encoding in [
+            "gzip, deflate, br",
+            "br, gzip, deflate",
+            "gzip;q=1.0, br;q=0.8, deflate, unknown",
+            "x-gzip, br, deflate, zstd;q=0",
+        ] {
+            router
+                .call(
+                    Request::get("/")
+                        .header(header::ACCEPT_ENCODING, accept_encoding)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            1,
+            counter.read(),
+            "equivalent Accept-Encoding headers should share the variant"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_force_store_for_trusted_callers() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            assert!(
+                headers.get("x-cache-force-store").is_none(),
+                "force store header should be stripped"
+            );
+            StatusCode::NOT_FOUND
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).force_store_header(
+            HeaderName::from_static("x-cache-force-store"),
+            HeaderValue::from_static("s3cr3t"),
+        );
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let forced_request = |secret| {
+            Request::get("/")
+                .header("x-cache-force-store", secret)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        router.call(forced_request("wrong")).await.unwrap();
+        router.call(forced_request("wrong")).await.unwrap();
+        assert_eq!(2, counter.read(), "wrong secret shouldn’t force storing");
+
+        router.call(forced_request("s3cr3t")).await.unwrap();
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert_eq!(3, counter.read(), "forced response should’ve been cached");
+    }
+
+    #[tokio::test]
+    async fn should_cache_cors_preflight_by_request_headers() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::NO_CONTENT
+        };
+        let preflight = |method| {
+            Request::options("/")
+                .header(header::ORIGIN, "https://example.com")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for (cache, expected_calls) in [
+            (CacheLayer::with_lifespan(60), 6),
+            (CacheLayer::with_lifespan(60).cache_cors_preflight(), 2),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route("/", axum::routing::options(handler).layer(cache))
+                .with_state(counter.clone());
+
+            for _ in 0..3 {
+                for method in ["PUT", "DELETE"] {
+                    let status = router.call(preflight(method)).await.unwrap().status();
+                    assert!(status.is_success(), "handler should return success");
+                }
+            }
+
+            assert_eq!(expected_calls, counter.read());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_dump_cache_contents() {
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(|| async { "root" }))
+            .route("/hello", get(|| async { "Hello, world!" }))
+            .layer(cache.clone());
+
+        for path in ["/", "/hello"] {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let mut dump = cache.dump();
+        dump.sort_by_key(|(key, _)| key.1.to_string());
+        assert_eq!(2, dump.len());
+        assert_eq!("/hello", dump[1].0 .1);
+        assert_eq!(StatusCode::OK, dump[1].1.status);
+        assert_eq!(13, dump[1].1.body_size);
+        assert!(dump[1].1.body.is_none(), "dump shouldn’t include bodies");
+
+        let full = cache.dump_full();
+        assert!(
+            full.iter().all(|(_, info)| info.body.is_some()),
+            "full dump should include bodies"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_stream_oversized_tee_responses_without_caching() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            let chunks = ["0123456789", "0123456789", "0123456789"];
+            Body::from_stream(futures_util::stream::iter(
+                chunks.map(Ok::<_, std::io::Error>),
+            ))
+        };
+
+        for (limit, expected_calls) in [(16, 3), (64, 1)] {
+            let counter = Counter::new(0);
+            let cache = CacheLayer::with_lifespan(60)
+                .tee_streaming()
+                .body_limit(limit);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+
+            for _ in 0..3 {
+                let response = router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert!(response.status().is_success());
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                assert_eq!(30, body.len(), "client should receive the complete body");
+            }
+
+            assert_eq!(
+                expected_calls,
+                counter.read(),
+                "only bodies within the limit of {limit} bytes should be cached"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_pass_through_oversized_responses_during_cooldown() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            "a response that is well beyond the limit of the cache!"
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .body_limit(16)
+            .oversized_cooldown(Duration::from_secs(60));
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let status = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+
+        for _ in 0..3 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(
+                &body[..],
+                b"a response that is well beyond the limit of the cache!",
+                "known oversized response should be passed through"
+            );
+        }
+
+        assert_eq!(4, counter.read());
+    }
+
+    #[tokio::test]
+    async fn should_extend_ttl_of_popular_entries() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).promote_after(2, Duration::from_secs(60));
+        let mut router = Router::new()
+            .route("/hot", get(handler))
+            .route("/cold", get(handler))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        for path in ["/hot", "/hot", "/hot", "/cold"] {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(2, counter.read());
+
+        // wait over 1s for cache eviction
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+        for path in ["/hot", "/cold"] {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            3,
+            counter.read(),
+            "only the cold entry should’ve been refreshed"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_flush_matching_entries() {
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(|| async { "root" }))
+            .route(
+                "/cookie",
+                get(|| async { ([(header::SET_COOKIE, "session=1")], "cookie") }),
+            )
+            .layer(cache.clone());
+
+        for path in ["/", "/cookie"] {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let flushed = cache.flush_where(|_, info| info.headers.contains_key(header::SET_COOKIE));
+        assert_eq!(1, flushed);
+        let dump = cache.dump();
+        assert_eq!(1, dump.len());
+        assert_eq!("/", dump[0].0 .1);
+    }
+
+    #[tokio::test]
+    async fn should_key_by_matched_path_when_enabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            "shell"
+        };
+
+        for (cache, expected_calls) in [
+            (CacheLayer::with_lifespan(60), 3),
+            (CacheLayer::with_lifespan(60).key_by_matched_path(), 1),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route("/users/:id", get(handler))
+                .layer(cache)
+                .with_state(counter.clone());
+
+            for path in ["/users/1", "/users/2", "/users/3?tab=posts"] {
+                let status = router
+                    .call(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success(), "handler should return success");
+            }
+
+            assert_eq!(expected_calls, counter.read());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_merge_headers_on_revalidation() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            if prev == 0 {
+                (
+                    [(header::ETAG, "\"v1\""), (header::EXPIRES, "first")],
+                    "body",
+                )
+                    .into_response()
+            } else {
+                assert_eq!(headers[header::IF_NONE_MATCH], "\"v1\"");
+                (
+                    StatusCode::NOT_MODIFIED,
+                    [(header::ETAG, "\"v1\""), (header::EXPIRES, "second")],
+                )
+                    .into_response()
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).revalidate_stale();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        // wait over 1s for cache eviction
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            assert_eq!(
+                response.headers()[header::EXPIRES],
+                "second",
+                "headers should be updated from the 304 response"
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], b"body", "stored body should be preserved");
+        }
+
+        assert_eq!(2, counter.read(), "revalidated entry should be fresh again");
+    }
+
+    #[tokio::test]
+    async fn should_use_boxed_layers() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let layers: Vec<(&str, BoxCacheLayer<axum::routing::Route>)> = vec![
+            ("/timed", CacheLayer::with_lifespan(60).boxed()),
+            (
+                "/sized",
+                CacheLayer::with(cached::TimedSizedCache::with_size_and_lifespan(10, 60)).boxed(),
+            ),
+        ];
+        let mut router = Router::new();
+        for (path, layer) in layers {
+            router = router.route(path, get(handler).layer(layer));
+        }
+        let mut router = router.with_state(counter.clone());
+
+        for _ in 0..3 {
+            for path in ["/timed", "/sized"] {
+                let status = router
+                    .call(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success(), "handler should return success");
+            }
+        }
+
+        assert_eq!(
+            2,
+            counter.read(),
+            "handler should’ve been called once per route"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_apply_safe_shared_rules() {
+        let handler = |State(cnt): State<Counter>, uri: Uri| async move {
+            cnt.increment();
+            let headers: &[_] = match uri.path() {
+                "/login" => &[(header::SET_COOKIE, "session=abc")],
+                "/challenge" => &[(header::WWW_AUTHENTICATE, "Basic")],
+                _ => &[],
+            };
+            (AppendHeaders(headers.to_vec()), "response")
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).safe_shared();
+        let mut router = Router::new()
+            .route("/login", get(handler).layer(cache.clone()))
+            .route("/challenge", get(handler).layer(cache.clone()))
+            .route("/public", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            for path in ["/login", "/challenge"] {
+                let status = router
+                    .call(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success(), "handler should return success");
+            }
+        }
+        assert_eq!(
+            4,
+            counter.read(),
+            "responses with auth state shouldn’t be cached"
+        );
+
+        for credentials in [
+            (header::AUTHORIZATION, "Bearer token"),
+            (header::COOKIE, "session=abc"),
+        ] {
+            let status = router
+                .call(
+                    Request::get("/public")
+                        .header(credentials.0, credentials.1)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+        assert_eq!(
+            6,
+            counter.read(),
+            "requests with credentials should bypass the cache"
+        );
+
+        for _ in 0..2 {
+            let status = router
+                .call(Request::get("/public").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+        assert_eq!(7, counter.read(), "anonymous requests should be cached");
+    }
+
+    #[tokio::test]
+    async fn should_honor_stale_directives() {
+        let handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            (
+                [(header::CACHE_CONTROL, "stale-while-revalidate=60")],
+                prev.to_string(),
+            )
+        };
+        let sie_handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            let status = if prev > 0 {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            };
+            (status, [(header::CACHE_CONTROL, "stale-if-error=60")])
+        };
+
+        let counter = Counter::new(0);
+        let errors = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).honor_stale_directives();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone())
+            .merge(
+                Router::new()
+                    .route("/unstable", get(sie_handler).layer(cache))
+                    .with_state(errors.clone()),
+            );
+
+        for path in ["/", "/unstable"] {
+            let status = router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            "0", body,
+            "stale value should be returned while revalidating"
+        );
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        assert_eq!(
+            2,
+            counter.read(),
+            "stale value should be refreshed in the background"
+        );
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!("1", body, "refreshed value should be returned");
+
+        let status = router
+            .call(Request::get("/unstable").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(
+            status.is_success(),
+            "server error should be masked with stale value"
+        );
+        assert_eq!(2, errors.read(), "handler should’ve been called");
+    }
+
+    #[tokio::test]
+    async fn should_cache_only_allowed_content_types() {
+        let handler = |State(cnt): State<Counter>, uri: Uri| async move {
+            cnt.increment();
+            let content_type = match uri.path() {
+                "/json" => "application/json; charset=utf-8",
+                "/image" => "image/png",
+                _ => "text/html",
+            };
+            [(header::CONTENT_TYPE, content_type)]
+        };
+
+        let counter = Counter::new(0);
+        let cache =
+            CacheLayer::with_lifespan(60).cacheable_content_types(&["application/json", "image/*"]);
+        let mut router = Router::new()
+            .route("/json", get(handler).layer(cache.clone()))
+            .route("/image", get(handler).layer(cache.clone()))
+            .route("/html", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            for path in ["/json", "/image", "/html"] {
+                let status = router
+                    .call(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success(), "handler should return success");
+            }
+        }
+
+        assert_eq!(
+            4,
+            counter.read(),
+            "only the HTML response should’ve been fetched twice"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_clone_inner_service_on_hits() {
+        #[derive(Debug)]
+        struct Handler {
+            clones: Counter,
+        }
+
+        impl Clone for Handler {
+            fn clone(&self) -> Self {
+                self.clones.increment();
+                Self {
+                    clones: self.clones.clone(),
+                }
+            }
+        }
+
+        impl Service<Request<Body>> for Handler {
+            type Response = Response;
+            type Error = Infallible;
+            type Future = std::future::Ready<Result<Response, Infallible>>;
+
+            fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, _: Request<Body>) -> Self::Future {
+                std::future::ready(Ok(StatusCode::OK.into_response()))
+            }
+        }
+
+        let clones = Counter::new(0);
+        let mut service = CacheLayer::with_lifespan(60).layer(Handler {
+            clones: clones.clone(),
+        });
+
+        // feed the cache
+        let status = service
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+        let misses = clones.read();
+
+        for _ in 0..10 {
+            let status = service
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+        assert_eq!(
+            misses,
+            clones.read(),
+            "hits shouldn’t clone the inner service"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_serve_byte_ranges() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            ([(header::ETAG, "\"v1\"")], "0123456789")
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).serve_ranges();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let cases = [
+            (None, None, StatusCode::OK, None, "0123456789"),
+            (
+                Some("bytes=2-4"),
+                None,
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 2-4/10"),
+                "234",
+            ),
+            (
+                Some("bytes=7-"),
+                None,
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 7-9/10"),
+                "789",
+            ),
+            (
+                Some("bytes=-3"),
+                None,
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 7-9/10"),
+                "789",
+            ),
+            (
+                Some("bytes=0-1,4-5"),
+                None,
+                StatusCode::OK,
+                None,
+                "0123456789",
+            ),
+            (
+                Some("bytes=2-4"),
+                Some("\"v1\""),
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 2-4/10"),
+                "234",
+            ),
+            (
+                Some("bytes=2-4"),
+                Some("\"v0\""),
+                StatusCode::OK,
+                None,
+                "0123456789",
+            ),
+            (
+                Some("bytes=20-"),
+                None,
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                Some("bytes */10"),
+                "",
+            ),
+        ];
+        for (range, if_range, status, content_range, body) in cases {
+            let mut request = Request::get("/");
+            if let Some(range) = range {
+                request = request.header(header::RANGE, range);
+            }
+            if let Some(if_range) = if_range {
+                request = request.header(header::IF_RANGE, if_range);
+            }
+            let response = router
+                .call(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(status, response.status(), "unexpected status for {range:?}");
+            assert_eq!(
+                content_range,
+                response
+                    .headers()
+                    .get(header::CONTENT_RANGE)
+                    .map(|value| value.to_str().unwrap()),
+                "unexpected Content-Range for {range:?}"
+            );
+            if status != StatusCode::RANGE_NOT_SATISFIABLE {
+                assert_eq!(
+                    "bytes",
+                    response.headers()[header::ACCEPT_RANGES],
+                    "ranges should be advertised"
+                );
+            }
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, bytes, "unexpected body for {range:?}");
+        }
+
+        assert_eq!(1, counter.read(), "whole body should’ve been cached once");
+    }
+
+    #[tokio::test]
+    async fn should_use_custom_key_and_ttl() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).key_and_ttl_fn(|request| {
+            let width = request
+                .uri()
+                .query()
+                .and_then(|query| query.split('&').find(|param| param.starts_with("w=")))
+                .unwrap_or_default();
+            let ttl = match width {
+                "w=short" => Duration::from_millis(200),
+                _ => Duration::from_secs(3),
+            };
+            (format!("/?{width}").parse().unwrap(), Some(ttl))
+        });
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let mut call = |uri: &'static str| {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = router.call(request);
+            async move { assert!(response.await.unwrap().status().is_success()) }
+        };
+
+        call("/?w=short&x=1").await;
+        call("/?w=short&x=2").await;
+        call("/?w=long&x=1").await;
+        call("/?x=2&w=long").await;
+        assert_eq!(
+            2,
+            counter.read(),
+            "ignored query params shouldn’t be part of the key"
+        );
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        call("/?w=short").await;
+        assert_eq!(3, counter.read(), "short TTL should’ve expired");
+
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+        call("/?w=long").await;
+        assert_eq!(
+            3,
+            counter.read(),
+            "long TTL should outlive the cache’s lifespan"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_evict_cheapest_responses() {
+        let handler = |State(cnt): State<Counter>, uri: Uri| async move {
+            cnt.increment();
+            "x".repeat(uri.path()[1..].parse().unwrap())
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::cost_weighted(2, 60, |_, size| size as u64);
+        let mut router = Router::new()
+            .route("/:size", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for path in ["/1000", "/10", "/500", "/1000", "/500", "/10"] {
+            let status = router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+
+        assert_eq!(
+            4,
+            counter.read(),
+            "the smallest response should’ve been evicted to store the bigger one"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_read_and_write_through_secondary_cache() {
+        #[derive(Clone, Default)]
+        struct MemoryStore {
+            entries: Arc<tokio::sync::Mutex<HashMap<Key, CachedEntry>>>,
+        }
+
+        #[axum::async_trait]
+        impl SecondaryCache for MemoryStore {
+            async fn get(&self, key: &Key) -> Option<CachedEntry> {
+                self.entries.lock().await.get(key).cloned()
+            }
+
+            async fn set(&self, key: Key, entry: CachedEntry) {
+                self.entries.lock().await.insert(key, entry);
+            }
+        }
+
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let secondary = MemoryStore::default();
+        // two instances sharing the secondary cache
+        let mut routers = [0, 1].map(|_| {
+            let cache = CacheLayer::with_tiers(TimedCache::with_lifespan(60), secondary.clone());
+            Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone())
+        });
+
+        let status = routers[0]
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+        // let the write through finish
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            1,
+            secondary.entries.lock().await.len(),
+            "entry should be mirrored"
+        );
+
+        for _ in 0..2 {
+            let status = routers[1]
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+        secondary.entries.lock().await.clear();
+        let status = routers[1]
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        assert_eq!(
+            1,
+            counter.read(),
+            "second instance should use the secondary cache"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_use_hashed_keys() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).hashed_keys();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone());
+
+        let long_query = format!("/?q={}", "x".repeat(1000));
+        for uri in [long_query.as_str(), long_query.as_str(), "/?q=y", "/?q=y"] {
+            let status = router
+                .call(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+
+        assert_eq!(
+            2,
+            counter.read(),
+            "handler should’ve been called once per URI"
+        );
+        let dump = cache.dump();
+        assert_eq!(2, dump.len(), "both responses should be cached");
+        for (key, _) in dump {
+            assert_eq!(33, key.1.to_string().len(), "key should have fixed size");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_reinsert_stale_value_above_memory_limit() {
+        let handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            if prev == 0 {
+                (StatusCode::OK, "Hello, world!")
+            } else {
+                (StatusCode::INTERNAL_SERVER_ERROR, "")
+            }
+        };
+
+        for (limit, kept) in [(usize::MAX, true), (10, false)] {
+            let cache = CacheLayer::with_lifespan(1)
+                .use_stale_on_failure()
+                .skip_stale_reinsert_above(limit);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache.clone()))
+                .with_state(Counter::new(0));
+
+            // feed the cache
+            let status = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+            assert!(
+                cache.memory_usage() > 10,
+                "cached response should use memory"
+            );
+
+            // wait over 1s for cache eviction
+            tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+            for _ in 0..2 {
+                let status = router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success() || !kept, "stale value should be served");
+            }
+            assert_eq!(
+                kept,
+                cache.memory_usage() > 0,
+                "stale value should be reinserted only below the limit"
+            );
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn should_count_mock_service_calls() {
+        let inner = testing::MockService::with_statuses([
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::OK,
+        ]);
+        let mut service = CacheLayer::with_lifespan(60).layer(inner.clone());
+
+        for expected in [false, true, true, true] {
+            let status = service
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert_eq!(expected, status.is_success());
+        }
+
+        assert_eq!(2, inner.calls(), "mock should’ve been called until success");
+    }
+
+    #[tokio::test]
+    async fn should_rewrite_location_host() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            let host = headers[header::HOST].to_str().unwrap().to_owned();
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                [(header::LOCATION, format!("https://{host}/new?page=1"))],
+            )
+        };
+        let external = || async {
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                [(header::LOCATION, "https://other.example/new")],
+            )
+        };
+
+        let counter = Counter::new(0);
+        // the redirects are stored by the warming requests forcing it
+        let cache = CacheLayer::with_lifespan(60)
+            .force_store_header(
+                HeaderName::from_static("x-force-store"),
+                HeaderValue::from_static("secret"),
+            )
+            .rewrite_location_host();
+        let mut router = Router::new()
+            .route("/old", get(handler))
+            .route("/external", get(external))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        for (path, host, location) in [
+            ("/old", "a.example", "https://a.example/new?page=1"),
+            (
+                "/old",
+                "b.example:8080",
+                "https://b.example:8080/new?page=1",
+            ),
+            ("/old", "a.example", "https://a.example/new?page=1"),
+            ("/external", "a.example", "https://other.example/new"),
+            ("/external", "b.example", "https://other.example/new"),
+        ] {
+            let request = Request::get(path)
+                .header(header::HOST, host)
+                .header("x-force-store", "secret")
+                .body(Body::empty())
+                .unwrap();
+            let response = router.call(request).await.unwrap();
+            assert_eq!(StatusCode::MOVED_PERMANENTLY, response.status());
+            assert_eq!(location, response.headers()[header::LOCATION]);
+        }
+
+        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+    }
+
+    #[tokio::test]
+    async fn should_apply_secondary_backpressure() {
+        use tower::ServiceExt;
+
+        #[derive(Clone, Default)]
+        struct BoundedStore {
+            full: Arc<std::sync::atomic::AtomicBool>,
+            waker: Arc<Mutex<Option<std::task::Waker>>>,
+        }
+
+        #[axum::async_trait]
+        impl SecondaryCache for BoundedStore {
+            async fn get(&self, _: &Key) -> Option<CachedEntry> {
+                None
+            }
+
+            async fn set(&self, _: Key, _: CachedEntry) {}
+
+            fn poll_write_capacity(&self, cx: &mut Context<'_>) -> Poll<()> {
+                if self.full.load(Ordering::Acquire) {
+                    *self.waker.lock().unwrap() = Some(cx.waker().clone());
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            }
+        }
+
+        let inner = tower::service_fn(|_: Request<Body>| async {
+            Ok::<_, Infallible>(StatusCode::OK.into_response())
+        });
+        let store = BoundedStore::default();
+        let mut services = [false, true].map(|backpressure| {
+            let cache = CacheLayer::with_tiers(TimedCache::with_lifespan(60), store.clone());
+            let cache = if backpressure {
+                cache.secondary_backpressure()
+            } else {
+                cache
+            };
+            cache.layer(inner)
+        });
+
+        store.full.store(true, Ordering::Release);
+        let timeout = Duration::from_millis(50);
+        assert!(
+            tokio::time::timeout(timeout, services[0].ready())
+                .await
+                .is_ok(),
+            "service should ignore the capacity by default"
+        );
+        assert!(
+            tokio::time::timeout(timeout, services[1].ready())
+                .await
+                .is_err(),
+            "service shouldn’t be ready while the store is full"
+        );
+
+        let waiting = tokio::spawn({
+            let mut service = services[1].clone();
+            async move {
+                service.ready().await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        store.full.store(false, Ordering::Release);
+        if let Some(waker) = store.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+        assert!(
+            tokio::time::timeout(timeout, waiting).await.is_ok(),
+            "service should be ready once the store has capacity"
+        );
+    }
+
+    #[cfg(feature = "span-attributes")]
+    #[tokio::test]
+    async fn should_record_span_attributes() {
+        use tracing::{
+            field::{Field, Visit},
+            span, Event, Metadata, Subscriber,
+        };
+
+        /// The subscriber collecting the recorded cache attributes.
+        #[derive(Clone, Default)]
+        struct Recorder {
+            fields: Arc<Mutex<Vec<(&'static str, String)>>>,
+            // the metadata of the `call` span while it’s entered
+            current: Arc<Mutex<Option<&'static Metadata<'static>>>>,
+            spans: Arc<Mutex<HashMap<u64, &'static Metadata<'static>>>>,
+        }
+
+        impl Visit for Recorder {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name().starts_with("cache.") {
+                    let value = format!("{value:?}");
+                    self.fields.lock().unwrap().push((field.name(), value));
+                }
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+                let mut spans = self.spans.lock().unwrap();
+                let id = spans.len() as u64 + 1;
+                spans.insert(id, attributes.metadata());
+                span::Id::from_u64(id)
+            }
+            fn record(&self, _: &span::Id, values: &span::Record<'_>) {
+                values.record(&mut self.clone());
+            }
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, _: &Event<'_>) {}
+            fn enter(&self, id: &span::Id) {
+                let metadata = self.spans.lock().unwrap()[&id.into_u64()];
+                *self.current.lock().unwrap() = Some(metadata);
+            }
+            fn exit(&self, _: &span::Id) {
+                *self.current.lock().unwrap() = None;
+            }
+            fn current_span(&self) -> tracing_core::span::Current {
+                match *self.current.lock().unwrap() {
+                    Some(metadata) => {
+                        tracing_core::span::Current::new(span::Id::from_u64(1), metadata)
+                    }
+                    None => tracing_core::span::Current::none(),
+                }
+            }
+        }
+
+        let recorder = Recorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+        let mut router = Router::new().route(
+            "/",
+            get(|| async { StatusCode::OK }).layer(CacheLayer::with_lifespan(60)),
+        );
+
+        for _ in 0..2 {
+            let status = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+
+        let fields = recorder.fields.lock().unwrap().clone();
+        let expected: Vec<(&str, String)> = vec![
+            ("cache.hit", "false".into()),
+            ("cache.key", "\"GET /\"".into()),
+            ("cache.hit", "true".into()),
+            ("cache.key", "\"GET /\"".into()),
+            ("cache.ttl_remaining", "59".into()),
+        ];
+        assert_eq!(expected, fields);
+    }
+
+    #[tokio::test]
+    async fn should_clamp_ttl() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .key_and_ttl_fn(|request| {
+                let ttl = match request.uri().path() {
+                    "/short" => Duration::ZERO,
+                    _ => Duration::from_secs(3600),
+                };
+                (request.uri().clone(), Some(ttl))
+            })
+            .clamp_ttl(Duration::from_secs(1), Duration::from_secs(2));
+        let mut router = Router::new()
+            .route("/short", get(handler))
+            .route("/long", get(handler))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        for (path, sleep, calls) in [
+            ("/short", 0, 1),
+            ("/short", 0, 1),
+            ("/long", 0, 2),
+            ("/long", 1500, 2),
+            ("/long", 600, 3),
+        ] {
+            tokio::time::sleep(Duration::from_millis(sleep)).await;
+            let status = router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+            assert_eq!(calls, counter.read(), "TTL should be clamped for {path}");
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[tokio::test]
+    async fn should_serve_file_backed_bodies() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            "x".repeat(100)
+        };
+
+        let dir = std::env::temp_dir().join(format!(
+            "axum-response-cache-test-{}",
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).file_backed_bodies(&dir, 10);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone());
+
+        for _ in 0..3 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!("x".repeat(100), body);
+        }
+        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+        assert_eq!(
+            1,
+            std::fs::read_dir(&dir).unwrap().count(),
+            "body should be in a file"
+        );
+
+        cache.flush_where(|_, _| true);
+        assert_eq!(
+            0,
+            std::fs::read_dir(&dir).unwrap().count(),
+            "file should be removed"
+        );
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_key_by_extension() {
+        #[derive(Clone, Hash)]
+        struct ClientIdentity(&'static str);
+
+        let handler = |State(cnt): State<Counter>, request: Request<Body>| async move {
+            cnt.increment();
+            match request.extensions().get::<ClientIdentity>() {
+                Some(ClientIdentity(subject)) => format!("report for {subject}"),
+                None => "anonymous".to_owned(),
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).key_extension::<ClientIdentity>();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for (identity, body, calls) in [
+            (Some("CN=a"), "report for CN=a", 1),
+            (Some("CN=b"), "report for CN=b", 2),
+            (Some("CN=a"), "report for CN=a", 2),
+            (None, "anonymous", 3),
+            (None, "anonymous", 4),
+            (Some("CN=b"), "report for CN=b", 4),
+        ] {
+            let mut request = Request::get("/").body(Body::empty()).unwrap();
+            if let Some(subject) = identity {
+                request.extensions_mut().insert(ClientIdentity(subject));
+            }
+            let response = router.call(request).await.unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            let received = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, received);
+            assert_eq!(
+                calls,
+                counter.read(),
+                "identities should be cached separately"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_probe_hits() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            let content_type = match headers.get(header::ACCEPT_LANGUAGE) {
+                Some(language) if language == "pl" => "text/html",
+                _ => "text/plain",
+            };
+            (
+                [
+                    (header::VARY, "accept-language"),
+                    (header::CONTENT_TYPE, content_type),
+                ],
+                "hello",
+            )
+        };
+
+        let cache = CacheLayer::with_lifespan(1).bypass_authorization(true);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(Counter::new(0));
+        let request = |language: &str| {
+            Request::get("/")
+                .header(header::ACCEPT_LANGUAGE, language)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert!(
+            !cache.would_hit(&request("en")),
+            "empty cache shouldn’t hit"
+        );
+        let status = router.call(request("en")).await.unwrap().status();
+        assert!(status.is_success(), "handler should return success");
+        assert!(cache.would_hit(&request("en")), "cached variant should hit");
+        assert!(
+            !cache.would_hit(&request("pl")),
+            "other variant shouldn’t hit"
+        );
+        let mut authorized = request("en");
+        authorized
+            .headers_mut()
+            .insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer x"));
+        assert!(
+            !cache.would_hit(&authorized),
+            "bypassed request shouldn’t hit"
+        );
+
+        let status = router.call(request("pl")).await.unwrap().status();
+        assert!(status.is_success(), "handler should return success");
+        assert!(cache.would_hit(&request("pl")), "cached variant should hit");
+        let consistent = cache.clone().enforce_consistent_content_type();
+        assert!(
+            consistent.would_hit(&request("en")),
+            "variant of the first media type should hit"
+        );
+        assert!(
+            !consistent.would_hit(&request("pl")),
+            "variant of the mismatched media type shouldn’t hit"
+        );
+
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+        assert!(
+            !cache.would_hit(&request("en")),
+            "expired variant shouldn’t hit"
+        );
+        assert_eq!(2, cache.dump().len(), "probing shouldn’t evict entries");
+    }
+
+    #[tokio::test]
+    async fn should_record_origin_request() {
+        let cache = CacheLayer::with_lifespan(60)
+            .key_by_matched_path()
+            .record_origin_request();
+        let mut router = Router::new().route(
+            "/users/:id",
+            get(|| async { StatusCode::OK }).layer(cache.clone()),
+        );
+
+        let request = Request::get("/users/1?tab=posts")
+            .header(header::HOST, "example.com")
+            .body(Body::empty())
+            .unwrap();
+        let status = router.call(request).await.unwrap().status();
+        assert!(status.is_success(), "handler should return success");
+
+        let dump = cache.dump();
+        assert_eq!(1, dump.len());
+        assert_eq!("/users/:id", dump[0].0 .1);
+        assert_eq!(
+            Some("GET /users/1?tab=posts (host: example.com)"),
+            dump[0].1.origin_request.as_deref()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_forward_cold_ranges() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            match headers.get(header::RANGE) {
+                Some(_) => (
+                    StatusCode::PARTIAL_CONTENT,
+                    [(header::CONTENT_RANGE, "bytes 0-1/10")],
+                    "01",
+                )
+                    .into_response(),
+                None => "0123456789".into_response(),
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).forward_cold_ranges();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for (range, status, body, calls) in [
+            (Some("bytes=0-1"), StatusCode::PARTIAL_CONTENT, "01", 1),
+            (Some("bytes=0-1"), StatusCode::PARTIAL_CONTENT, "01", 2),
+            (None, StatusCode::OK, "0123456789", 3),
+            (Some("bytes=2-4"), StatusCode::PARTIAL_CONTENT, "234", 3),
+        ] {
+            let mut request = Request::get("/").body(Body::empty()).unwrap();
+            if let Some(range) = range {
+                request
+                    .headers_mut()
+                    .insert(header::RANGE, HeaderValue::from_static(range));
+            }
+            let response = router.call(request).await.unwrap();
+            assert_eq!(status, response.status());
+            let received = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, received);
+            assert_eq!(
+                calls,
+                counter.read(),
+                "only full responses should be cached"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_buffer_bodies_with_known_length() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            let chunks = ["01234", "56789"].map(|chunk| Ok::<_, Infallible>(Bytes::from(chunk)));
+            let body = Body::from_stream(futures_util::stream::iter(chunks));
+            ([(header::CONTENT_LENGTH, "10")], body)
+        };
+
+        for (limit, status, calls) in [
+            (10, StatusCode::OK, 1),
+            (8, StatusCode::INTERNAL_SERVER_ERROR, 2),
+        ] {
+            let counter = Counter::new(0);
+            let cache = CacheLayer::with_lifespan(60).body_limit(limit);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+
+            for _ in 0..2 {
+                let response = router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(status, response.status());
+                if status.is_success() {
+                    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                        .await
+                        .unwrap();
+                    assert_eq!("0123456789", body);
+                }
+            }
+            assert_eq!(
+                calls,
+                counter.read(),
+                "only bodies within the limit should be cached"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_serve_only_from_cache_in_cache_only_mode() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache_only = Arc::new(AtomicBool::new(false));
+        let cache = CacheLayer::with_lifespan(1).cache_only(Arc::clone(&cache_only));
+        let mut router = Router::new()
+            .route("/cached", get(handler))
+            .route("/missing", get(handler))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        let status = router
+            .call(Request::get("/cached").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+
+        cache_only.store(true, Ordering::Relaxed);
+        // wait over 1s for cache eviction
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+        for (path, expected) in [
+            ("/cached", StatusCode::OK),
+            ("/missing", StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let status = router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert_eq!(expected, status, "only cached values should be served");
+        }
+        assert_eq!(
+            1,
+            counter.read(),
+            "handler shouldn’t be called in cache-only mode"
+        );
+
+        cache_only.store(false, Ordering::Relaxed);
+        let status = router
+            .call(Request::get("/missing").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success(), "handler should return success");
+        assert_eq!(
+            2,
+            counter.read(),
+            "handler should be called once switched back"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn should_normalize_json_fields() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            (
+                [(header::CONTENT_TYPE, "application/json")],
+                format!(
+                    r#"{{"data":[1,2],"generated_at":{},"meta":{{"request_id":"x"}}}}"#,
+                    cnt.read()
+                ),
+            )
+        };
+        let malformed = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            ([(header::CONTENT_TYPE, "application/json")], "{not json")
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).normalize_json_fields(
+            &[
+                ("/generated_at", JsonFieldAction::Remove),
+                ("/meta/request_id", JsonFieldAction::Null),
+                ("/missing", JsonFieldAction::Remove),
+            ],
+            MalformedJson::PassThrough,
+        );
+        let mut router = Router::new()
+            .route("/", get(handler))
+            .route("/malformed", get(malformed))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        for (path, body, calls) in [
+            ("/", r#"{"data":[1,2],"meta":{"request_id":null}}"#, 1),
+            ("/", r#"{"data":[1,2],"meta":{"request_id":null}}"#, 1),
+            ("/malformed", "{not json", 2),
+            ("/malformed", "{not json", 3),
+        ] {
+            let response = router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            let received = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, received);
+            assert_eq!(calls, counter.read(), "malformed JSON shouldn’t be cached");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_enforce_consistent_content_type() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            match cnt.read() {
+                1 => ([(header::CONTENT_TYPE, "application/json")], "{}"),
+                2 => ([(header::CONTENT_TYPE, "text/html")], "<h1>Oops</h1>"),
+                _ => (
+                    [(header::CONTENT_TYPE, "application/json; charset=utf-8")],
+                    "[]",
+                ),
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1)
+            .use_stale_on_failure()
+            .enforce_consistent_content_type();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone());
+
+        for (sleep, body) in [(0, "{}"), (1050, "<h1>Oops</h1>"), (0, "{}"), (1050, "[]")] {
+            tokio::time::sleep(Duration::from_millis(sleep)).await;
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            let received = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, received, "mismatched media type shouldn’t be cached");
+        }
+        assert_eq!(3, counter.read());
+    }
+
+    #[tokio::test]
+    async fn should_limit_variants_per_key() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            ([(header::VARY, "accept")], cnt.read().to_string())
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).max_variants_per_key(2);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone());
+
+        // `a` is used again before `c` is stored, so `b` is the least recently used
+        for (accept, body) in [
+            ("a", "1"),
+            ("b", "2"),
+            ("a", "1"),
+            ("c", "3"),
+            ("a", "1"),
+            ("b", "4"),
+        ] {
+            let response = router
+                .call(
+                    Request::get("/")
+                        .header(header::ACCEPT, accept)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            let received = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, received, "unexpected variant for {accept}");
+        }
+        assert_eq!(2, cache.dump().len(), "only two variants should be cached");
+    }
+
+    #[tokio::test]
+    async fn should_decorate_hits() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            match cnt.read() {
+                1 => StatusCode::OK,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        };
+
+        let cache = CacheLayer::with_lifespan(1)
+            .use_stale_on_failure()
+            .decorate_hit(|response, info| {
+                let outcome = format!("{:?}", info.outcome);
+                response
+                    .headers_mut()
+                    .insert("x-cache-lookup", HeaderValue::from_str(&outcome).unwrap());
+            });
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(Counter::new(0));
+
+        for (sleep, expected) in [(0, None), (0, Some("Fresh")), (1050, Some("Stale"))] {
+            tokio::time::sleep(Duration::from_millis(sleep)).await;
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            assert_eq!(
+                expected,
+                response
+                    .headers()
+                    .get("x-cache-lookup")
+                    .map(|value| value.to_str().unwrap()),
+                "only cached responses should be decorated"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_keep_newest_response_of_concurrent_misses() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            let call = cnt.read();
+            // the first request completes after the second one
+            if call == 1 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            call.to_string()
+        };
+
+        let cache = CacheLayer::with_lifespan(60).newest_response_wins();
+        let router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(Counter::new(0));
+
+        let (older, newer) = tokio::join!(
+            router
+                .clone()
+                .call(Request::get("/").body(Body::empty()).unwrap()),
+            router
+                .clone()
+                .call(Request::get("/").body(Body::empty()).unwrap()),
+        );
+        assert!(older.unwrap().status().is_success());
+        assert!(newer.unwrap().status().is_success());
+
+        let response = router
+            .clone()
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!("2", body, "older response shouldn’t replace the newer one");
+    }
+
+    #[tokio::test]
+    async fn should_add_headers_on_serve() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            (
+                [(header::ACCESS_CONTROL_MAX_AGE, "600")],
+                cnt.read().to_string(),
+            )
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("*"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from_static("60"),
+        );
+        let cache = CacheLayer::with_lifespan(60).add_headers_on_serve(headers);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(Counter::new(0));
+
+        for expected in [None, Some("*")] {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            assert_eq!(
+                expected,
+                response
+                    .headers()
+                    .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                    .map(|value| value.to_str().unwrap()),
+                "only cached responses should get the headers"
+            );
+            assert_eq!(
+                "600",
+                response.headers()[header::ACCESS_CONTROL_MAX_AGE],
+                "stored headers shouldn’t be replaced"
+            );
+        }
+    }
+
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn should_invalidate_on_file_changes() {
+        let dir = std::env::temp_dir().join(format!(
+            "axum-response-cache-test-{}",
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "first").unwrap();
+        std::fs::write(dir.join("b.txt"), "first").unwrap();
+
+        let root = dir.clone();
+        let handler =
+            move |State(cnt): State<Counter>,
+                  axum::extract::Path(path): axum::extract::Path<String>| {
+                let root = root.clone();
+                async move {
+                    cnt.increment();
+                    tokio::fs::read_to_string(root.join(path)).await.unwrap()
+                }
+            };
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .invalidate_on_file_changes(&dir, "/static")
+            .unwrap();
+        let mut router = Router::new()
+            .route("/static/*path", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for (path, expected) in [
+            ("/static/a.txt", "first"),
+            ("/static/b.txt", "first"),
+            ("/static/a.txt", "second"),
+            ("/static/b.txt", "first"),
+        ] {
+            let response = router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(expected, body, "unexpected content of {path}");
+            if counter.read() == 2 {
+                std::fs::write(dir.join("a.txt"), "second").unwrap();
+                // let the watching task process the events
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        }
+        assert_eq!(
+            3,
+            counter.read(),
+            "only the changed file should be fetched again"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_warn_on_lock_hold() {
+        use tracing::{span, Event, Level, Metadata, Subscriber};
+
+        /// The subscriber counting the warnings.
+        #[derive(Clone, Default)]
+        struct Warnings(Arc<std::sync::atomic::AtomicUsize>);
+
+        impl Subscriber for Warnings {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                if *event.metadata().level() == Level::WARN {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let warnings = Warnings::default();
+        let _guard = tracing::subscriber::set_default(warnings.clone());
+        for (threshold, expected) in [(Duration::from_secs(60), 0), (Duration::ZERO, 1)] {
+            let cache = CacheLayer::with_lifespan(60).warn_on_lock_hold(threshold);
+            let mut router =
+                Router::new().route("/", get(|| async { StatusCode::OK }).layer(cache));
+            let status = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+            assert_eq!(
+                expected,
+                warnings.0.load(Ordering::Relaxed),
+                "unexpected warnings for the {threshold:?} threshold"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_honor_cache_policy_extension() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(CacheLayer::with_lifespan(60)))
+            .with_state(counter.clone());
+
+        for (policy, expected) in [
+            (Some(CachePolicy::Bypass), 1),
+            (Some(CachePolicy::Cache), 2),
+            (None, 2),
+            (Some(CachePolicy::Bypass), 3),
+        ] {
+            let mut request = Request::get("/").body(Body::empty()).unwrap();
+            if let Some(policy) = policy {
+                request.extensions_mut().insert(policy);
+            }
+            let status = router.call(request).await.unwrap().status();
+            assert!(status.is_success(), "handler should return success");
+            assert_eq!(expected, counter.read(), "unexpected calls with {policy:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_signal_invalidation_once() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let cache = CacheLayer::with_lifespan(60).signal_invalidation(
+            HeaderName::from_static("clear-site-data"),
+            HeaderValue::from_static("\"cache\""),
+        );
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(Counter::new(0));
+
+        for (flush, expected) in [
+            (false, None),
+            (true, Some("\"cache\"")),
+            (false, None),
+            (false, None),
+        ] {
+            if flush {
+                assert_eq!(1, cache.flush_where(|_, _| true));
+            }
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            assert_eq!(
+                expected,
+                response
+                    .headers()
+                    .get("clear-site-data")
+                    .map(|value| value.to_str().unwrap()),
+                "only the first response after the invalidation should be signaled"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_retain_slow_responses() {
+        let slow = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            StatusCode::OK
+        };
+        let fast = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::cost_weighted(2, 60, ResponseLatency::cost).retain_by_latency();
+        let mut router = Router::new()
+            .route("/slow", get(slow))
+            .route("/fast/:id", get(fast))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        // the cheapest response is evicted when the third one is stored
+        for path in ["/slow", "/fast/1", "/fast/2", "/slow"] {
+            let status = router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status();
+            assert!(status.is_success(), "handler should return success");
+        }
+        assert_eq!(3, counter.read(), "slow response should’ve been retained");
+    }
+
+    #[tokio::test]
+    async fn should_invalidate_matching_keys() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/api/v1/:id", get(handler))
+            .layer(cache.clone())
+            .with_state(counter.clone());
+
+        let paths = ["/api/v1/users", "/api/v1/posts", "/api/v1/health"];
+        for path in paths {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+        let removed = cache.invalidate_matching(|(_, uri)| {
+            uri.path().starts_with("/api/v1/") && uri.path() != "/api/v1/health"
+        });
+        assert_eq!(2, removed, "all but the health check should be invalidated");
+
+        for path in paths {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            5,
+            counter.read(),
+            "only invalidated responses should be fetched"
+        );
+    }
+
+    #[test]
+    fn should_parse_cache_control() {
+        let parse = |values: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(header::CACHE_CONTROL, HeaderValue::from_str(value).unwrap());
+            }
+            CacheControl::from_headers(&headers)
+        };
+        let secs = |seconds| Some(Duration::from_secs(seconds));
+
+        let parsed = parse(&[
+            "public, MAX-AGE=60,s-maxage=\"120\"",
+            " no-cache , Immutable",
+        ]);
+        assert_eq!(secs(60), parsed.max_age, "casing shouldn’t matter");
+        assert_eq!(secs(120), parsed.s_maxage, "quoted values should be parsed");
+        assert!(
+            parsed.no_cache && parsed.immutable,
+            "headers should be combined"
+        );
+        assert!(!parsed.no_store && !parsed.private && !parsed.must_revalidate);
+
+        let parsed =
+            parse(&["private=\"set-cookie, x-user\", no-store, must-understand, must-revalidate"]);
+        assert!(parsed.private, "qualified directive should be recognized");
+        assert!(parsed.no_store && parsed.must_understand && parsed.must_revalidate);
+
+        let parsed = parse(&[
+            "max-age=abc, stale-if-error=-1, stale-while-revalidate=",
+            "foo=\"a,b",
+        ]);
+        assert_eq!(None, parsed.max_age, "malformed values should be ignored");
+        assert_eq!(None, parsed.stale_if_error);
+        assert_eq!(None, parsed.stale_while_revalidate);
+
+        let parsed = parse(&[
+            "max-age=10, max-age=20, x-unknown=\"max-age=30\", stale-while-revalidate=99999999999",
+        ]);
+        assert_eq!(secs(10), parsed.max_age, "first occurrence should be used");
+        assert_eq!(
+            secs(1 << 31),
+            parsed.stale_while_revalidate,
+            "big values should be capped"
+        );
+
+        assert_eq!(CacheControl::default(), parse(&[]));
+        assert_eq!(CacheControl::default(), parse(&[",, ,"]));
+    }
+
+    #[tokio::test]
+    async fn should_add_debug_key_header() {
+        let cache =
+            CacheLayer::with_lifespan(60).debug_key_header(HeaderName::from_static("x-cache-key"));
+        let mut router =
+            Router::new().route("/users", get(|| async { StatusCode::OK }).layer(cache));
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/users?page=2").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            assert_eq!(
+                "GET /users?page=2",
+                response.headers()["x-cache-key"],
+                "both the miss and the hit should expose the key"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_coalesce_concurrent_misses() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cnt.read().to_string()
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).coalesce_misses();
+        let router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let call = |mut router: Router| async move {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+        };
+        let (first, second, third) = tokio::join!(
+            call(router.clone()),
+            call(router.clone()),
+            call(router.clone())
+        );
+        assert_eq!(1, counter.read(), "only the first miss should be fetched");
+        assert_eq!("1", first);
+        assert_eq!(first, second);
+        assert_eq!(first, third);
+    }
+
+    #[tokio::test]
+    async fn should_apply_method_policies() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            cnt.read().to_string()
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).method_policies([
+            (Method::GET, MethodPolicy::cached()),
+            (
+                Method::POST,
+                MethodPolicy::cached()
+                    .ttl(Duration::from_millis(500))
+                    .body_in_key(),
+            ),
+            (Method::DELETE, MethodPolicy::bypassed()),
+        ]);
+        let mut router = Router::new()
+            .route("/", get(handler).post(handler).delete(handler))
+            .layer(cache)
+            .with_state(counter.clone());
+
+        for (method, body, sleep, expected) in [
+            (Method::GET, "", 0, "1"),
+            (Method::GET, "", 0, "1"),
+            (Method::POST, "a", 0, "2"),
+            (Method::POST, "b", 0, "3"),
+            (Method::POST, "a", 0, "2"),
+            (Method::DELETE, "", 0, "4"),
+            (Method::DELETE, "", 0, "5"),
+            // the TTL of the `POST` responses is shorter than the lifespan
+            (Method::POST, "a", 600, "6"),
+            (Method::GET, "", 0, "1"),
+        ] {
+            tokio::time::sleep(Duration::from_millis(sleep)).await;
+            let request = Request::builder()
+                .method(method.clone())
+                .uri("/")
+                .body(Body::from(body))
+                .unwrap();
+            let response = router.call(request).await.unwrap();
+            assert!(
+                response.status().is_success(),
+                "handler should return success"
+            );
+            let received = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(
+                expected, received,
+                "unexpected response to {method} {body:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_report_failed_body_stream() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            let chunks: Vec<Result<Bytes, std::io::Error>> = vec![
+                Ok(Bytes::from("partial")),
+                Err(std::io::Error::other("connection dropped")),
+            ];
+            Body::from_stream(futures_util::stream::iter(chunks))
+        };
+
+        let counter = Counter::new(0);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(CacheLayer::with_lifespan(60)))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!("Failed to read the response body", body);
+        }
+        assert_eq!(2, counter.read(), "failed response shouldn’t be cached");
+    }
+
+    #[tokio::test]
+    async fn should_refresh_soft_invalidated_responses_in_background() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            cnt.read().to_string()
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone());
+        let key = || (axum::http::Method::GET, axum::http::Uri::from_static("/"));
+
+        assert!(
+            !cache.soft_invalidate(&key()),
+            "nothing should be cached yet"
+        );
+        for (soft_invalidate, expected) in [(false, "1"), (true, "1"), (false, "2")] {
+            if soft_invalidate {
+                assert!(cache.soft_invalidate(&key()));
+            }
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(expected, body, "old value should be served meanwhile");
+            // let the background refresh complete
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(2, counter.read(), "value should be refreshed once");
+    }
+
+    #[tokio::test]
+    async fn should_vary_by_host_when_enabled() {
+        let handler = |headers: HeaderMap| async move {
+            headers
+                .get(header::HOST)
+                .map(|host| host.to_str().unwrap().to_owned())
+                .unwrap_or_default()
+        };
+
+        let mut router = Router::new().route(
+            "/",
+            get(handler).layer(CacheLayer::with_lifespan(60).vary_by_host()),
+        );
+
+        for (host, expected) in [
+            ("a.example", "a.example"),
+            ("b.example", "b.example"),
+            ("A.Example:80", "a.example"),
+            ("a.example:8080", "a.example:8080"),
+            ("bad host", "bad host"),
+        ] {
+            let response = router
+                .call(
+                    Request::get("/")
+                        .header(header::HOST, host)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(expected, body, "wrong response for {host}");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_stream_bypassed_responses_unbuffered() {
+        use futures_util::StreamExt as _;
+
+        // the body never ends, so buffering it would hang
+        let handler = || async {
+            let chunks = futures_util::stream::iter([Ok::<_, std::io::Error>("first")])
+                .chain(futures_util::stream::pending());
+            Body::from_stream(chunks)
+        };
+
+        let requests = [
+            (
+                CacheLayer::with_lifespan(60).bypass_authorization(true),
+                Request::get("/")
+                    .header(header::AUTHORIZATION, "Bearer token")
+                    .body(Body::empty())
+                    .unwrap(),
+            ),
+            (CacheLayer::with_lifespan(60), {
+                let mut request = Request::get("/").body(Body::empty()).unwrap();
+                request.extensions_mut().insert(CachePolicy::Bypass);
+                request
+            }),
+            (
+                CacheLayer::with_lifespan(60).async_skip_if(|_: &Request<Body>| async { true }),
+                Request::get("/").body(Body::empty()).unwrap(),
+            ),
+        ];
+        for (cache, request) in requests {
+            let mut router = Router::new().route("/", get(handler).layer(cache));
+            let response = tokio::time::timeout(Duration::from_secs(1), router.call(request))
+                .await
+                .expect("bypassed response shouldn’t be buffered")
+                .unwrap();
+            let chunk = response.into_body().into_data_stream().next().await;
+            assert_eq!("first", chunk.unwrap().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_iterate_cached_entries() {
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/short", get(|| async { "short" }))
+            .route("/longer", get(|| async { "longer body" }))
+            .layer(cache.clone());
+
+        assert_eq!(0, cache.entries().count(), "nothing should be cached yet");
+        for path in ["/short", "/longer", "/short"] {
+            router
+                .call(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let mut report = cache
+            .entries()
+            .map(|((_, uri), info)| (uri.to_string(), info.body_size))
+            .collect::<Vec<_>>();
+        report.sort();
+        assert_eq!(
+            vec![("/longer".to_owned(), 11), ("/short".to_owned(), 5)],
+            report
+        );
+    }
+
+    #[tokio::test]
+    async fn should_cache_privately_by_credential() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            headers
+                .get(header::AUTHORIZATION)
+                .map(|credential| credential.to_str().unwrap().to_owned())
+                .unwrap_or_default()
+        };
+
+        for (auth_mode, expected_calls) in [
+            (AuthMode::Ignore, 1),
+            (AuthMode::Bypass, 4),
+            (AuthMode::PrivateByCredential, 3),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route(
+                    "/",
+                    get(handler).layer(CacheLayer::with_lifespan(60).auth_mode(auth_mode)),
+                )
+                .with_state(counter.clone());
+
+            for credential in [Some("alice"), Some("bob"), None, Some("alice"), None] {
+                let mut request = Request::get("/");
+                if let Some(credential) = credential {
+                    request = request.header(header::AUTHORIZATION, credential);
+                }
+                let response = router
+                    .call(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                if auth_mode != AuthMode::Ignore {
+                    assert_eq!(credential.unwrap_or_default(), body, "{auth_mode:?}");
+                }
+            }
+            assert_eq!(expected_calls, counter.read(), "{auth_mode:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_share_variants_of_negotiated_image_formats() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            let format = match headers.get(header::ACCEPT).map(HeaderValue::as_bytes) {
+                Some(b"image/avif") => "avif",
+                Some(b"image/webp") => "webp",
+                _ => "jpeg",
+            };
+            ([(header::VARY, "accept")], format)
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).negotiate_image_formats(&[
+            "image/avif",
+            "image/webp",
+            "image/jpeg",
+        ]);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for (accept, expected) in [
+            ("image/avif,image/webp,*/*", "avif"),
+            ("image/avif, image/webp, image/*;q=0.8", "avif"),
+            ("image/webp,*/*;q=0.8", "webp"),
+            ("image/avif;q=0.5, image/webp", "webp"),
+            ("image/*,image/avif;q=0", "webp"),
+            ("image/jpeg", "jpeg"),
+            ("text/html", "jpeg"),
+        ] {
+            let response = router
+                .call(
+                    Request::get("/")
+                        .header(header::ACCEPT, accept)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(expected, body, "wrong format for {accept}");
+        }
+        assert_eq!(
+            4,
+            counter.read(),
+            "each resolved format (and the unacceptable header) should be fetched once"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_repopulate_cache_from_write_behind_store() {
+        // persisting the plain-data snapshots, like the stores writing to disk
+        #[derive(Clone, Default)]
+        struct Store(Arc<Mutex<Vec<(Key, EntrySnapshot)>>>);
+
+        #[axum::async_trait]
+        impl PersistentStore for Store {
+            async fn load(&self) -> Vec<(Key, CachedEntry)> {
+                let entries = self.0.lock().unwrap().clone();
+                entries
+                    .into_iter()
+                    .map(|(key, snapshot)| (key, snapshot.into()))
+                    .collect()
+            }
+
+            async fn persist(&self, key: Key, entry: CachedEntry) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((key, EntrySnapshot::from(&entry)));
+            }
+        }
+
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            cnt.read().to_string()
+        };
+
+        let counter = Counter::new(0);
+        let store = Store::default();
+        // the second layer stands for the restarted instance
+        for _ in 0..2 {
+            let cache =
+                CacheLayer::with_write_behind(TimedCache::with_lifespan(60), store.clone(), 16);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+            // let the persisted entries load and the stored ones persist
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!("1", body, "persisted response should be served");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(1, counter.read(), "response should be fetched once");
+        assert_eq!(1, store.0.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn should_reuse_unchanged_bodies_when_enabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            String::from("unchanged")
+        };
+
+        for (cache, reused) in [
+            (CacheLayer::with_lifespan(1), false),
+            (CacheLayer::with_lifespan(1).reuse_unchanged_bodies(), true),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache.clone()))
+                .with_state(counter.clone());
+
+            let mut bodies = Vec::new();
+            for _ in 0..2 {
+                router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let dump = cache.dump_full();
+                bodies.push(dump[0].1.body.clone().unwrap());
+                tokio::time::sleep(Duration::from_millis(1100)).await;
+            }
+            assert_eq!(2, counter.read(), "expired response should be refreshed");
+            assert_eq!(bodies[0], bodies[1]);
+            assert_eq!(
+                reused,
+                bodies[0].as_ptr() == bodies[1].as_ptr(),
+                "unchanged body should be reused only when enabled"
+            );
+        }
+    }
+
+    #[cfg(feature = "hot")]
+    #[tokio::test]
+    async fn should_serve_hot_keys_without_locking_cache() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            "home"
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).hot_keys(1, Duration::from_millis(50));
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone());
+        let request = || Request::get("/").body(Body::empty()).unwrap();
+
+        for _ in 0..2 {
+            router.call(request()).await.unwrap();
+        }
+        // let the hot key into the snapshot
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let store = Arc::clone(&cache.cache);
+        let (locked, wait_locked) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn(move || {
+            let _guard = store.lock().unwrap();
+            locked.send(()).unwrap();
+            std::thread::sleep(Duration::from_secs(1));
+        });
+        wait_locked.recv().unwrap();
+
+        let started = std::time::Instant::now();
+        let response = router.call(request()).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!("home", body);
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "hot key shouldn’t wait for the cache lock"
+        );
+        assert_eq!(1, counter.read());
+        holder.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_handle_upstream_not_modified_when_enabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            if prev == 0 {
+                ([(header::ETAG, "\"v1\"")], "body").into_response()
+            } else {
+                (StatusCode::NOT_MODIFIED, [(header::ETAG, "\"v1\"")]).into_response()
+            }
+        };
+
+        for (cache, expected) in [
+            (CacheLayer::with_lifespan(1), StatusCode::NOT_MODIFIED),
+            (
+                CacheLayer::with_lifespan(1).handle_upstream_not_modified(),
+                StatusCode::OK,
+            ),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+            let request = |etag: Option<&str>| {
+                let mut request = Request::get("/");
+                if let Some(etag) = etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                request.body(Body::empty()).unwrap()
+            };
+
+            router.call(request(None)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1050)).await;
+
+            let response = router.call(request(None)).await.unwrap();
+            assert_eq!(expected, response.status());
+            if expected == StatusCode::OK {
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                assert_eq!("body", body, "stored body should be served");
+
+                tokio::time::sleep(Duration::from_millis(1050)).await;
+                let response = router.call(request(Some("\"v1\""))).await.unwrap();
+                assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+                let response = router.call(request(None)).await.unwrap();
+                assert_eq!(StatusCode::OK, response.status());
+                assert_eq!(3, counter.read(), "revalidated value should be kept");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_apply_coalesced_failure_behaviour() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+        };
+
+        for (on_failure, expected_calls) in
+            [(CoalesceFailure::Retry, 3), (CoalesceFailure::Share, 1)]
+        {
+            let counter = Counter::new(0);
+            let cache = CacheLayer::with_lifespan(60)
+                .coalesce_misses()
+                .coalesce_on_failure(on_failure);
+            let router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+
+            let call = |mut router: Router| async move {
+                let response = router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+                axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap()
+            };
+            let bodies = tokio::join!(
+                call(router.clone()),
+                call(router.clone()),
+                call(router.clone())
+            );
+            assert_eq!(expected_calls, counter.read(), "{on_failure:?}");
+            assert_eq!(
+                ("unavailable".into(), "unavailable".into()),
+                (bodies.1, bodies.2)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_set_client_cache_control() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            ([(header::CACHE_CONTROL, "max-age=3600")], "hello")
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .set_client_cache_control(HeaderValue::from_static("public, max-age=60"));
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                "public, max-age=60",
+                response.headers()[header::CACHE_CONTROL]
+            );
+        }
+        assert_eq!(1, counter.read());
+        assert_eq!(
+            "max-age=3600",
+            cache.dump()[0].1.headers[header::CACHE_CONTROL],
+            "stored response should keep its own header"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_refuse_responses_with_headers_outside_allowlist() {
+        let handler = |State(cnt): State<Counter>, Path(name): Path<String>| async move {
+            cnt.increment();
+            ([(HeaderName::try_from(name).unwrap(), "value")], "body").into_response()
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .strict_header_allowlist(&[header::CONTENT_TYPE, header::ETAG]);
+        let mut router = Router::new()
+            .route("/:name", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            router
+                .call(Request::get("/etag").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(1, counter.read(), "allowed headers should be cached");
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/x-internal").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                response.headers()["x-internal"],
+                "value",
+                "refused response should be passed-through as it is"
+            );
+        }
+        assert_eq!(
+            3,
+            counter.read(),
+            "response with an unexpected header shouldn’t be cached"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_only_coalesce_without_retaining_responses() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cnt.read().to_string()
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).coalesce_only();
+        let router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let call = |mut router: Router| async move {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+        };
+        let (first, second) = tokio::join!(call(router.clone()), call(router.clone()));
+        assert_eq!(1, counter.read(), "only the first miss should be fetched");
+        assert_eq!(first, second);
+
+        assert_eq!(
+            "2",
+            call(router.clone()).await,
+            "response shouldn’t be retained"
+        );
+        assert_eq!(2, counter.read());
+    }
+
+    #[tokio::test]
+    async fn should_pass_through_upgrade_responses() {
+        use futures_util::StreamExt as _;
 
-impl<S, C> Service<Request<Body>> for CacheService<S, C>
-where
-    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send,
-    S::Future: Send + 'static,
-    C: Cached<Key, CachedResponse> + CloneCached<Key, CachedResponse> + Send + 'static,
-{
-    type Response = Response;
-    type Error = Infallible;
-    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send + 'static>>;
+        // the upgraded connection never ends, so buffering it would hang
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            let chunks = futures_util::stream::iter([Ok::<_, std::io::Error>("first")])
+                .chain(futures_util::stream::pending());
+            (StatusCode::SWITCHING_PROTOCOLS, Body::from_stream(chunks))
+        };
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .force_store_header(
+                HeaderName::from_static("x-force-store"),
+                HeaderValue::from_static("secret"),
+            )
+            .coalesce_misses()
+            .coalesce_on_failure(CoalesceFailure::Share);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            let request = Request::get("/")
+                .header(header::UPGRADE, "websocket")
+                .header("x-force-store", "secret")
+                .body(Body::empty())
+                .unwrap();
+            let response = tokio::time::timeout(Duration::from_secs(1), router.call(request))
+                .await
+                .expect("upgrade response shouldn’t be buffered")
+                .unwrap();
+            assert_eq!(StatusCode::SWITCHING_PROTOCOLS, response.status());
+        }
+        assert_eq!(2, counter.read(), "upgrade response shouldn’t be cached");
     }
 
-    #[instrument(skip(self, request))]
-    fn call(&mut self, request: Request<Body>) -> Self::Future {
-        let mut inner = self.inner.clone();
-        let use_stale = self.use_stale;
-        let allow_invalidation = self.allow_invalidation;
-        let add_response_headers = self.add_response_headers;
-        let limit = self.limit;
-        let cache = Arc::clone(&self.cache);
-        let key = (request.method().clone(), request.uri().clone());
+    #[tokio::test]
+    async fn should_key_by_path_and_query_only_when_enabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            "users"
+        };
 
-        // Check for the custom header "X-Invalidate-Cache" if invalidation is allowed
-        if allow_invalidation && request.headers().contains_key("X-Invalidate-Cache") {
-            // Manually invalidate the cache for this key
-            cache.lock().unwrap().cache_remove(&key);
-            debug!("Cache invalidated manually for key {:?}", key);
+        for (cache, expected_calls) in [
+            (CacheLayer::with_lifespan(60), 2),
+            (CacheLayer::with_lifespan(60).key_path_and_query_only(), 1),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route("/users", get(handler).layer(cache))
+                .with_state(counter.clone());
+
+            for uri in ["http://example.com/users?page=2", "/users?page=2"] {
+                let status = router
+                    .call(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success(), "handler should return success");
+            }
+
+            assert_eq!(expected_calls, counter.read());
         }
+    }
 
-        let inner_fut = inner
-            .call(request)
-            .instrument(tracing::info_span!("inner_service"));
-        let (cached, evicted) = {
-            let mut guard = cache.lock().unwrap();
-            let (cached, evicted) = guard.cache_get_expired(&key);
-            if let (Some(stale), true) = (cached.as_ref(), evicted) {
-                // reinsert stale value immediately so that others don’t schedule their updating
-                debug!("Found stale value in cache, reinsterting and attempting refresh");
-                guard.cache_set(key.clone(), stale.clone());
+    #[tokio::test]
+    async fn should_cancel_stale_refresh_when_client_disconnects() {
+        // counts the handler’s futures dropped before completing
+        struct Cancelled(Counter);
+
+        impl Drop for Cancelled {
+            fn drop(&mut self) {
+                self.0.increment();
             }
-            (cached, evicted)
-        };
-
-        Box::pin(async move {
-            match (cached, evicted) {
-                (Some(value), false) => Ok(value.into_response()),
-                (Some(stale_value), true) => {
-                    let response = inner_fut.await.unwrap();
-                    if response.status().is_success() {
-                        Ok(update_cache(&cache, key, response, limit, add_response_headers).await)
-                    } else if use_stale {
-                        debug!("Returning stale value.");
-                        Ok(stale_value.into_response())
-                    } else {
-                        debug!("Stale value in cache, evicting and returning failed response.");
-                        cache.lock().unwrap().cache_remove(&key);
-                        Ok(response)
+        }
+
+        let cancelled = Counter::new(0);
+        let handler = {
+            let cancelled = cancelled.clone();
+            move |State(cnt): State<Counter>| {
+                let guard = Cancelled(cancelled.clone());
+                async move {
+                    cnt.increment();
+                    if cnt.read() > 1 {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
                     }
+                    std::mem::forget(guard);
+                    "body"
                 }
-                (None, _) => {
-                    let response = inner_fut.await.unwrap();
-                    if response.status().is_success() {
-                        Ok(update_cache(&cache, key, response, limit, add_response_headers).await)
-                    } else {
-                        Ok(response)
-                    }
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).use_stale_on_failure();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        // wait over 1s for cache eviction
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+
+        let refresh = router.call(Request::get("/").body(Body::empty()).unwrap());
+        let disconnected = tokio::time::timeout(Duration::from_millis(50), refresh).await;
+        assert!(disconnected.is_err(), "refresh should still be running");
+        assert_eq!(2, counter.read(), "stale response should be refreshed");
+        assert_eq!(
+            1,
+            cancelled.read(),
+            "refresh should be dropped with the client’s request"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_key_by_selected_uri_source() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            "items"
+        };
+
+        for (source, expected_calls) in [(UriSource::Nested, 1), (UriSource::Original, 2)] {
+            let counter = Counter::new(0);
+            let cache = CacheLayer::with_lifespan(60).key_uri_source(source);
+            let service = Router::new()
+                .route("/items", get(handler).layer(cache))
+                .with_state(counter.clone());
+            let mut router = Router::new()
+                .nest_service("/a", service.clone())
+                .nest_service("/b", service);
+
+            for uri in ["/a/items", "/b/items", "/a/items"] {
+                let status = router
+                    .call(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status();
+                assert!(status.is_success(), "handler should return success");
+            }
+
+            assert_eq!(expected_calls, counter.read(), "{source:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_advertise_keyed_headers_with_vary() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            (
+                [
+                    (header::VARY, "Accept-Language, accept-encoding"),
+                    (header::CONTENT_ENCODING, "gzip"),
+                ],
+                "gzipped",
+            )
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .vary_by_host()
+            .auth_mode(AuthMode::PrivateByCredential);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            let response = router
+                .call(
+                    Request::get("/")
+                        .header(header::HOST, "example.com")
+                        .header(header::AUTHORIZATION, "Bearer token")
+                        .header(header::ACCEPT_ENCODING, "gzip")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let vary: Vec<_> = vary_names(response.headers()).collect();
+            assert_eq!(
+                vec![
+                    "Accept-Language",
+                    "accept-encoding",
+                    "host",
+                    "authorization"
+                ],
+                vary,
+                "keyed headers should be merged into the service’s Vary"
+            );
+        }
+        assert_eq!(1, counter.read());
+    }
+
+    #[tokio::test]
+    async fn should_cache_with_from_fn_middleware() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            cnt.read().to_string()
+        };
+
+        let counter = Counter::new(0);
+        let mut router = Router::new()
+            .route("/", get(handler))
+            .layer(axum::middleware::from_fn_with_state(
+                CacheLayer::with_lifespan(60),
+                cache_middleware,
+            ))
+            .with_state(counter.clone());
+
+        for _ in 0..3 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!("1", body, "response should be served from the cache");
+        }
+        assert_eq!(1, counter.read());
+    }
+
+    #[tokio::test]
+    async fn should_refetch_responses_older_than_max_revalidation_age() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            match headers.get(header::IF_NONE_MATCH) {
+                Some(_) => StatusCode::NOT_MODIFIED.into_response(),
+                None => ([(header::ETAG, "\"v1\"")], cnt.read().to_string()).into_response(),
+            }
+        };
+
+        for (cache, expected_body) in [
+            (CacheLayer::with_lifespan(1).revalidate_stale(), "1"),
+            (
+                CacheLayer::with_lifespan(1)
+                    .revalidate_stale()
+                    .max_revalidation_age(Duration::from_millis(1500)),
+                "3",
+            ),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+
+            let mut body = Bytes::new();
+            for attempt in 0..3 {
+                if attempt > 0 {
+                    // wait over 1s for cache eviction
+                    tokio::time::sleep(Duration::from_millis(1050)).await;
                 }
+                let response = router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(StatusCode::OK, response.status());
+                body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
             }
-        })
+
+            assert_eq!(3, counter.read());
+            assert_eq!(
+                expected_body, body,
+                "old response should be fetched again instead of revalidated"
+            );
+        }
     }
-}
 
-#[instrument(skip(cache, response))]
-async fn update_cache<C: Cached<Key, CachedResponse> + CloneCached<Key, CachedResponse>>(
-    cache: &Arc<Mutex<C>>,
-    key: Key,
-    response: Response,
-    limit: usize,
-    add_response_headers: bool,
-) -> Response {
-    let (parts, body) = response.into_parts();
-    let Ok(body) = axum::body::to_bytes(body, limit).await else {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("File too big, over {limit} bytes"),
-        )
-            .into_response();
-    };
-    let value = CachedResponse {
-        parts,
-        body,
-        timestamp: if add_response_headers {
-            Some(std::time::Instant::now())
-        } else {
-            None
-        },
-    };
-    {
-        cache.lock().unwrap().cache_set(key, value.clone());
+    #[cfg(feature = "digest")]
+    #[tokio::test]
+    async fn should_serve_content_digest_computed_at_store_time() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            "hello"
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .content_digest()
+            .compression(Compression::Gzip);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                response.headers()["repr-digest"],
+                "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:",
+                "digest should match the decompressed body"
+            );
+
+            let response = router
+                .call(
+                    Request::get("/")
+                        .header(header::ACCEPT_ENCODING, "gzip")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let digest = response.headers()["repr-digest"].clone();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(
+                digest::repr_digest(&body),
+                digest,
+                "digest should match the compressed body"
+            );
+        }
+        assert_eq!(1, counter.read());
     }
-    value.into_response()
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::Rng;
-    use std::sync::atomic::{AtomicIsize, Ordering};
+    #[tokio::test]
+    async fn should_apply_get_with_body_policy() {
+        let handler = |State(cnt): State<Counter>, body: String| async move {
+            cnt.increment();
+            body
+        };
 
-    use axum::{
-        extract::State,
-        http::{Request, StatusCode},
-        routing::get,
-        Router,
-    };
-    use tower::Service;
+        for (policy, expected_calls) in [
+            (GetBodyPolicy::Ignore, 1),
+            (GetBodyPolicy::IncludeInKey, 2),
+            (GetBodyPolicy::Bypass, 3),
+        ] {
+            let counter = Counter::new(0);
+            let cache = CacheLayer::with_lifespan(60).get_with_body(policy);
+            let mut router = Router::new()
+                .route("/search", get(handler).layer(cache))
+                .with_state(counter.clone());
+
+            for query in ["first", "second", "first"] {
+                let response = router
+                    .call(Request::get("/search").body(Body::from(query)).unwrap())
+                    .await
+                    .unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                if policy != GetBodyPolicy::Ignore {
+                    assert_eq!(query, body, "{policy:?}");
+                }
+            }
+            assert_eq!(expected_calls, counter.read(), "{policy:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_measure_cache_latency_when_enabled() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            "body"
+        };
+
+        assert!(CacheLayer::with_lifespan(60).cache_latency().is_none());
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).measure_cache_latency();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache.clone()))
+            .with_state(counter.clone());
+
+        for _ in 0..3 {
+            router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let latency = cache.cache_latency().unwrap();
+        assert_eq!(
+            3,
+            latency.lookup.count(),
+            "every request should be looked up"
+        );
+        assert_eq!(1, latency.store.count(), "only the miss should be stored");
+        assert!(latency.lookup.total > Duration::ZERO);
+        assert_eq!(
+            Some(&Duration::MAX),
+            latency.store.buckets.last().map(|(bound, _)| bound),
+            "last bucket should collect the longest operations"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_replay_custom_reason_phrase() {
+        use hyper::ext::ReasonPhrase;
+
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            let mut response = "body".into_response();
+            response
+                .extensions_mut()
+                .insert(ReasonPhrase::from_static(b"Legacy Okay"));
+            response
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(&b"Legacy Okay"[..]),
+                response
+                    .extensions()
+                    .get::<ReasonPhrase>()
+                    .map(ReasonPhrase::as_bytes),
+                "reason phrase should survive caching"
+            );
+        }
+        assert_eq!(1, counter.read());
+    }
+
+    #[tokio::test]
+    async fn should_cache_rate_limits_for_retry_after_window() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            match cnt.read() {
+                1 => (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, "1")],
+                    "slow down",
+                )
+                    .into_response(),
+                _ => "body".into_response(),
+            }
+        };
+
+        for (cache, expected_calls) in [
+            (CacheLayer::with_lifespan(60), 2),
+            (CacheLayer::with_lifespan(60).cache_rate_limits(), 1),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+
+            for _ in 0..2 {
+                router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(expected_calls, counter.read());
+        }
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).cache_rate_limits();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        // wait over 1s for the window to pass
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status(), "window should’ve passed");
+        assert_eq!(2, counter.read());
+    }
+
+    #[tokio::test]
+    async fn should_preserve_multi_valued_headers() {
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            let mut response = "body".into_response();
+            let headers = response.headers_mut();
+            for cookie in ["a=1", "b=2", "a=1"] {
+                headers.append(header::SET_COOKIE, HeaderValue::from_static(cookie));
+            }
+            response
+        };
+
+        let counter = Counter::new(0);
+        let mut serve_headers = HeaderMap::new();
+        serve_headers.append(header::SET_COOKIE, HeaderValue::from_static("c=3"));
+        serve_headers.append(header::WARNING, HeaderValue::from_static("110 - \"a\""));
+        serve_headers.append(header::WARNING, HeaderValue::from_static("111 - \"b\""));
+        let cache = CacheLayer::with_lifespan(60).add_headers_on_serve(serve_headers);
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let cookies: Vec<_> = response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .collect();
+            assert_eq!(
+                cookies,
+                ["a=1", "b=2", "a=1"],
+                "all values should be kept in order"
+            );
+        }
+        assert_eq!(1, counter.read());
 
-    #[derive(Clone, Debug)]
-    struct Counter {
-        value: Arc<AtomicIsize>,
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let warnings: Vec<_> = response.headers().get_all(header::WARNING).iter().collect();
+        assert_eq!(
+            warnings,
+            ["110 - \"a\"", "111 - \"b\""],
+            "all values of the added header should be served"
+        );
     }
 
-    impl Counter {
-        fn new(init: isize) -> Self {
-            Self {
-                value: AtomicIsize::from(init).into(),
+    #[tokio::test]
+    async fn should_replace_all_values_of_multi_valued_headers() {
+        let handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            let links: &[&str] = if prev == 0 {
+                &[
+                    "</a>; rel=preload",
+                    "</b>; rel=preload",
+                    "</c>; rel=preload",
+                ]
+            } else {
+                &["</d>; rel=preload", "</e>; rel=preload"]
+            };
+            let mut response = match prev {
+                0 => "body".into_response(),
+                _ => StatusCode::NOT_MODIFIED.into_response(),
+            };
+            let headers = response.headers_mut();
+            headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+            for link in links {
+                headers.append(header::LINK, HeaderValue::from_static(link));
             }
-        }
+            response
+        };
 
-        fn increment(&self) {
-            self.value.fetch_add(1, Ordering::Release);
-        }
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).revalidate_stale();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
 
-        fn read(&self) -> isize {
-            self.value.load(Ordering::Acquire)
+        router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        // wait over 1s for cache eviction
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let links: Vec<_> = response.headers().get_all(header::LINK).iter().collect();
+            assert_eq!(
+                links,
+                ["</d>; rel=preload", "</e>; rel=preload"],
+                "all the stored values should be replaced"
+            );
         }
+        assert_eq!(2, counter.read());
     }
 
     #[tokio::test]
-    async fn should_use_cached_value() {
+    async fn should_strip_and_check_all_values_of_multi_valued_headers() {
+        let name = HeaderName::from_static("x-force-store");
         let handler = |State(cnt): State<Counter>| async move {
             cnt.increment();
-            StatusCode::OK
+            let mut response = "body".into_response();
+            let headers = response.headers_mut();
+            headers.append("x-force-store", HeaderValue::from_static("one"));
+            headers.append("x-force-store", HeaderValue::from_static("two"));
+            headers.append(header::SET_COOKIE, HeaderValue::from_static("a=1"));
+            headers.append(header::SET_COOKIE, HeaderValue::from_static("b=2"));
+            response
         };
 
         let counter = Counter::new(0);
-        let cache = CacheLayer::with_lifespan(60).use_stale_on_failure();
+        let cache = CacheLayer::with_lifespan(60)
+            .force_store_header(name.clone(), HeaderValue::from_static("secret"));
         let mut router = Router::new()
             .route("/", get(handler).layer(cache))
             .with_state(counter.clone());
-
-        for _ in 0..10 {
-            let status = router
+        for _ in 0..2 {
+            let response = router
                 .call(Request::get("/").body(Body::empty()).unwrap())
                 .await
-                .unwrap()
-                .status();
-            assert!(status.is_success(), "handler should return success");
+                .unwrap();
+            assert!(
+                !response.headers().contains_key(&name),
+                "all values should be stripped"
+            );
         }
+        assert_eq!(1, counter.read());
 
-        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+        for (cache, expected_calls) in [
+            (
+                CacheLayer::with_lifespan(60).refuse_headers(&[header::SET_COOKIE]),
+                2,
+            ),
+            (
+                CacheLayer::with_lifespan(60).strict_header_allowlist(&[
+                    header::CONTENT_TYPE,
+                    header::SET_COOKIE,
+                    name.clone(),
+                ]),
+                1,
+            ),
+            (
+                CacheLayer::with_lifespan(60)
+                    .strict_header_allowlist(&[header::CONTENT_TYPE, header::SET_COOKIE]),
+                2,
+            ),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone());
+            for _ in 0..2 {
+                let response = router
+                    .call(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let cookies: Vec<_> = response
+                    .headers()
+                    .get_all(header::SET_COOKIE)
+                    .iter()
+                    .collect();
+                assert_eq!(cookies, ["a=1", "b=2"]);
+            }
+            assert_eq!(expected_calls, counter.read());
+        }
     }
 
     #[tokio::test]
-    async fn should_not_cache_unsuccessful_responses() {
-        let handler = |State(cnt): State<Counter>| async move {
+    async fn should_vary_by_trusted_headers() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
             cnt.increment();
-            let responses = [
-                StatusCode::BAD_REQUEST,
-                StatusCode::INTERNAL_SERVER_ERROR,
-                StatusCode::NOT_FOUND,
-            ];
-            let mut rng = rand::thread_rng();
-            responses[rng.gen_range(0..responses.len())]
+            headers
+                .get("cloudfront-viewer-country")
+                .map_or("none", |value| value.to_str().unwrap())
+                .to_owned()
         };
 
         let counter = Counter::new(0);
-        let cache = CacheLayer::with_lifespan(60).use_stale_on_failure();
+        let cache = CacheLayer::with_lifespan(60)
+            .vary_by_trusted_headers(&[HeaderName::from_static("cloudfront-viewer-country")]);
         let mut router = Router::new()
             .route("/", get(handler).layer(cache))
             .with_state(counter.clone());
 
-        for _ in 0..10 {
-            let status = router
-                .call(Request::get("/").body(Body::empty()).unwrap())
+        for (country, expected_calls) in [
+            (Some("US"), 1),
+            (Some("DE"), 2),
+            (Some("US"), 2),
+            (None, 3),
+            (Some(""), 4),
+            (None, 4),
+        ] {
+            let mut request = Request::get("/");
+            if let Some(country) = country {
+                request = request.header("cloudfront-viewer-country", country);
+            }
+            let response = router
+                .call(request.body(Body::empty()).unwrap())
                 .await
-                .unwrap()
-                .status();
-            assert!(!status.is_success(), "handler should never return success");
+                .unwrap();
+            assert_eq!(
+                response.headers()[header::VARY],
+                "cloudfront-viewer-country"
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let expected = match country {
+                Some("") => "",
+                Some(country) => country,
+                None => "none",
+            };
+            assert_eq!(expected.as_bytes(), &body[..], "{country:?}");
+            assert_eq!(expected_calls, counter.read(), "{country:?}");
         }
+    }
 
-        assert_eq!(
-            10,
-            counter.read(),
-            "handler should’ve been called for all requests"
-        );
+    #[tokio::test]
+    async fn should_strip_force_store_header_from_bypassed_requests() {
+        let handler = |State(cnt): State<Counter>, headers: HeaderMap| async move {
+            cnt.increment();
+            assert!(
+                headers.get("x-cache-force-store").is_none(),
+                "force store header should be stripped"
+            );
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60)
+            .bypass_authorization(true)
+            .bypass_cookie(true)
+            .get_with_body(GetBodyPolicy::Bypass)
+            .force_store_header(
+                HeaderName::from_static("x-cache-force-store"),
+                HeaderValue::from_static("s3cr3t"),
+            );
+        let mut router = Router::new()
+            .route("/", get(handler).options(handler).layer(cache))
+            .with_state(counter.clone());
+
+        let requests = [
+            Request::get("/").header(header::AUTHORIZATION, "Bearer x"),
+            Request::get("/").header(header::COOKIE, "a=1"),
+            Request::get("/").extension(CachePolicy::Bypass),
+            Request::options("/")
+                .header(header::ORIGIN, "https://example.com")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET"),
+        ];
+        for request in requests {
+            let request = request
+                .header("x-cache-force-store", "s3cr3t")
+                .body(Body::empty())
+                .unwrap();
+            let status = router.call(request).await.unwrap().status();
+            assert_eq!(StatusCode::OK, status);
+        }
+        let request = Request::get("/")
+            .header("x-cache-force-store", "wrong")
+            .body(Body::from("body"))
+            .unwrap();
+        let status = router.call(request).await.unwrap().status();
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(5, counter.read());
     }
 
     #[tokio::test]
-    async fn should_use_last_correct_stale_value() {
+    async fn should_not_store_refused_headers_from_revalidation() {
         let handler = |State(cnt): State<Counter>| async move {
             let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
-            let responses = [
-                StatusCode::BAD_REQUEST,
-                StatusCode::INTERNAL_SERVER_ERROR,
-                StatusCode::NOT_FOUND,
-            ];
-            let mut rng = rand::thread_rng();
-
-            // first response successful, later failed
-            if prev == 0 {
-                StatusCode::OK
-            } else {
-                responses[rng.gen_range(0..responses.len())]
+            match prev {
+                0 => ([(header::ETAG, "\"v1\"")], "body").into_response(),
+                1 => (
+                    StatusCode::NOT_MODIFIED,
+                    [(header::ETAG, "\"v1\""), (header::SET_COOKIE, "session=a")],
+                )
+                    .into_response(),
+                _ => (StatusCode::NOT_MODIFIED, [(header::ETAG, "\"v1\"")]).into_response(),
             }
         };
 
         let counter = Counter::new(0);
-        let cache = CacheLayer::with_lifespan(1).use_stale_on_failure();
+        let cache = CacheLayer::with_lifespan(1)
+            .safe_shared()
+            .bypass_cookie(false)
+            .revalidate_stale();
         let mut router = Router::new()
             .route("/", get(handler).layer(cache))
-            .with_state(counter);
+            .with_state(counter.clone());
 
-        // feed the cache
-        let status = router
+        router
             .call(Request::get("/").body(Body::empty()).unwrap())
             .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
+            .unwrap();
 
         // wait over 1s for cache eviction
         tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
 
-        for _ in 1..10 {
-            let status = router
+        let response = router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            response.headers()[header::SET_COOKIE],
+            "session=a",
+            "the revalidating client should get its cookie"
+        );
+
+        // the stale value reinserted during the revalidation is served, without the cookie
+        for _ in 0..2 {
+            let response = router
                 .call(Request::get("/").body(Body::empty()).unwrap())
                 .await
-                .unwrap()
-                .status();
+                .unwrap();
+            assert_eq!(StatusCode::OK, response.status());
             assert!(
-                status.is_success(),
-                "cache should return stale successful value"
+                !response.headers().contains_key(header::SET_COOKIE),
+                "the cookie shouldn’t be stored"
             );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], b"body");
         }
+        assert_eq!(2, counter.read());
     }
 
     #[tokio::test]
-    async fn should_not_use_stale_values() {
+    async fn should_not_store_auth_headers_from_upstream_not_modified() {
         let handler = |State(cnt): State<Counter>| async move {
             let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
-            let responses = [
-                StatusCode::BAD_REQUEST,
-                StatusCode::INTERNAL_SERVER_ERROR,
-                StatusCode::NOT_FOUND,
-            ];
-            let mut rng = rand::thread_rng();
-
-            // first response successful, later failed
             if prev == 0 {
-                StatusCode::OK
+                ([(header::ETAG, "\"v1\"")], "body").into_response()
             } else {
-                responses[rng.gen_range(0..responses.len())]
+                (
+                    StatusCode::NOT_MODIFIED,
+                    [
+                        (header::ETAG, "\"v1\""),
+                        (header::WWW_AUTHENTICATE, "Bearer realm=\"a\""),
+                    ],
+                )
+                    .into_response()
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1)
+            .safe_shared()
+            .handle_upstream_not_modified();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+        let request = |etag: Option<&str>| {
+            let mut request = Request::get("/");
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        router.call(request(None)).await.unwrap();
+        for etag in [Some("\"v1\""), None] {
+            tokio::time::sleep(Duration::from_millis(1050)).await;
+
+            // the revalidating client gets its response as it is
+            let response = router.call(request(etag)).await.unwrap();
+            assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+            let response = router.call(request(None)).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            assert!(
+                !response.headers().contains_key(header::WWW_AUTHENTICATE),
+                "auth header shouldn’t be stored in the shared entry"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_collide_body_keys_with_paths() {
+        let handler = |State(cnt): State<Counter>, uri: Uri, body: String| async move {
+            cnt.increment();
+            format!("{} {body}", uri.path())
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(60).get_with_body(GetBodyPolicy::IncludeInKey);
+        let mut router = Router::new()
+            .fallback(handler)
+            .layer(cache)
+            .with_state(counter.clone());
+
+        let digest = blake3::hash(b"query").to_hex();
+        let poisoning = format!("/{}/search", &digest[..32]);
+        for (path, body) in [(&*poisoning, ""), ("/search", "query")] {
+            let response = router
+                .call(Request::get(path).body(Body::from(body)).unwrap())
+                .await
+                .unwrap();
+            let response = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(format!("{path} {body}"), response);
+        }
+        assert_eq!(2, counter.read());
+    }
+
+    #[tokio::test]
+    async fn should_not_collide_prefixed_keys_with_paths() {
+        let handler = |State(cnt): State<Counter>, uri: Uri| async move {
+            cnt.increment();
+            uri.path().to_owned()
+        };
+        let credential = blake3::hash(b"Bearer victim").to_hex()[..32].to_owned();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-country", HeaderValue::from_static("US"));
+        let country = trusted_headers_hash(&headers, &[HeaderName::from_static("x-country")]);
+
+        for (cache, prefix, name, value) in [
+            (
+                CacheLayer::with_lifespan(60).auth_mode(AuthMode::PrivateByCredential),
+                credential,
+                header::AUTHORIZATION,
+                "Bearer victim",
+            ),
+            (
+                CacheLayer::with_lifespan(60)
+                    .vary_by_trusted_headers(&[HeaderName::from_static("x-country")]),
+                country,
+                HeaderName::from_static("x-country"),
+                "US",
+            ),
+        ] {
+            let counter = Counter::new(0);
+            let mut router = Router::new()
+                .fallback(handler)
+                .layer(cache)
+                .with_state(counter.clone());
+
+            let poisoning = format!("/{prefix}/me");
+            let request = Request::get(&poisoning).body(Body::empty()).unwrap();
+            router.call(request).await.unwrap();
+
+            let request = Request::get("/me")
+                .header(&name, value)
+                .body(Body::empty())
+                .unwrap();
+            let response = router.call(request).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!("/me", body, "{name}");
+            assert_eq!(2, counter.read(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_restore_entries_from_snapshots() {
+        #[derive(Clone, Default)]
+        struct SnapshotStore {
+            entries: Arc<tokio::sync::Mutex<HashMap<String, EntrySnapshot>>>,
+        }
+
+        #[axum::async_trait]
+        impl SecondaryCache for SnapshotStore {
+            async fn get(&self, (method, uri): &Key) -> Option<CachedEntry> {
+                let entries = self.entries.lock().await;
+                let snapshot = entries.get(&format!("{method} {uri}")).cloned()?;
+                Some(snapshot.into())
+            }
+
+            async fn set(&self, (method, uri): Key, entry: CachedEntry) {
+                let snapshot = EntrySnapshot::from(&entry);
+                // encoded like by the external stores
+                #[cfg(feature = "serde")]
+                let snapshot =
+                    serde_json::from_slice(&serde_json::to_vec(&snapshot).unwrap()).unwrap();
+                let key = format!("{method} {uri}");
+                self.entries.lock().await.insert(key, snapshot);
+            }
+        }
+
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            (
+                [(header::VARY, "accept-language"), (header::ETAG, "\"v1\"")],
+                "body",
+            )
+        };
+
+        let counter = Counter::new(0);
+        let secondary = SnapshotStore::default();
+        // two instances sharing the secondary cache, like before and after a restart
+        let mut routers = [0, 1].map(|_| {
+            let cache = CacheLayer::with_tiers(TimedCache::with_lifespan(60), secondary.clone())
+                .compression(Compression::Gzip)
+                .refresh_date_header();
+            Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone())
+        });
+        let request = || {
+            Request::get("/")
+                .header(header::ACCEPT_LANGUAGE, "en")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        routers[0].call(request()).await.unwrap();
+        // let the write through finish and the entry age
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+
+        let response = routers[1].call(request()).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(response.headers()[header::ETAG], "\"v1\"");
+        assert_eq!(response.headers()[header::AGE], "1", "age should be kept");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!("body", body, "compressed body should be restored");
+        assert_eq!(1, counter.read(), "restored entry should be served");
+
+        let snapshot = secondary.entries.lock().await["GET /"].clone();
+        assert_eq!(
+            snapshot.variants[0].vary,
+            [("accept-language".to_owned(), b"en".to_vec())]
+        );
+        let mut outdated = snapshot.clone();
+        outdated.variants[0].compression = Some("unknown".to_owned());
+        assert!(
+            CachedEntry::from(outdated).variants.is_empty(),
+            "unknown codec should drop the variant"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_write_behind_alongside_secondary_cache() {
+        #[derive(Clone, Default)]
+        struct MemoryStore(Arc<tokio::sync::Mutex<HashMap<Key, CachedEntry>>>);
+
+        #[axum::async_trait]
+        impl SecondaryCache for MemoryStore {
+            async fn get(&self, key: &Key) -> Option<CachedEntry> {
+                self.0.lock().await.get(key).cloned()
+            }
+
+            async fn set(&self, key: Key, entry: CachedEntry) {
+                self.0.lock().await.insert(key, entry);
+            }
+        }
+
+        #[derive(Clone, Default)]
+        struct Store(Arc<Mutex<Vec<Key>>>);
+
+        #[axum::async_trait]
+        impl PersistentStore for Store {
+            async fn load(&self) -> Vec<(Key, CachedEntry)> {
+                Vec::new()
+            }
+
+            async fn persist(&self, key: Key, _entry: CachedEntry) {
+                self.0.lock().unwrap().push(key);
+            }
+        }
+
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            StatusCode::OK
+        };
+
+        let counter = Counter::new(0);
+        let secondary = MemoryStore::default();
+        let stores = [Store::default(), Store::default()];
+        // two instances sharing the secondary cache, each with its own persistent store
+        let mut routers = stores.clone().map(|store| {
+            let cache = CacheLayer::with_tiers(TimedCache::with_lifespan(60), secondary.clone())
+                .write_behind(store, 16);
+            Router::new()
+                .route("/", get(handler).layer(cache))
+                .with_state(counter.clone())
+        });
+
+        for router in &mut routers {
+            router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            // let the write through and behind finish
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(
+            1,
+            secondary.0.lock().await.len(),
+            "entry should be mirrored"
+        );
+        assert_eq!(
+            1,
+            stores[0].0.lock().unwrap().len(),
+            "entry should be persisted"
+        );
+        assert_eq!(
+            1,
+            counter.read(),
+            "second instance should use the secondary cache"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_keep_stale_response_over_rate_limits() {
+        let handler = |State(cnt): State<Counter>| async move {
+            let prev = cnt.value.fetch_add(1, Ordering::AcqRel);
+            match prev {
+                0 => "body".into_response(),
+                1 => (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, "1")],
+                    "slow down",
+                )
+                    .into_response(),
+                _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        };
+
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1)
+            .use_stale_on_failure()
+            .cache_rate_limits();
+        let mut router = Router::new()
+            .route("/", get(handler).layer(cache))
+            .with_state(counter.clone());
+
+        router
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        // 429 and then 500 once the window would’ve passed
+        for expected_calls in [2, 3] {
+            // wait over 1s for cache eviction
+            tokio::time::sleep(Duration::from_millis(1050)).await;
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, response.status(), "stale response expected");
+            assert_eq!(expected_calls, counter.read());
+        }
+
+        // the rate limit stored without the stale response isn’t a fallback itself
+        let handler = |State(cnt): State<Counter>| async move {
+            cnt.increment();
+            match cnt.read() {
+                1 => (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "60")]).into_response(),
+                _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             }
         };
-
         let counter = Counter::new(0);
-        let cache = CacheLayer::with_lifespan(1);
+        let cache = CacheLayer::with_lifespan(60)
+            .use_stale_on_failure()
+            .cache_rate_limits()
+            .clamp_ttl(Duration::from_secs(1), Duration::from_secs(1));
         let mut router = Router::new()
             .route("/", get(handler).layer(cache))
             .with_state(counter.clone());
-
-        // feed the cache
-        let status = router
+        for _ in 0..2 {
+            let response = router
+                .call(Request::get("/").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        }
+        assert_eq!(1, counter.read());
+        // wait over the clamped 1s window
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+        let response = router
             .call(Request::get("/").body(Body::empty()).unwrap())
             .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
+            .unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        assert_eq!(2, counter.read());
+    }
 
-        // wait over 1s for cache eviction
-        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+    #[test]
+    fn should_reject_codings_over_wildcard() {
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+            headers
+        };
 
-        for _ in 1..10 {
-            let status = router
-                .call(Request::get("/").body(Body::empty()).unwrap())
-                .await
+        for (value, accepted) in [
+            ("gzip", true),
+            ("*", true),
+            ("br, *", true),
+            ("gzip;q=0, *", false),
+            ("*, GZIP;q=0", false),
+            ("*;q=0", false),
+            ("*;q=0, gzip", true),
+            ("br", false),
+        ] {
+            assert_eq!(
+                accepted,
+                Compression::Gzip.is_accepted(&headers(value)),
+                "{value}"
+            );
+        }
+        assert_ne!(
+            compression::normalized_accept_encoding(&headers("gzip;q=0, *")),
+            compression::normalized_accept_encoding(&headers("*")),
+            "rejected coding should select another variant"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_pass_intact_method_and_uri_to_service() {
+        #[derive(Clone, Hash)]
+        struct Tenant;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let seen = Arc::clone(&seen);
+            move |method: Method, uri: Uri| {
+                let seen = Arc::clone(&seen);
+                async move {
+                    seen.lock().unwrap().push(format!("{method} {uri}"));
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    match uri.path() {
+                        "/failing" => StatusCode::INTERNAL_SERVER_ERROR,
+                        _ => StatusCode::OK,
+                    }
+                }
+            }
+        };
+        let request = |method: Method, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
                 .unwrap()
-                .status();
-            assert!(
-                !status.is_success(),
-                "cache should forward unsuccessful values"
+        };
+
+        for cache in [
+            CacheLayer::with_lifespan(60).coalesce_misses(),
+            CacheLayer::with_lifespan(60)
+                .coalesce_misses()
+                .key_and_ttl_fn(|request| {
+                    let key = format!("/custom{}", request.uri().path());
+                    (Uri::try_from(key).unwrap(), None)
+                }),
+        ] {
+            seen.lock().unwrap().clear();
+            let router = Router::new().fallback(handler.clone()).layer(cache);
+
+            // miss, hit, miss of another method and the miss with the absolute URI
+            for (method, uri) in [
+                (Method::GET, "/path?query=1"),
+                (Method::GET, "/path?query=1"),
+                (Method::HEAD, "/path?query=1"),
+                (Method::GET, "http://example.com/absolute?query=2"),
+            ] {
+                router.clone().oneshot(request(method, uri)).await.unwrap();
+            }
+            // the followers fetch on their own after the leader’s failure
+            let call = || router.clone().oneshot(request(Method::GET, "/failing"));
+            let (first, second) = tokio::join!(call(), call());
+            assert!(first.is_ok() && second.is_ok());
+
+            assert_eq!(
+                vec![
+                    "GET /path?query=1",
+                    "HEAD /path?query=1",
+                    "GET http://example.com/absolute?query=2",
+                    "GET /failing",
+                    "GET /failing",
+                ],
+                *seen.lock().unwrap()
             );
         }
 
+        // the bypassed request lacking the keyed extension
+        seen.lock().unwrap().clear();
+        let router = Router::new()
+            .fallback(handler)
+            .layer(CacheLayer::with_lifespan(60).key_extension::<Tenant>());
+        router
+            .oneshot(request(Method::GET, "/bypassed?query=3"))
+            .await
+            .unwrap();
+        assert_eq!(vec!["GET /bypassed?query=3"], *seen.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_compute_by_lookup_rules_once_for_concurrent_calls() {
+        let counter = Counter::new(0);
+        let cache = CacheLayer::with_lifespan(1).promote_after(1, Duration::from_secs(60));
+        let key = || (axum::http::Method::GET, axum::http::Uri::from_static("/"));
+        let compute = || async {
+            counter.increment();
+            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+            let (parts, _) = Response::new(()).into_parts();
+            CachedResponse::new(parts, Bytes::from("computed"))
+        };
+
+        tokio::join!(
+            cache.get_or_compute(key(), compute),
+            cache.get_or_compute(key(), compute),
+            cache.get_or_compute(key(), compute),
+        );
+        assert_eq!(1, counter.read(), "concurrent calls should compute once");
+
+        // the hit promotes the entry past the lifespan of the cache
+        cache.get_or_compute(key(), compute).await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(1050)).await;
+        cache.get_or_compute(key(), compute).await;
+        assert_eq!(1, counter.read(), "promoted entry should’ve been kept");
+
+        assert!(cache.soft_invalidate(&key()));
+        cache.get_or_compute(key(), compute).await;
+        cache.get_or_compute(key(), compute).await;
         assert_eq!(
-            10,
+            2,
             counter.read(),
-            "handler should’ve been called for all requests"
+            "soft invalidated entry should’ve been computed again"
         );
     }
 
     #[tokio::test]
-    async fn should_not_invalidate_cache_when_disabled() {
+    async fn should_respect_request_cache_control() {
         let handler = |State(cnt): State<Counter>| async move {
             cnt.increment();
-            StatusCode::OK
+            cnt.read().to_string()
+        };
+        let request = |name: Option<HeaderName>, value| {
+            let mut request = Request::get("/").body(Body::empty()).unwrap();
+            if let Some(name) = name {
+                request
+                    .headers_mut()
+                    .insert(name, HeaderValue::from_static(value));
+            }
+            request
+        };
+        let body = |response: Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
         };
 
         let counter = Counter::new(0);
-        let cache = CacheLayer::with_lifespan(60);
         let mut router = Router::new()
-            .route("/", get(handler).layer(cache))
+            .route("/", get(handler))
+            .layer(CacheLayer::with_lifespan(60).respect_request_cache_control())
             .with_state(counter.clone());
 
-        // First request to cache the response
-        let status = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
-
-        // Second request should return the cached response - no increment
-        let status = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
-
-        // Third request with X-Invalidate-Cache header should not invalidate the cache - no increment
-        let status = router
-            .call(
-                Request::get("/")
-                    .header("X-Invalidate-Cache", "true")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
+        let cases = [
+            (None, "", "1"),
+            (None, "", "1"),
+            (Some(header::CACHE_CONTROL), "no-cache", "2"),
+            (None, "", "2"),
+            (Some(header::PRAGMA), "no-cache", "3"),
+            (Some(header::CACHE_CONTROL), "max-age=60", "3"),
+            (Some(header::CACHE_CONTROL), "max-age=0", "4"),
+            (Some(header::CACHE_CONTROL), "no-store", "5"),
+            (None, "", "4"),
+        ];
+        for (name, value, expected) in cases {
+            let response = router.call(request(name.clone(), value)).await.unwrap();
+            assert_eq!(expected, body(response).await, "{name:?}: {value}");
+        }
 
-        // Fourth request should still return the cached response - no increment
-        let status = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
+        // Pragma is ignored when the request has Cache-Control
+        let mut request_with_both = request(Some(header::PRAGMA), "no-cache");
+        request_with_both.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("max-age=60"),
+        );
+        let response = router.call(request_with_both).await.unwrap();
+        assert_eq!("4", body(response).await);
 
-        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+        // the directives are ignored by default
+        let counter = Counter::new(0);
+        let mut router = Router::new()
+            .route("/", get(handler))
+            .layer(CacheLayer::with_lifespan(60))
+            .with_state(counter.clone());
+        for value in ["", "no-cache", "no-store"] {
+            let response = router
+                .call(request(Some(header::CACHE_CONTROL), value))
+                .await
+                .unwrap();
+            assert_eq!("1", body(response).await);
+        }
     }
 
     #[tokio::test]
-    async fn should_invalidate_cache_when_enabled() {
+    async fn should_invalidate_keys_prefixed_by_trusted_headers() {
         let handler = |State(cnt): State<Counter>| async move {
             cnt.increment();
             StatusCode::OK
         };
 
+        let country = HeaderName::from_static("cloudfront-viewer-country");
+        let (sender, receiver) = broadcast::channel(16);
         let counter = Counter::new(0);
-        let cache = CacheLayer::with_lifespan(60).allow_invalidation();
+        let cache = CacheLayer::with_lifespan(60)
+            .vary_by_trusted_headers(std::slice::from_ref(&country))
+            .invalidate_on(receiver);
         let mut router = Router::new()
-            .route("/", get(handler).layer(cache))
+            .route("/users/:id", get(handler))
+            .route("/posts/:id", get(handler))
+            .layer(cache)
             .with_state(counter.clone());
+        let requests = || {
+            ["/users/1", "/posts/1"].into_iter().flat_map(|path| {
+                [None, Some("PL"), Some("US")].map(|value| {
+                    let mut request = Request::get(path).body(Body::empty()).unwrap();
+                    if let Some(value) = value {
+                        request
+                            .headers_mut()
+                            .insert(country.clone(), HeaderValue::from_static(value));
+                    }
+                    request
+                })
+            })
+        };
 
-        // First request to cache the response
-        let status = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
-
-        // Second request should return the cached response - no increment
-        let status = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
-
-        // Third request with X-Invalidate-Cache header to invalidate the cache
-        let status = router
-            .call(
-                Request::get("/")
-                    .header("X-Invalidate-Cache", "true")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
+        for request in requests() {
+            router.call(request).await.unwrap();
+        }
+        assert_eq!(6, counter.read(), "all responses should’ve been cached");
 
-        // Fourth request to verify that the handler is called again
-        let status = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap()
-            .status();
-        assert!(status.is_success(), "handler should return success");
+        sender
+            .send(InvalidationMsg::Prefix(String::from("/users/")))
+            .unwrap();
+        sender
+            .send(InvalidationMsg::Key(
+                axum::http::Method::GET,
+                axum::http::Uri::from_static("/posts/1"),
+            ))
+            .unwrap();
+        // let the listening task process the messages
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
 
-        assert_eq!(2, counter.read(), "handler should’ve been called twice");
+        for request in requests() {
+            router.call(request).await.unwrap();
+        }
+        assert_eq!(
+            12,
+            counter.read(),
+            "responses for all the header values should’ve been invalidated"
+        );
     }
 
     #[tokio::test]
-    async fn should_not_include_age_header_when_disabled() {
+    async fn should_invalidate_keys_prefixed_by_credentials() {
         let handler = |State(cnt): State<Counter>| async move {
             cnt.increment();
             StatusCode::OK
         };
 
+        let (sender, receiver) = broadcast::channel(16);
         let counter = Counter::new(0);
-        let cache = CacheLayer::with_lifespan(60);
+        let cache = CacheLayer::with_lifespan(60)
+            .auth_mode(AuthMode::PrivateByCredential)
+            .invalidate_on(receiver);
         let mut router = Router::new()
-            .route("/", get(handler).layer(cache))
+            .route("/users/:id", get(handler))
+            .route("/posts/:id", get(handler))
+            .layer(cache)
             .with_state(counter.clone());
+        let requests = || {
+            ["/users/1", "/posts/1"].into_iter().flat_map(|path| {
+                [None, Some("Bearer alice"), Some("Bearer bob")].map(|credential| {
+                    let mut request = Request::get(path).body(Body::empty()).unwrap();
+                    if let Some(credential) = credential {
+                        request
+                            .headers_mut()
+                            .insert(header::AUTHORIZATION, HeaderValue::from_static(credential));
+                    }
+                    request
+                })
+            })
+        };
 
-        // First request to cache the response
-        let response = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap();
-        assert!(
-            response.status().is_success(),
-            "handler should return success"
-        );
+        for request in requests() {
+            router.call(request).await.unwrap();
+        }
+        assert_eq!(6, counter.read(), "all responses should’ve been cached");
 
-        // Second request should return the cached response
-        let response = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
+        sender
+            .send(InvalidationMsg::Prefix(String::from("/users/")))
             .unwrap();
-        assert!(
-            response.status().is_success(),
-            "handler should return success"
-        );
-        assert!(
-            response.headers().get("X-Cache-Age").is_none(),
-            "Age header should not be present"
-        );
+        sender
+            .send(InvalidationMsg::Key(
+                axum::http::Method::GET,
+                axum::http::Uri::from_static("/posts/1"),
+            ))
+            .unwrap();
+        // let the listening task process the messages
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
 
-        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+        for request in requests() {
+            router.call(request).await.unwrap();
+        }
+        assert_eq!(
+            12,
+            counter.read(),
+            "responses of all the users should’ve been invalidated"
+        );
     }
 
     #[tokio::test]
-    async fn should_include_age_header_when_enabled() {
+    async fn should_invalidate_keys_varying_by_host() {
         let handler = |State(cnt): State<Counter>| async move {
             cnt.increment();
             StatusCode::OK
         };
 
+        let (sender, receiver) = broadcast::channel(16);
         let counter = Counter::new(0);
-        let cache = CacheLayer::with_lifespan(60).add_response_headers();
+        let cache = CacheLayer::with_lifespan(60)
+            .vary_by_host()
+            .invalidate_on(receiver);
         let mut router = Router::new()
-            .route("/", get(handler).layer(cache))
+            .route("/users/:id", get(handler))
+            .route("/posts/:id", get(handler))
+            .layer(cache)
             .with_state(counter.clone());
+        let request = |path, host| {
+            Request::get(path)
+                .header(header::HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let requests = || {
+            ["/users/1", "/posts/1"]
+                .into_iter()
+                .flat_map(|path| ["a.example", "b.example"].map(|host| request(path, host)))
+        };
 
-        // First request to cache the response
-        let response = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
+        for request in requests() {
+            router.call(request).await.unwrap();
+        }
+        assert_eq!(4, counter.read(), "all responses should’ve been cached");
+
+        sender
+            .send(InvalidationMsg::Key(
+                axum::http::Method::GET,
+                axum::http::Uri::from_static("/posts/1"),
+            ))
             .unwrap();
+        sender
+            .send(InvalidationMsg::Key(
+                axum::http::Method::GET,
+                axum::http::Uri::from_static("http://a.example/users/1"),
+            ))
+            .unwrap();
+        // let the listening task process the messages
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+        for request in requests() {
+            router.call(request).await.unwrap();
+        }
+        assert_eq!(
+            7,
+            counter.read(),
+            "responses of all the hosts should’ve been invalidated, except the other host’s"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_probe_hits_without_using_entries() {
+        let cache = CacheLayer::with(cached::stores::TimedSizedCache::with_size_and_lifespan(
+            2, 60,
+        ));
+        let mut router = Router::new()
+            .route("/a", get(|| async { "a" }))
+            .route("/b", get(|| async { "b" }))
+            .route("/c", get(|| async { "c" }))
+            .layer(cache.clone());
+        let request = |path| Request::get(path).body(Body::empty()).unwrap();
+
+        for path in ["/a", "/b"] {
+            router.call(request(path)).await.unwrap();
+        }
         assert!(
-            response.status().is_success(),
-            "handler should return success"
+            cache.would_hit(&request("/a")),
+            "cached response should hit"
+        );
+        assert!(
+            !cache.would_hit(
+                &Request::options("/a")
+                    .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                    .body(Body::empty())
+                    .unwrap()
+            ),
+            "bypassed preflight shouldn’t hit"
         );
 
-        // Age should be 0
+        // probing `/a` mustn’t make it more recently used than `/b`
+        router.call(request("/c")).await.unwrap();
+        assert!(
+            !cache.would_hit(&request("/a")),
+            "evicted response shouldn’t hit"
+        );
+        assert!(
+            cache.would_hit(&request("/b")),
+            "cached response should hit"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_stop_listening_for_invalidations_once_dropped() {
+        let (sender, receiver) = broadcast::channel::<InvalidationMsg>(16);
+        let cache = CacheLayer::with_lifespan(60).invalidate_on(receiver);
+        assert_eq!(1, sender.receiver_count());
+
+        drop(cache);
+        // wait over 1s for the listening task to notice
+        tokio::time::sleep(tokio::time::Duration::from_millis(1100)).await;
         assert_eq!(
-            response
-                .headers()
-                .get("X-Cache-Age")
-                .and_then(|v| v.to_str().ok())
-                .unwrap_or(""),
-            "0",
-            "Age header should be present and equal to 0"
+            0,
+            sender.receiver_count(),
+            "receiver should’ve been dropped without any messages sent"
         );
-        // wait over 2s to age the cache
-        tokio::time::sleep(tokio::time::Duration::from_millis(2100)).await;
-        // Second request should return the cached response
-        let response = router
-            .call(Request::get("/").body(Body::empty()).unwrap())
-            .await
+    }
+
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn should_stop_watching_files_once_dropped() {
+        let dir = std::env::temp_dir().join(format!(
+            "axum-response-cache-test-{}",
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        let metrics = tokio::runtime::Handle::current().metrics();
+
+        let cache = CacheLayer::with_lifespan(60)
+            .invalidate_on_file_changes(&dir, "/static")
             .unwrap();
+        assert_eq!(1, metrics.num_alive_tasks());
 
+        drop(cache);
+        // wait over 1s for the watching task to notice
+        tokio::time::sleep(Duration::from_millis(1100)).await;
         assert_eq!(
-            response
-                .headers()
-                .get("X-Cache-Age")
-                .and_then(|v| v.to_str().ok())
-                .unwrap_or(""),
-            "2",
-            "Age header should be present and equal to 2"
+            0,
+            metrics.num_alive_tasks(),
+            "watching task should’ve stopped without any files changed"
         );
-
-        assert_eq!(1, counter.read(), "handler should’ve been called only once");
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
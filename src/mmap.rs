@@ -0,0 +1,63 @@
+//! Backing the large cached bodies with memory-mapped files.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axum::body::Bytes;
+use memmap2::Mmap;
+
+/// The counter making the names of the files unique within the process.
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// The mapped file removed once the last reference to the body is dropped.
+struct MappedFile {
+    // `None` only while dropping, so the file is unmapped before it’s removed
+    map: Option<Mmap>,
+    path: PathBuf,
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or_default()
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        self.map = None;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Write the body to a new file in the directory and return the body backed by the mapping of
+/// the file, so it’s held by the OS page cache instead of the heap.
+pub(crate) fn file_backed(dir: &Path, body: &[u8]) -> std::io::Result<Bytes> {
+    let path = dir.join(format!(
+        "axum-response-cache-{}-{}.body",
+        std::process::id(),
+        NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    let map = file.write_all(body).and_then(|_| {
+        // SAFETY: the file was created exclusively for this body and is never written again
+        unsafe { Mmap::map(&file) }
+    });
+    match map {
+        Ok(map) => Ok(Bytes::from_owner(MappedFile {
+            map: Some(map),
+            path,
+        })),
+        Err(err) => {
+            let _ = fs::remove_file(&path);
+            Err(err)
+        }
+    }
+}
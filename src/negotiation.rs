@@ -0,0 +1,71 @@
+//! Negotiation of the image formats by the request’s `Accept` header.
+
+use axum::http::{header, HeaderMap, HeaderValue};
+
+/// Replace the request’s `Accept` header with the offered format preferred by the client, so
+/// the requests resolving to the same format share the variants of the responses varying by
+/// `Accept`. The header is left as it is when none of the formats is acceptable.
+pub(crate) fn negotiate_accept(headers: &mut HeaderMap, formats: &[HeaderValue]) {
+    if let Some(format) = negotiate(headers, formats) {
+        headers.insert(header::ACCEPT, format.clone());
+    }
+}
+
+/// Select the offered format with the highest quality in the `Accept` header, the earlier
+/// formats winning the ties (the missing header accepts all of them).
+fn negotiate<'a>(headers: &HeaderMap, formats: &'a [HeaderValue]) -> Option<&'a HeaderValue> {
+    let ranges: Vec<_> = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(media_range)
+        .collect();
+    if ranges.is_empty() {
+        return formats.first();
+    }
+    let mut best: Option<(&HeaderValue, f32)> = None;
+    for format in formats {
+        let Some((kind, subtype)) = format
+            .to_str()
+            .ok()
+            .and_then(|format| format.split_once('/'))
+        else {
+            continue;
+        };
+        // the most specific range matching the format sets its quality
+        let quality = ranges
+            .iter()
+            .filter_map(|(range_kind, range_subtype, quality)| {
+                let specificity = match (range_kind.as_str(), range_subtype.as_str()) {
+                    ("*", "*") => 0,
+                    (range_kind, "*") if range_kind.eq_ignore_ascii_case(kind) => 1,
+                    (range_kind, range_subtype)
+                        if range_kind.eq_ignore_ascii_case(kind)
+                            && range_subtype.eq_ignore_ascii_case(subtype) =>
+                    {
+                        2
+                    }
+                    _ => return None,
+                };
+                Some((specificity, *quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality);
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((format, quality));
+        }
+    }
+    best.map(|(format, _)| format)
+}
+
+/// Parse the media range of the `Accept` header into its type, subtype and quality.
+fn media_range(range: &str) -> Option<(String, String, f32)> {
+    let mut params = range.split(';').map(str::trim);
+    let (kind, subtype) = params.next()?.split_once('/')?;
+    let quality = params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+        .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok())?;
+    Some((kind.trim().to_owned(), subtype.trim().to_owned(), quality))
+}
@@ -0,0 +1,232 @@
+//! The plain-data snapshots of the cached entries, for the secondary and persistent stores to
+//! encode them.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderName, HeaderValue, Response, StatusCode},
+};
+
+use crate::{CacheControl, CachedEntry, CachedResponse, Compression};
+
+/// The snapshot of the cached entry holding only the plain data, so it can be encoded for the
+/// stores outside the process (eg. Redis or disk), see [`SecondaryCache`](crate::SecondaryCache)
+/// and [`PersistentStore`](crate::PersistentStore). With the `serde` feature, it implements
+/// `Serialize` and `Deserialize`.
+///
+/// The times are taken from the wall clock, so the entries restored after a restart (or by
+/// another instance) keep their age. The extensions of the responses aren’t included.
+///
+/// ```rust
+/// use std::{collections::HashMap, sync::Mutex};
+///
+/// use axum::{
+///     async_trait,
+///     http::{Method, Uri},
+/// };
+/// use axum_response_cache::{CachedEntry, EntrySnapshot, SecondaryCache};
+///
+/// /// The store of the encoded entries, eg. in Redis.
+/// #[derive(Default)]
+/// struct EncodedStore(Mutex<HashMap<String, EntrySnapshot>>);
+///
+/// #[async_trait]
+/// impl SecondaryCache for EncodedStore {
+///     async fn get(&self, (method, uri): &(Method, Uri)) -> Option<CachedEntry> {
+///         let snapshot = self.0.lock().unwrap().get(&format!("{method} {uri}")).cloned()?;
+///         Some(snapshot.into())
+///     }
+///
+///     async fn set(&self, (method, uri): (Method, Uri), entry: CachedEntry) {
+///         let snapshot = EntrySnapshot::from(&entry);
+///         self.0.lock().unwrap().insert(format!("{method} {uri}"), snapshot);
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntrySnapshot {
+    /// The variants of the response, see [`CachedEntry`].
+    pub variants: Vec<VariantSnapshot>,
+    /// The number of the hits of the entry.
+    pub hits: u64,
+}
+
+/// The snapshot of one variant of the cached response, see [`EntrySnapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VariantSnapshot {
+    /// The status code of the response.
+    pub status: u16,
+    /// The names and values of the response headers, in order.
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// The stored (possibly compressed) body.
+    pub body: Vec<u8>,
+    /// The content coding the body is compressed with by the layer, see
+    /// [`Compression::encoding`].
+    pub compression: Option<String>,
+    /// The request headers (with their values) selecting this variant.
+    pub vary: Vec<(String, Vec<u8>)>,
+    /// When the response was stored (or last revalidated).
+    pub stored_at: SystemTime,
+    /// When the body was fetched, kept when the response is revalidated.
+    pub fetched_at: SystemTime,
+    /// The own TTL of the response, overriding the lifespan of the cache.
+    pub ttl: Option<Duration>,
+    /// Whether the `Age` header is added when serving the response.
+    pub age_header: bool,
+    /// The `Host` header of the request the response was stored for.
+    pub host: Option<Vec<u8>>,
+    /// The signature of the request the response was stored for, see
+    /// [`CacheLayer::record_origin_request`](crate::CacheLayer::record_origin_request).
+    pub origin: Option<String>,
+    /// Whether the response was invalidated softly, to be refreshed on the next request.
+    pub soft_invalidated: bool,
+    /// The BLAKE3 hash of the body, see
+    /// [`CacheLayer::reuse_unchanged_bodies`](crate::CacheLayer::reuse_unchanged_bodies).
+    pub body_hash: Option<[u8; 32]>,
+    /// The digest of the body served decompressed, see `CacheLayer::content_digest`.
+    pub identity_digest: Option<Vec<u8>>,
+}
+
+impl From<&CachedEntry> for EntrySnapshot {
+    fn from(entry: &CachedEntry) -> Self {
+        Self {
+            variants: entry.variants.iter().map(VariantSnapshot::from).collect(),
+            hits: entry.hits,
+        }
+    }
+}
+
+/// Restore the entry from the snapshot, dropping the variants that can’t be restored: the ones
+/// with invalid status or headers, compressed with a codec the crate was built without, or
+/// stored before the earliest moment the monotonic clock of the process can represent (eg.
+/// before the reboot of the host).
+impl From<EntrySnapshot> for CachedEntry {
+    fn from(snapshot: EntrySnapshot) -> Self {
+        Self {
+            variants: snapshot
+                .variants
+                .into_iter()
+                .filter_map(restore_variant)
+                .collect(),
+            hits: snapshot.hits,
+        }
+    }
+}
+
+impl From<&CachedResponse> for VariantSnapshot {
+    fn from(value: &CachedResponse) -> Self {
+        Self {
+            status: value.parts.status.as_u16(),
+            headers: header_pairs(&value.parts.headers),
+            body: value.body.to_vec(),
+            compression: value
+                .compression
+                .map(|compression| compression.encoding().to_owned()),
+            vary: value
+                .vary
+                .iter()
+                .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_vec()))
+                .collect(),
+            stored_at: wall_clock(value.timestamp),
+            fetched_at: wall_clock(value.fetched),
+            ttl: value.ttl,
+            age_header: value.age_header,
+            host: value.host.as_ref().map(|host| host.as_bytes().to_vec()),
+            origin: value.origin.as_deref().map(str::to_owned),
+            soft_invalidated: value.soft_invalidated,
+            body_hash: value.body_hash.map(|hash| *hash.as_bytes()),
+            #[cfg(feature = "digest")]
+            identity_digest: value
+                .identity_digest
+                .as_ref()
+                .map(|digest| digest.as_bytes().to_vec()),
+            #[cfg(not(feature = "digest"))]
+            identity_digest: None,
+        }
+    }
+}
+
+/// Restore the variant from the snapshot, or return `None` when it can’t be restored.
+fn restore_variant(snapshot: VariantSnapshot) -> Option<CachedResponse> {
+    let (mut parts, _) = Response::new(()).into_parts();
+    parts.status = StatusCode::from_u16(snapshot.status).ok()?;
+    parts.headers = header_map(snapshot.headers)?;
+    let compression = match snapshot.compression {
+        Some(encoding) => Some(Compression::from_encoding(&encoding)?),
+        None => None,
+    };
+    let timestamp = monotonic(snapshot.stored_at)?;
+    let cache_control = CacheControl::from_headers(&parts.headers);
+    Some(CachedResponse {
+        stale_while_revalidate: cache_control.stale_while_revalidate,
+        stale_if_error: cache_control.stale_if_error,
+        parts,
+        body: Bytes::from(snapshot.body),
+        timestamp,
+        fetched: monotonic(snapshot.fetched_at).unwrap_or(timestamp),
+        requested: timestamp,
+        age_header: snapshot.age_header,
+        compression,
+        vary: snapshot
+            .vary
+            .into_iter()
+            .map(header_pair)
+            .collect::<Option<_>>()?,
+        ttl: snapshot.ttl,
+        host: snapshot
+            .host
+            .map(|host| HeaderValue::from_bytes(&host))
+            .transpose()
+            .ok()?,
+        origin: snapshot.origin.map(Into::into),
+        soft_invalidated: snapshot.soft_invalidated,
+        body_hash: snapshot.body_hash.map(blake3::Hash::from_bytes),
+        #[cfg(feature = "digest")]
+        identity_digest: snapshot
+            .identity_digest
+            .map(|digest| HeaderValue::from_bytes(&digest))
+            .transpose()
+            .ok()?,
+    })
+}
+
+/// List the headers as the pairs of their names and values.
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, Vec<u8>)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+/// Parse the header name and value, or return `None` if either is invalid.
+fn header_pair((name, value): (String, Vec<u8>)) -> Option<(HeaderName, HeaderValue)> {
+    Some((
+        HeaderName::from_bytes(name.as_bytes()).ok()?,
+        HeaderValue::from_bytes(&value).ok()?,
+    ))
+}
+
+/// Collect the pairs of the header names and values, or return `None` if any is invalid.
+fn header_map(pairs: Vec<(String, Vec<u8>)>) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::with_capacity(pairs.len());
+    for pair in pairs {
+        let (name, value) = header_pair(pair)?;
+        headers.append(name, value);
+    }
+    Some(headers)
+}
+
+/// Convert the moment measured with the monotonic clock into the wall-clock time.
+fn wall_clock(instant: Instant) -> SystemTime {
+    SystemTime::now() - instant.elapsed()
+}
+
+/// Convert the wall-clock time into the moment measured with the monotonic clock, or return
+/// `None` when it can’t be represented.
+fn monotonic(time: SystemTime) -> Option<Instant> {
+    let elapsed = time.elapsed().unwrap_or_default();
+    Instant::now().checked_sub(elapsed)
+}
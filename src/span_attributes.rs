@@ -0,0 +1,34 @@
+//! Recording the cache attributes on the span of the request, following the OpenTelemetry
+//! naming conventions.
+//!
+//! The attributes are recorded as the fields of the `call` span only: the crate doesn’t depend on
+//! OpenTelemetry, exporting them as the span attributes takes the bridge (eg.
+//! `tracing-opentelemetry`) set up by the user.
+
+use std::time::Duration;
+
+use tracing::Span;
+
+use crate::{CachedResponse, Key};
+
+/// The attribute telling whether the response was served from the cache.
+pub(crate) const HIT: &str = "cache.hit";
+/// The attribute with the caching key of the request (the method and the URI).
+pub(crate) const KEY: &str = "cache.key";
+/// The attribute with the number of seconds until the served response expires.
+pub(crate) const TTL_REMAINING: &str = "cache.ttl_remaining";
+
+/// Record the outcome of the lookup on the current span, with the response served on a hit.
+pub(crate) fn record(key: &Key, hit: Option<&CachedResponse>, lifespan: Option<u64>) {
+    let span = Span::current();
+    span.record(HIT, hit.is_some());
+    span.record(KEY, format!("{} {}", key.0, key.1));
+    let Some(value) = hit else {
+        return;
+    };
+    // the entry’s own TTL takes precedence over the lifespan of the cache
+    if let Some(ttl) = value.ttl.or(lifespan.map(Duration::from_secs)) {
+        let remaining = ttl.saturating_sub(value.timestamp.elapsed());
+        span.record(TTL_REMAINING, remaining.as_secs());
+    }
+}
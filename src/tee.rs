@@ -0,0 +1,80 @@
+//! Streaming the response to the client while buffering it for the cache.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::body::{Body, Bytes};
+use http_body::{Frame, SizeHint};
+use tracing::warn;
+
+/// The callback storing the fully buffered body.
+type OnComplete = Box<dyn FnOnce(Bytes) + Send>;
+
+/// The body forwarding all the frames of the inner body while collecting its data. The collected
+/// data are passed to the callback only when the inner body ends successfully without exceeding
+/// the limit, so partial bodies are never stored.
+pub(crate) struct TeeBody {
+    inner: Body,
+    buffer: Option<Vec<u8>>,
+    limit: usize,
+    on_complete: Option<OnComplete>,
+}
+
+impl TeeBody {
+    pub(crate) fn new(inner: Body, limit: usize, on_complete: OnComplete) -> Self {
+        Self {
+            inner,
+            buffer: Some(Vec::new()),
+            limit,
+            on_complete: Some(on_complete),
+        }
+    }
+}
+
+impl http_body::Body for TeeBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = std::task::ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(buffer), Some(data)) = (this.buffer.as_mut(), frame.data_ref()) {
+                    if buffer.len() + data.len() > this.limit {
+                        warn!(
+                            "Streamed body exceeded the limit of {} bytes, not caching.",
+                            this.limit
+                        );
+                        this.buffer = None;
+                    } else {
+                        buffer.extend_from_slice(data);
+                    }
+                }
+            }
+            Some(Err(_)) => this.buffer = None,
+            None => {
+                if let (Some(buffer), Some(on_complete)) =
+                    (this.buffer.take(), this.on_complete.take())
+                {
+                    on_complete(Bytes::from(buffer));
+                }
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        // report the end only after the final `None` frame was polled and the body was stored
+        false
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
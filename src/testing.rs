@@ -0,0 +1,185 @@
+//! Test doubles for asserting the caching behavior in downstream test suites.
+//!
+//! Available with the `testing` feature.
+
+use std::{
+    convert::Infallible,
+    future::{Future, Ready},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tower::Service;
+
+/// The function producing the response to the call with the given number (starting from 0).
+type Respond = Arc<dyn Fn(usize) -> Response + Send + Sync>;
+
+/// The [`tower::Service`] counting its calls and responding with the programmed responses,
+/// to be wrapped with the [`CacheLayer`](crate::CacheLayer) in tests.
+///
+/// The clones share the call counter, so a clone kept by the test observes the calls made to
+/// the one wrapped by the layer.
+///
+/// ```rust
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use axum::{body::Body, http::{Request, StatusCode}, response::IntoResponse};
+/// use axum_response_cache::{testing::MockService, CacheLayer};
+/// use tower::{Layer, Service};
+///
+/// // succeed only on the first call
+/// let inner = MockService::responding(|call| match call {
+///     0 => (StatusCode::OK, "first").into_response(),
+///     _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+/// });
+/// let mut service = CacheLayer::with_lifespan(60).layer(inner.clone());
+///
+/// for _ in 0..3 {
+///     let request = Request::get("/").body(Body::empty()).unwrap();
+///     let response = service.call(request).await.unwrap();
+///     assert_eq!(StatusCode::OK, response.status());
+/// }
+/// assert_eq!(1, inner.calls());
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MockService {
+    calls: Arc<AtomicUsize>,
+    respond: Respond,
+}
+
+impl MockService {
+    /// Create the service responding with `200 OK` and the number of the call (starting from 0)
+    /// as the body.
+    pub fn new() -> Self {
+        Self::responding(|call| call.to_string().into_response())
+    }
+
+    /// Create the service responding with the result of the function called with the number of
+    /// the call (starting from 0).
+    pub fn responding<F>(respond: F) -> Self
+    where
+        F: Fn(usize) -> Response + Send + Sync + 'static,
+    {
+        Self {
+            calls: Arc::default(),
+            respond: Arc::new(respond),
+        }
+    }
+
+    /// Create the service responding with the given statuses in order, repeating the last one
+    /// once all were used.
+    pub fn with_statuses(statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        let statuses: Vec<StatusCode> = statuses.into_iter().collect();
+        Self::responding(move |call| {
+            let status = statuses
+                .get(call)
+                .or(statuses.last())
+                .copied()
+                .unwrap_or(StatusCode::OK);
+            (status, call.to_string()).into_response()
+        })
+    }
+
+    /// Return the number of calls the service (and its clones) received.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::Acquire)
+    }
+}
+
+impl Default for MockService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service<Request<Body>> for MockService {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Ready<Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Request<Body>) -> Self::Future {
+        let call = self.calls.fetch_add(1, Ordering::AcqRel);
+        std::future::ready(Ok((self.respond)(call)))
+    }
+}
+
+/// The [`tower::Service`] delaying the responses of the wrapped one, to keep its calls in flight
+/// long enough for the concurrent requests to reliably overlap, eg. when testing the coalescing
+/// of the misses (see [`CacheLayer::coalesce_misses`](crate::CacheLayer::coalesce_misses)).
+///
+/// The wrapped service is called right away, only its response is held back.
+///
+/// ```rust
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::time::Duration;
+///
+/// use axum::{body::Body, http::Request};
+/// use axum_response_cache::{
+///     testing::{Delayed, MockService},
+///     CacheLayer,
+/// };
+/// use tower::{Layer, ServiceExt};
+///
+/// let inner = MockService::new();
+/// let delayed = Delayed::new(inner.clone(), Duration::from_millis(50));
+/// let service = CacheLayer::with_lifespan(60)
+///     .coalesce_misses()
+///     .layer(delayed);
+///
+/// let call = || service.clone().oneshot(Request::get("/").body(Body::empty()).unwrap());
+/// let (first, second, third) = tokio::join!(call(), call(), call());
+/// assert!(first.is_ok() && second.is_ok() && third.is_ok());
+/// assert_eq!(1, inner.calls());
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Delayed<S> {
+    inner: S,
+    delay: Duration,
+}
+
+impl<S> Delayed<S> {
+    /// Wrap the service, delaying each of its responses by the given duration.
+    pub fn new(inner: S, delay: Duration) -> Self {
+        Self { inner, delay }
+    }
+}
+
+impl<S> Service<Request<Body>> for Delayed<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let response = self.inner.call(request);
+        let delay = self.delay;
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            response.await
+        })
+    }
+}
@@ -0,0 +1,49 @@
+//! The secondary (eg. shared or remote) store of the two-tier cache.
+
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::async_trait;
+use tracing::debug;
+
+use crate::{CachedEntry, Key};
+
+/// The asynchronous cache used as the second tier behind the in-memory cache of the layer, see
+/// [`CacheLayer::with_tiers`](crate::CacheLayer::with_tiers).
+///
+/// The keys are the `(Method, Uri)` pairs of the requests. The store is responsible for the
+/// expiration of its own entries. The stores outside the process encode the entries through
+/// their [`EntrySnapshot`](crate::EntrySnapshot)s.
+#[async_trait]
+pub trait SecondaryCache: Send + Sync {
+    /// Return the entry stored for the key, if any.
+    async fn get(&self, key: &Key) -> Option<CachedEntry>;
+
+    /// Store the entry for the key, replacing the previous one.
+    async fn set(&self, key: Key, entry: CachedEntry);
+
+    /// Check whether the store can accept more writes (eg. its bounded write queue isn’t full),
+    /// registering the waker to be notified when it can. Stores without a bounded capacity are
+    /// always ready.
+    ///
+    /// Consulted only when [`CacheLayer::secondary_backpressure`](crate::CacheLayer::secondary_backpressure)
+    /// is enabled.
+    fn poll_write_capacity(&self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+}
+
+/// The shared handle to the secondary cache.
+pub(crate) type Secondary = Arc<dyn SecondaryCache>;
+
+/// Write the entry stored in the first tier through to the secondary cache in the background,
+/// so the response doesn’t wait for it.
+pub(crate) fn mirror(secondary: &Secondary, key: Key, entry: CachedEntry) {
+    let secondary = Arc::clone(secondary);
+    tokio::spawn(async move {
+        debug!("Mirroring the entry for {key:?} to the secondary cache");
+        secondary.set(key, entry).await;
+    });
+}
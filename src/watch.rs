@@ -0,0 +1,119 @@
+//! Invalidation of the cached files served from a directory when they change on disk.
+
+use std::{
+    path::{Component, Path},
+    sync::{Arc, Mutex, Weak},
+};
+
+use axum::http::{Method, Uri};
+use cached::Cached;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::{
+    invalidation::{self, Invalidated, DROP_CHECK_PERIOD},
+    CacheKeys, CachedEntry, InvalidationMsg, Key,
+};
+
+/// Watch the directory and purge the responses cached for the changed files (under the URI
+/// prefix the directory is served at) until the cache is dropped.
+pub(crate) fn watch<C>(
+    cache: Weak<Mutex<C>>,
+    invalidated: Arc<Invalidated>,
+    dir: &Path,
+    prefix: &str,
+) -> notify::Result<()>
+where
+    C: Cached<Key, CachedEntry> + CacheKeys<Key, CachedEntry> + Send + 'static,
+{
+    // the watcher reports the canonical paths on some platforms
+    let dir = dir.canonicalize()?;
+    let prefix = prefix.trim_end_matches('/').to_owned();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher = RecommendedWatcher::new(
+        move |event: notify::Result<Event>| {
+            let _ = sender.send(event);
+        },
+        notify::Config::default(),
+    )?;
+    watcher.watch(&dir, RecursiveMode::Recursive)?;
+    tokio::spawn(async move {
+        // the watcher stops once it’s dropped with the task
+        let _watcher = watcher;
+        loop {
+            let event = match tokio::time::timeout(DROP_CHECK_PERIOD, receiver.recv()).await {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(_) if cache.strong_count() == 0 => break,
+                Err(_) => continue,
+            };
+            let Some(cache) = cache.upgrade() else {
+                break;
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    // the missed changes can’t be recovered, so nothing in the cache can be trusted
+                    debug!("Failed to watch the files, clearing the cache: {err}");
+                    let mut cache = cache.lock().unwrap();
+                    invalidated.mark(cache.cache_keys());
+                    cache.cache_clear();
+                    continue;
+                }
+            };
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            for path in &event.paths {
+                for msg in messages(&dir, &prefix, path) {
+                    let removed = invalidation::invalidate(&mut *cache.lock().unwrap(), &msg);
+                    debug!("Invalidated {} cached responses for {msg:?}", removed.len());
+                    invalidated.mark(removed);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Map the changed file to the invalidations of its URI (with any query, and of the other files
+/// the URI is a prefix of) and, for the `index.html` files, of the URI of their directory.
+fn messages(dir: &Path, prefix: &str, path: &Path) -> Vec<InvalidationMsg> {
+    let Ok(relative) = path.strip_prefix(dir) else {
+        return Vec::new();
+    };
+    let mut uri = String::from(prefix);
+    for component in relative.components() {
+        let Component::Normal(segment) = component else {
+            return Vec::new();
+        };
+        uri.push('/');
+        uri.push_str(&encode(&segment.to_string_lossy()));
+    }
+    let mut messages = Vec::new();
+    if relative
+        .file_name()
+        .is_some_and(|name| name == "index.html")
+    {
+        if let Ok(parent) = uri.trim_end_matches("index.html").parse::<Uri>() {
+            messages.push(InvalidationMsg::Key(Method::GET, parent.clone()));
+            messages.push(InvalidationMsg::Key(Method::HEAD, parent));
+        }
+    }
+    messages.push(InvalidationMsg::Prefix(uri));
+    messages
+}
+
+/// Percent-encode the path segment, leaving the characters allowed in it as they are.
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => char::from(byte).to_string(),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' => char::from(byte).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,99 @@
+//! Persisting the cached entries in the background, off the request path.
+
+use std::{
+    sync::{Mutex, Weak},
+    task::{Context, Poll},
+};
+
+use axum::async_trait;
+use cached::Cached;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::{tiered::Secondary, CachedEntry, Key, SecondaryCache};
+
+/// The persistent store the cached entries are written behind to, see
+/// [`CacheLayer::write_behind`](crate::CacheLayer::write_behind).
+///
+/// The stores writing outside the process (eg. to disk) encode the entries through their
+/// [`EntrySnapshot`](crate::EntrySnapshot)s, which keep their age across the restarts.
+#[async_trait]
+pub trait PersistentStore: Send + Sync {
+    /// Return all the persisted entries, to repopulate the cache with on startup.
+    async fn load(&self) -> Vec<(Key, CachedEntry)>;
+
+    /// Persist the entry for the key, replacing the previous one.
+    async fn persist(&self, key: Key, entry: CachedEntry);
+}
+
+/// The secondary cache queueing the stored entries for the persisting task, so storing never
+/// waits for the persistent store, in front of the secondary cache configured before (if any).
+pub(crate) struct WriteBehind {
+    queue: mpsc::Sender<(Key, CachedEntry)>,
+    next: Option<Secondary>,
+}
+
+#[async_trait]
+impl SecondaryCache for WriteBehind {
+    // all the persisted entries are loaded on startup, so only the next cache is looked up
+    async fn get(&self, key: &Key) -> Option<CachedEntry> {
+        self.next.as_ref()?.get(key).await
+    }
+
+    async fn set(&self, key: Key, entry: CachedEntry) {
+        let next = self
+            .next
+            .as_ref()
+            .map(|next| (next, key.clone(), entry.clone()));
+        if let Err(err) = self.queue.try_send((key, entry)) {
+            debug!("Write-behind queue unavailable, not persisting the entry: {err}");
+        }
+        if let Some((next, key, entry)) = next {
+            next.set(key, entry).await;
+        }
+    }
+
+    fn poll_write_capacity(&self, cx: &mut Context<'_>) -> Poll<()> {
+        match &self.next {
+            Some(next) => next.poll_write_capacity(cx),
+            None => Poll::Ready(()),
+        }
+    }
+}
+
+/// Spawn the task repopulating the cache from the store and then persisting the queued entries
+/// one by one, until the queue is closed. The stored entries are passed on to the next secondary
+/// cache too.
+pub(crate) fn spawn<C, P>(
+    cache: Weak<Mutex<C>>,
+    store: P,
+    capacity: usize,
+    next: Option<Secondary>,
+) -> WriteBehind
+where
+    C: Cached<Key, CachedEntry> + Send + 'static,
+    P: PersistentStore + 'static,
+{
+    let (sender, mut receiver) = mpsc::channel(capacity);
+    tokio::spawn(async move {
+        let entries = store.load().await;
+        debug!(
+            "Repopulating the cache with {} persisted entries",
+            entries.len()
+        );
+        if let Some(cache) = cache.upgrade() {
+            let mut guard = cache.lock().unwrap();
+            for (key, entry) in entries {
+                // the entries stored meanwhile are newer than the persisted ones
+                guard.cache_get_or_set_with(key, || entry);
+            }
+        }
+        while let Some((key, entry)) = receiver.recv().await {
+            store.persist(key, entry).await;
+        }
+    });
+    WriteBehind {
+        queue: sender,
+        next,
+    }
+}